@@ -1,12 +1,17 @@
 use std::sync::Arc;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::SocketAddr;
+use std::io::{Error, ErrorKind};
 
 use logger::debug;
 
 use relcomm::reliable_communication::ReliableCommunication;
-use relcomm::node::Node;
+use relcomm::discovery::Discovery;
 use crate::hashmap::DistrHash;
-use crate::config::{KEYS, MSG_NUM, MSG_SIZE, WRITE_READ_RATIO};
+use crate::checker::Operation;
+use crate::merkle::Repairs;
+use crate::resolver::Resolver;
+use crate::storage::Storage;
+use crate::config::{CLAIM_KEY, COUNTER_KEY, INCREMENTS, KEYS, SESSION_TTL, MSG_NUM, MSG_SIZE, LINEARIZABLE, PARTITIONED, PERSISTENT, READ_QUORUM, REPLICATION_FACTOR, RESOLVER, SNAPSHOT_EVERY, STORAGE_DIR, SYNC_WAL, WRITE_QUORUM, WRITE_READ_RATIO};
 
 pub struct Agent {
     id: usize,
@@ -17,13 +22,23 @@ pub struct Agent {
 impl Agent {
     fn new(
         id: usize,
-        nodes: Vec<Node>
+        addr: SocketAddr
     ) -> Result<Self, std::io::Error> {
-        let communication = ReliableCommunication::new(
-                nodes[id].clone(),
-                nodes
-            )?;
-        let (hash_table, listener_handle) = DistrHash::new(communication);
+        let communication = ReliableCommunication::discover(addr)?;
+        let storage = if PERSISTENT {
+            Some(Storage::open(format!("{STORAGE_DIR}/agent_{id}"), SNAPSHOT_EVERY, SYNC_WAL)?)
+        } else {
+            None
+        };
+        let (hash_table, listener_handle) = if PARTITIONED {
+            DistrHash::partitioned(communication, REPLICATION_FACTOR, LINEARIZABLE, storage)?
+        } else {
+            DistrHash::new(communication, LINEARIZABLE, storage)?
+        };
+        match Resolver::from_name(RESOLVER) {
+            Some(resolver) => hash_table.set_resolver(resolver),
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid resolver {RESOLVER}"))),
+        }
         Ok(Agent {
             id,
             hash_table,
@@ -31,22 +46,68 @@ impl Agent {
         })
     }
 
-    pub fn run(self) -> std::time::Duration {
+    /// Returns the time taken, the history of the operations that succeeded, to be checked later,
+    /// and how much the anti-entropy repaired
+    pub fn run(self) -> (std::time::Duration, Vec<Operation>, Repairs) {
         let start = std::time::Instant::now();
+        let mut history = Vec::new();
         for _ in 0..MSG_NUM {
             // Decide if it will read or write
             let key: String = Agent::get_random_key();
-            if rand::random::<f32>() < WRITE_READ_RATIO {
+            let invoke = Operation::now();
+            let (write, result) = if rand::random::<f32>() < WRITE_READ_RATIO {
                 let msg = Agent::get_rnd_msg(MSG_SIZE);
-                let _ = self.hash_table.write(&key, &msg);
+                (true, self.hash_table.write(key.as_bytes(), msg.as_bytes(), WRITE_QUORUM).map(|_| Some(msg)))
             } else {
-                let _ = self.hash_table.read(&key);
+                let value = self.hash_table.read(key.as_bytes(), READ_QUORUM);
+                (false, value.map(|value| value.map(|value| String::from_utf8_lossy(&value).into_owned())))
+            };
+            if let Ok(value) = result {
+                let kind = if write { 'W' } else { 'R' };
+                history.push(Operation { agent: self.id, kind, key, value, invoke, response: Operation::now() });
             }
         }
+        history.extend(self.run_conditional());
         let total = start.elapsed();
         self.listener_handle.join().unwrap();
         debug!("->-> Agente {} finished", self.id);
-        total
+        (total, history, self.hash_table.repairs())
+    }
+
+    /// Conditional writes on keys of their own: the increments of all the agents must return different numbers,
+    /// a single agent claims the claim key, and only that agent can release it
+    /// Meanwhile the agent keeps a session key, with a time to live in case it fails before deleting it
+    fn run_conditional(&self) -> Vec<Operation> {
+        let id = self.id.to_string();
+        let session = format!("session{id}");
+        let _ = self.hash_table.write_with_ttl(session.as_bytes(), id.as_bytes(), SESSION_TTL, WRITE_QUORUM);
+        let mut history = Vec::new();
+        let mut record = |kind: char, key: &str, invoke: u64, value: String| {
+            history.push(Operation {
+                agent: self.id,
+                kind,
+                key: key.to_string(),
+                value: Some(value),
+                invoke,
+                response: Operation::now(),
+            });
+        };
+        let invoke = Operation::now();
+        if let Ok(claimed) = self.hash_table.put_if_absent(CLAIM_KEY.as_bytes(), id.as_bytes()) {
+            record('C', CLAIM_KEY, invoke, (claimed as u8).to_string());
+        }
+        for _ in 0..INCREMENTS {
+            let invoke = Operation::now();
+            if let Ok(number) = self.hash_table.increment(COUNTER_KEY.as_bytes(), 1) {
+                record('I', COUNTER_KEY, invoke, number.to_string());
+            }
+        }
+        let invoke = Operation::now();
+        if let Ok(released) = self.hash_table.compare_and_swap(CLAIM_KEY.as_bytes(), id.as_bytes(), b"released") {
+            record('S', CLAIM_KEY, invoke, (released as u8).to_string());
+        }
+        let _ = self.hash_table.delete(session.as_bytes(), WRITE_QUORUM);
+        history
     }
 
     fn get_random_key() -> String {
@@ -66,22 +127,13 @@ impl Agent {
     }
 }
 
-/// Creates the vector of nodes for all of the members of the group
-/// Since this is currently always being tested in the same machine, the IP is always the same and the ports are based on the node id
-/// Then it creates and returns the agent for the node that matches the sub-process id
-pub fn create_agents(
-    id: usize,
-    agent_num: usize,
-) -> Result<Agent, std::io::Error> {
-    let ip: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-    let port: u16 = 3000;
-    let nodes: Vec<Node> = (0..agent_num)
-        .map(|i| Node::new(
-            SocketAddr::new(
-                ip, port + (i as u16)),
-                 i))
-        .collect();
-
-    let agent = Agent::new(id, nodes)?;
+/// Creates and returns the agent for the node that matches the sub-process id
+/// The group is discovered from the seeds in the config file of relcomm, the agent uses the seed with its id
+pub fn create_agents(id: usize) -> Result<Agent, std::io::Error> {
+    let addr = match Discovery::from_config()?.seeds().get(id) {
+        Some(addr) => *addr,
+        None => return Err(Error::new(ErrorKind::NotFound, format!("There's no seed for agent {id}"))),
+    };
+    let agent = Agent::new(id, addr)?;
     Ok(agent)
 }
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A completed operation, with the times it was invoked and returned, in microseconds since the epoch
+/// The agents run in processes of the same machine, so their clocks can be compared
+#[derive(Clone)]
+pub struct Operation {
+    pub agent: usize,
+    // W for writes, R for reads, I for increments, C for claims with put_if_absent and S for releases with compare_and_swap
+    pub kind: char,
+    pub key: String,
+    // Written value, the value read, the number returned by the increment, or 1 if the claim or release succeeded
+    pub value: Option<String>,
+    pub invoke: u64,
+    pub response: u64,
+}
+
+impl Operation {
+    pub fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+    }
+
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {} {} {}\n",
+            self.agent,
+            self.kind,
+            self.key,
+            self.invoke,
+            self.response,
+            self.value.as_deref().unwrap_or("-"),
+        )
+    }
+
+    pub fn from_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() != 6 { return None; }
+        Some(Operation {
+            agent: fields[0].parse().ok()?,
+            kind: fields[1].chars().next()?,
+            key: fields[2].to_string(),
+            invoke: fields[3].parse().ok()?,
+            response: fields[4].parse().ok()?,
+            value: if fields[5] == "-" { None } else { Some(fields[5].to_string()) },
+        })
+    }
+}
+
+/// Reads the histories of the agents, the files named history_<agent>.txt in the folder
+pub fn load(folder: &str) -> Result<Vec<Operation>, Error> {
+    let mut history = Vec::new();
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        let is_history = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("history_"));
+        if !is_history { continue; }
+        history.extend(fs::read_to_string(path)?.lines().filter_map(Operation::from_line));
+    }
+    Ok(history)
+}
+
+/// Looks for reads that can't happen in a linearizable register, where every write has a unique value:
+/// a read of a value written only after it returned, a read of nothing after a write completed,
+/// or a read of a value overwritten by a write that completed before the read started
+/// These are necessary conditions, a history without violations isn't proven linearizable
+/// Reads of values whose write failed are ignored, the write may or may not have happened
+pub fn check(history: &[Operation]) -> Vec<String> {
+    let mut writes: HashMap<&str, Vec<&Operation>> = HashMap::new();
+    for operation in history.iter().filter(|operation| operation.kind == 'W') {
+        writes.entry(&operation.key).or_default().push(operation);
+    }
+    let mut violations = check_conditional(history);
+    for read in history.iter().filter(|operation| operation.kind == 'R') {
+        let key_writes = writes.get(read.key.as_str()).map(Vec::as_slice).unwrap_or(&[]);
+        let value = match &read.value {
+            Some(value) => value,
+            None => {
+                if key_writes.iter().any(|write| write.response < read.invoke) {
+                    violations.push(format!("Agent {} read nothing from {} after a write completed", read.agent, read.key));
+                }
+                continue;
+            }
+        };
+        let source = match key_writes.iter().find(|write| write.value.as_ref() == Some(value)) {
+            Some(source) => source,
+            None => continue,
+        };
+        if source.invoke > read.response {
+            violations.push(format!("Agent {} read from {} a value written after the read", read.agent, read.key));
+        } else if key_writes.iter().any(|write| source.response < write.invoke && write.response < read.invoke) {
+            violations.push(format!("Agent {} read from {} a value already overwritten", read.agent, read.key));
+        }
+    }
+    violations
+}
+
+/// Looks for outcomes of conditional writes that no total order explains: two increments of a key returning
+/// the same number, more than one claim or release of a key succeeding, or a release by an agent that didn't claim it
+fn check_conditional(history: &[Operation]) -> Vec<String> {
+    let mut violations = Vec::new();
+    let mut numbers: HashMap<(&str, &str), usize> = HashMap::new();
+    let mut winners: HashMap<(char, &str), Vec<usize>> = HashMap::new();
+    for operation in history {
+        let value = operation.value.as_deref().unwrap_or("-");
+        match operation.kind {
+            'I' => *numbers.entry((&operation.key, value)).or_default() += 1,
+            'C' | 'S' if value == "1" => winners.entry((operation.kind, &operation.key)).or_default().push(operation.agent),
+            _ => {}
+        }
+    }
+    for ((key, number), count) in numbers {
+        if count > 1 {
+            violations.push(format!("{count} increments of {key} returned {number}"));
+        }
+    }
+    for ((kind, key), agents) in &winners {
+        if agents.len() > 1 {
+            violations.push(format!("Agents {agents:?} all succeeded in {kind} on {key}"));
+        }
+        let claimed = winners.get(&('C', *key));
+        if *kind == 'S' && claimed.is_none_or(|claimed| !claimed.contains(&agents[0])) {
+            violations.push(format!("Agent {} released {key} without claiming it", agents[0]));
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(agent: usize, kind: char, value: Option<&str>, invoke: u64, response: u64) -> Operation {
+        Operation { agent, kind, key: "key1".to_string(), value: value.map(str::to_string), invoke, response }
+    }
+
+    #[test]
+    fn operations_survive_their_line() {
+        for operation in [operation(3, 'W', Some("v1"), 10, 20), operation(1, 'R', None, 5, 6)] {
+            let parsed = Operation::from_line(operation.to_line().trim_end()).unwrap();
+            assert_eq!(parsed.to_line(), operation.to_line());
+        }
+        assert!(Operation::from_line("1 W key1 10").is_none());
+    }
+
+    #[test]
+    fn overlapping_operations_may_be_ordered_either_way() {
+        let history = [
+            operation(0, 'W', Some("a"), 0, 10),
+            operation(1, 'W', Some("b"), 5, 30),
+            // Concurrent with the write of b, may see either value or, before both complete, nothing
+            operation(2, 'R', Some("b"), 8, 12),
+            operation(3, 'R', Some("a"), 11, 25),
+            operation(4, 'R', None, 1, 4),
+        ];
+        assert!(check(&history).is_empty(), "{:?}", check(&history));
+    }
+
+    #[test]
+    fn stale_and_early_reads_are_violations() {
+        let history = [
+            operation(0, 'W', Some("a"), 0, 10),
+            operation(1, 'W', Some("b"), 20, 30),
+            // a was overwritten before the read started
+            operation(2, 'R', Some("a"), 40, 50),
+            // b was written after the read returned
+            operation(3, 'R', Some("b"), 12, 15),
+            // a write had completed
+            operation(4, 'R', None, 11, 12),
+        ];
+        assert_eq!(check(&history).len(), 3, "{:?}", check(&history));
+    }
+
+    #[test]
+    fn conditional_writes_need_a_single_winner() {
+        let history = [
+            operation(0, 'I', Some("1"), 0, 10),
+            operation(1, 'I', Some("1"), 0, 10),
+            operation(0, 'C', Some("1"), 0, 10),
+            operation(1, 'C', Some("0"), 0, 10),
+            operation(2, 'S', Some("1"), 20, 30),
+        ];
+        let violations = check(&history);
+        assert_eq!(violations.len(), 2, "{violations:?}");
+        assert!(violations.iter().any(|violation| violation.contains("increments")));
+        assert!(violations.iter().any(|violation| violation.contains("without claiming")));
+    }
+}
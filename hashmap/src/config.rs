@@ -1,6 +1,7 @@
+use std::time::Duration;
+
 pub const TESTS_NUM: usize = 1;
 pub const MSG_NUM: u32 = 1000;
-pub const AGENT_NUM: usize = 10;
 pub const MSG_SIZE: usize = 1<<10;
 pub const KEYS: [&str; 10] = [
     "key1",
@@ -15,3 +16,23 @@ pub const KEYS: [&str; 10] = [
     "key10"
 ];
 pub const WRITE_READ_RATIO: f32 = 1.;
+pub const PARTITIONED: bool = false;
+pub const VIRTUAL_NODES: usize = 64;
+pub const REPLICATION_FACTOR: usize = 3;
+pub const LINEARIZABLE: bool = false;
+pub const OPERATION_TIMEOUT: Duration = Duration::from_secs(5);
+pub const WRITE_QUORUM: usize = 2;
+pub const READ_QUORUM: usize = 2;
+pub const RESOLVER: &str = "LWW";
+pub const COUNTER_KEY: &str = "counter";
+pub const CLAIM_KEY: &str = "claim";
+pub const INCREMENTS: u32 = 20;
+pub const SESSION_TTL: Duration = Duration::from_secs(10);
+pub const PERSISTENT: bool = false;
+pub const STORAGE_DIR: &str = "storage";
+pub const SNAPSHOT_EVERY: usize = 1000;
+pub const SYNC_WAL: bool = false;
+pub const CATCH_UP_RETRY: Duration = Duration::from_millis(100);
+pub const ANTI_ENTROPY_PERIOD: Duration = Duration::from_secs(1);
+pub const MERKLE_DEPTH: u8 = 6;
+pub const LISTENER_IDLE: Duration = Duration::from_secs(2);
@@ -1,23 +1,346 @@
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
 
-pub fn to_bytes(key: &String, msg: &String) -> Result<Vec<u8>, Error> {
-    if key.contains(':') {
-        return Err(Error::new(ErrorKind::InvalidInput, "Key contains invalid character"));
+use crate::version::Versioned;
+
+/// Version of the command format, the first byte of every command, commands in other formats are rejected
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Op codes of the commands
+pub const WRITE: u8 = 0;
+pub const READ: u8 = 1;
+/// Conditional writes, their argument is the expected value or the amount to add
+pub const CAS: u8 = 2;
+pub const INCREMENT: u8 = 3;
+/// State transfer request of a node catching up, partitioned its argument is the ranges of the ring the node replicates
+pub const SYNC: u8 = 4;
+/// Anti-entropy messages, sent straight to a replica instead of broadcast: the hashes of some nodes of the Merkle
+/// tree of the sender, or its keys in the leaves where the trees differ
+pub const NODES: u8 = 5;
+pub const KEYS: u8 = 6;
+
+/// Values of each key, as kept by a replica
+pub type Table = HashMap<Vec<u8>, Vec<Versioned>>;
+
+/// Kinds of arguments, the byte before each argument
+const NONE: u8 = 0;
+const EXPECTED: u8 = 1;
+const DELTA: u8 = 2;
+const RANGES: u8 = 3;
+
+/// Argument of a command, which one depends on the op code
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Arg {
+    #[default]
+    None,
+    // CAS: the value the key must have, none if the key must be absent
+    Expected(Option<Vec<u8>>),
+    // INCREMENT: the amount added to the number of the key
+    Delta(i64),
+    // SYNC: the positions of the keys the node catching up replicates, as inclusive ranges
+    Ranges(Vec<(u64, u64)>),
+}
+
+impl Arg {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Arg::None => vec![NONE],
+            Arg::Expected(None) => vec![EXPECTED, 0],
+            Arg::Expected(Some(value)) => {
+                let mut bytes = vec![EXPECTED, 1];
+                put_field(&mut bytes, value);
+                bytes
+            }
+            Arg::Delta(delta) => [vec![DELTA], delta.to_be_bytes().to_vec()].concat(),
+            Arg::Ranges(ranges) => {
+                let mut bytes = vec![RANGES];
+                bytes.extend((ranges.len() as u32).to_be_bytes());
+                for (start, end) in ranges {
+                    bytes.extend(start.to_be_bytes());
+                    bytes.extend(end.to_be_bytes());
+                }
+                bytes
+            }
+        }
+    }
+
+    fn from_bytes(bytes: &[u8], start: &mut usize) -> Result<Self, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "Truncated command");
+        let kind = *bytes.get(*start).ok_or_else(invalid)?;
+        *start += 1;
+        let arg = match kind {
+            NONE => Arg::None,
+            EXPECTED => {
+                let present = *bytes.get(*start).ok_or_else(invalid)?;
+                *start += 1;
+                Arg::Expected(if present == 1 { Some(get_field(bytes, start)?) } else { None })
+            }
+            DELTA => {
+                let delta = i64::from_be_bytes(bytes.get(*start..*start + 8).ok_or_else(invalid)?.try_into().unwrap());
+                *start += 8;
+                Arg::Delta(delta)
+            }
+            RANGES => {
+                let len = u32::from_be_bytes(bytes.get(*start..*start + 4).ok_or_else(invalid)?.try_into().unwrap());
+                *start += 4;
+                let mut ranges = Vec::new();
+                for _ in 0..len {
+                    let range = bytes.get(*start..*start + 16).ok_or_else(invalid)?;
+                    ranges.push((
+                        u64::from_be_bytes(range[0..8].try_into().unwrap()),
+                        u64::from_be_bytes(range[8..16].try_into().unwrap()),
+                    ));
+                    *start += 16;
+                }
+                Arg::Ranges(ranges)
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown argument kind {kind}"))),
+        };
+        Ok(arg)
+    }
+}
+
+/// Operation on a key, sent to the replicas of the key or broadcast to the group
+/// After the format version and the op code come the origin and the id, then the key, prefixed by its length,
+/// the version with the value and the argument, after its kind, so keys and values may hold any byte
+pub struct Command {
+    pub op: u8,
+    // The node that created the command and its id there, so the origin recognizes the command when it's delivered
+    pub origin: usize,
+    pub id: u32,
+    pub key: Vec<u8>,
+    pub versioned: Versioned,
+    pub arg: Arg,
+}
+
+impl Command {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![FORMAT_VERSION, self.op];
+        bytes.extend((self.origin as u32).to_be_bytes());
+        bytes.extend(self.id.to_be_bytes());
+        put_field(&mut bytes, &self.key);
+        bytes.extend(self.versioned.to_bytes());
+        bytes.extend(self.arg.to_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "Truncated command");
+        match bytes.first() {
+            Some(&FORMAT_VERSION) => {}
+            Some(version) => {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported command format {version}")));
+            }
+            None => return Err(invalid()),
+        }
+        let op = *bytes.get(1).ok_or_else(invalid)?;
+        let origin = u32::from_be_bytes(bytes.get(2..6).ok_or_else(invalid)?.try_into().unwrap()) as usize;
+        let id = u32::from_be_bytes(bytes.get(6..10).ok_or_else(invalid)?.try_into().unwrap());
+        let mut start = 10;
+        let key = get_field(bytes, &mut start)?;
+        let versioned = Versioned::from_bytes(bytes, &mut start).ok_or_else(invalid)?;
+        let arg = Arg::from_bytes(bytes, &mut start)?;
+        Ok(Command { op, origin, id, key, versioned, arg })
+    }
+}
+
+/// Anti-entropy message, after the format version and the op code come the origin, the level of the tree,
+/// whether the receiver must answer with its own keys, the nodes with their hashes and the keys
+pub struct Exchange {
+    pub op: u8,
+    pub origin: usize,
+    pub level: u8,
+    pub nodes: Vec<(u32, u64)>,
+    pub table: Table,
+    pub reply: bool,
+}
+
+impl Exchange {
+    /// Whether the message is an anti-entropy message instead of a command
+    /// Messages in other formats aren't, and are rejected as commands
+    pub fn is_exchange(bytes: &[u8]) -> bool {
+        bytes.first() == Some(&FORMAT_VERSION) && matches!(bytes.get(1), Some(&NODES) | Some(&KEYS))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![FORMAT_VERSION, self.op];
+        bytes.extend((self.origin as u32).to_be_bytes());
+        bytes.extend([self.level, self.reply as u8]);
+        bytes.extend((self.nodes.len() as u32).to_be_bytes());
+        for (index, hash) in &self.nodes {
+            bytes.extend(index.to_be_bytes());
+            bytes.extend(hash.to_be_bytes());
+        }
+        bytes.extend(to_table(&self.table));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "Truncated anti-entropy message");
+        match bytes.first() {
+            Some(&FORMAT_VERSION) => {}
+            Some(version) => {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported anti-entropy message format {version}")));
+            }
+            None => return Err(invalid()),
+        }
+        let op = *bytes.get(1).ok_or_else(invalid)?;
+        let origin = u32::from_be_bytes(bytes.get(2..6).ok_or_else(invalid)?.try_into().unwrap()) as usize;
+        let level = *bytes.get(6).ok_or_else(invalid)?;
+        let reply = *bytes.get(7).ok_or_else(invalid)? == 1;
+        let len = u32::from_be_bytes(bytes.get(8..12).ok_or_else(invalid)?.try_into().unwrap());
+        let mut start = 12;
+        let mut nodes = Vec::new();
+        for _ in 0..len {
+            let node = bytes.get(start..start + 12).ok_or_else(invalid)?;
+            nodes.push((
+                u32::from_be_bytes(node[0..4].try_into().unwrap()),
+                u64::from_be_bytes(node[4..12].try_into().unwrap()),
+            ));
+            start += 12;
+        }
+        let table = from_table(&bytes[start..])?;
+        Ok(Exchange { op, origin, level, nodes, table, reply })
     }
-    let mut bytes = vec![];
-    bytes.extend_from_slice(key.as_bytes());
-    bytes.push(b':');
-    bytes.extend_from_slice(msg.as_bytes());
-    return Ok(bytes);
 }
 
-pub fn from_bytes(msg: Vec<u8>) -> Result<(String, String), Error> {
-    for i in 0..msg.len() {
-        if msg[i] == b':' {
-            let key = String::from_utf8(msg[0..i].to_vec()).unwrap();
-            let value = String::from_utf8(msg[i+1..].to_vec()).unwrap();
-            return Ok((key, value));
+pub fn to_siblings(siblings: &[Versioned]) -> Vec<u8> {
+    let mut bytes = (siblings.len() as u16).to_be_bytes().to_vec();
+    for sibling in siblings {
+        bytes.extend(sibling.to_bytes());
+    }
+    bytes
+}
+
+pub fn from_siblings(msg: &[u8]) -> Result<Vec<Versioned>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid siblings");
+    let len = u16::from_be_bytes(msg.get(0..2).ok_or_else(invalid)?.try_into().unwrap());
+    let mut start = 2;
+    (0..len)
+        .map(|_| Versioned::from_bytes(msg, &mut start).ok_or_else(invalid))
+        .collect()
+}
+
+/// Every key of a table with its siblings, the state a node catching up receives
+pub fn to_table(table: &Table) -> Vec<u8> {
+    let mut bytes = (table.len() as u32).to_be_bytes().to_vec();
+    for (key, siblings) in table {
+        put_field(&mut bytes, key);
+        bytes.extend(to_siblings(siblings));
+    }
+    bytes
+}
+
+pub fn from_table(msg: &[u8]) -> Result<Table, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid table");
+    let len = u32::from_be_bytes(msg.get(0..4).ok_or_else(invalid)?.try_into().unwrap());
+    let mut start = 4;
+    let mut table = HashMap::new();
+    for _ in 0..len {
+        let key = get_field(msg, &mut start)?;
+        let count = u16::from_be_bytes(msg.get(start..start + 2).ok_or_else(invalid)?.try_into().unwrap());
+        start += 2;
+        let siblings = (0..count)
+            .map(|_| Versioned::from_bytes(msg, &mut start).ok_or_else(invalid))
+            .collect::<Result<Vec<Versioned>, Error>>()?;
+        table.insert(key, siblings);
+    }
+    Ok(table)
+}
+
+fn put_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.extend((field.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(field);
+}
+
+fn get_field(bytes: &[u8], start: &mut usize) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Truncated command");
+    let len = u32::from_be_bytes(bytes.get(*start..*start + 4).ok_or_else(invalid)?.try_into().unwrap()) as usize;
+    let field = bytes.get(*start + 4..*start + 4 + len).ok_or_else(invalid)?;
+    *start += 4 + len;
+    Ok(field.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::{Timestamp, VectorClock};
+
+    // Bytes that aren't UTF-8
+    const KEY: &[u8] = &[0xff, 0x00, 0xfe, b'k'];
+    const VALUE: &[u8] = &[0xc3, 0x28, 0x00, 0x80];
+
+    fn versioned(value: &[u8]) -> Versioned {
+        let mut clock = VectorClock::default();
+        clock.set(1, 3);
+        Versioned { clock, timestamp: Timestamp { counter: 7, node: 1 }, value: value.to_vec(), deleted: false, expires: 0 }
+    }
+
+    fn command(op: u8, arg: Arg) -> Command {
+        Command { op, origin: 2, id: 9, key: KEY.to_vec(), versioned: versioned(VALUE), arg }
+    }
+
+    #[test]
+    fn commands_keep_any_byte_and_their_arguments() {
+        let args = [
+            (WRITE, Arg::None),
+            (CAS, Arg::Expected(Some(vec![0x80, 0xff]))),
+            (CAS, Arg::Expected(None)),
+            (INCREMENT, Arg::Delta(-42)),
+            (SYNC, Arg::Ranges(vec![(0, 7), (u64::MAX - 1, u64::MAX)])),
+            (SYNC, Arg::Ranges(vec![])),
+        ];
+        for (op, arg) in args {
+            let decoded = Command::from_bytes(&command(op, arg.clone()).to_bytes()).unwrap();
+            assert_eq!((decoded.op, decoded.origin, decoded.id), (op, 2, 9));
+            assert_eq!(decoded.key, KEY);
+            assert_eq!(decoded.versioned.value, VALUE);
+            assert_eq!(decoded.versioned.clock, versioned(VALUE).clock);
+            assert_eq!(decoded.arg, arg);
         }
     }
-    return Err(Error::new(ErrorKind::InvalidInput, "Invalid message format"));
-}
\ No newline at end of file
+
+    #[test]
+    fn truncated_commands_are_rejected() {
+        let bytes = command(CAS, Arg::Expected(Some(VALUE.to_vec()))).to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Command::from_bytes(&bytes[..len]).is_err(), "Aceitou {len} de {} bytes", bytes.len());
+        }
+    }
+
+    #[test]
+    fn exchanges_and_tables_keep_any_byte() {
+        let table = Table::from([(KEY.to_vec(), vec![versioned(VALUE), versioned(&[])]), (vec![0x80], vec![versioned(KEY)])]);
+        let exchange = Exchange { op: KEYS, origin: 4, level: 2, nodes: vec![(5, u64::MAX)], table: table.clone(), reply: true };
+        let bytes = exchange.to_bytes();
+        assert!(Exchange::is_exchange(&bytes));
+        let decoded = Exchange::from_bytes(&bytes).unwrap();
+        assert_eq!((decoded.op, decoded.origin, decoded.level, decoded.reply), (KEYS, 4, 2, true));
+        assert_eq!(decoded.nodes, vec![(5, u64::MAX)]);
+        let values = |table: &Table| {
+            let mut values: Vec<(Vec<u8>, Vec<Vec<u8>>)> = table
+                .iter()
+                .map(|(key, siblings)| (key.clone(), siblings.iter().map(|sibling| sibling.value.clone()).collect()))
+                .collect();
+            values.sort();
+            values
+        };
+        assert_eq!(values(&decoded.table), values(&table));
+        assert_eq!(values(&from_table(&to_table(&table)).unwrap()), values(&table));
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let mut bytes = command(WRITE, Arg::None).to_bytes();
+        bytes[0] = FORMAT_VERSION + 1;
+        assert!(Command::from_bytes(&bytes).is_err());
+
+        let exchange = Exchange { op: NODES, origin: 1, level: 0, nodes: vec![(0, 1)], table: Table::new(), reply: false };
+        let mut bytes = exchange.to_bytes();
+        bytes[0] = FORMAT_VERSION + 1;
+        assert!(!Exchange::is_exchange(&bytes));
+        assert!(Exchange::from_bytes(&bytes).is_err());
+        // A command isn't taken for an anti-entropy message either
+        assert!(!Exchange::is_exchange(&command(WRITE, Arg::None).to_bytes()));
+    }
+}
@@ -1,61 +1,1254 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
 
 
 use std::collections::HashMap;
+use logger::debug;
+use relcomm::membership::MembershipEvent;
 use relcomm::reliable_communication::ReliableCommunication;
 
-use crate::formatter;
+use crate::formatter::{self, Arg, Command, Exchange, Table};
+use crate::merkle::{MerkleTree, Repairs};
+use crate::ring::Ring;
+use crate::resolver::Resolver;
+use crate::storage::Storage;
+use crate::version::{self, Clock, VectorClock, Versioned};
+use crate::config::{ANTI_ENTROPY_PERIOD, CATCH_UP_RETRY, LISTENER_IDLE, MERKLE_DEPTH, OPERATION_TIMEOUT, VIRTUAL_NODES};
+
+/// Responses of the replicas of a key
+const OK: u8 = 1;
+const INVALID: u8 = 0;
+// The condition of a conditional write didn't hold, or the replica has no state to give
+const FAILED: u8 = 2;
+// The state transfer request wasn't delivered to the replica yet
+const NOT_YET: u8 = 3;
+// The value of the key isn't a number, or adding the amount to it overflows
+const NOT_A_NUMBER: u8 = 4;
+const OVERFLOW: u8 = 5;
+
+/// The siblings of the key after a command, or why it wasn't applied
+type Applied = Result<Vec<Versioned>, Refusal>;
+
+/// Tables taken for the state transfer requests of other nodes, by origin and id, none if the host had none to give
+type Transfers = HashMap<(usize, u32), Option<Vec<u8>>>;
+
+/// Why a replica didn't apply a command, sent back as the response code in the partitioned mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Refusal {
+    Invalid,
+    // The condition of a conditional write didn't hold
+    Failed,
+    NotANumber,
+    Overflow,
+}
+
+impl Refusal {
+    fn code(self) -> u8 {
+        match self {
+            Refusal::Invalid => INVALID,
+            Refusal::Failed => FAILED,
+            Refusal::NotANumber => NOT_A_NUMBER,
+            Refusal::Overflow => OVERFLOW,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            INVALID => Some(Refusal::Invalid),
+            FAILED => Some(Refusal::Failed),
+            NOT_A_NUMBER => Some(Refusal::NotANumber),
+            OVERFLOW => Some(Refusal::Overflow),
+            _ => None,
+        }
+    }
+
+    /// None if only the condition didn't hold, an error if the command couldn't be applied
+    fn into_result(self) -> Result<Option<Vec<Versioned>>, Error> {
+        match self {
+            Refusal::Failed => Ok(None),
+            Refusal::Invalid => Err(Error::new(ErrorKind::InvalidData, "Replica refused the command")),
+            Refusal::NotANumber => Err(Error::new(ErrorKind::InvalidData, "The value of the key isn't a number")),
+            Refusal::Overflow => Err(Error::new(ErrorKind::InvalidData, "Adding the amount overflows the number of the key")),
+        }
+    }
+}
 
 /// Struct that represents the distributed hash table
+/// Replicated, every node keeps all the keys, or partitioned, each key is kept only by its replicas in the ring
+/// When linearizable, replicated reads and writes are ordered by the atomic broadcast, which relcomm must use,
+/// and return once applied locally, and partitioned reads wait for every replica
+/// Each key keeps its concurrent values (siblings), reads return the value picked by the resolver
+/// A node starting catches up with the state of a living replica before it serves any operation,
+/// and then compares its Merkle tree with a random replica from time to time to repair the keys that diverged
 pub struct DistrHash {
     communication: Arc<ReliableCommunication>,
-    hash_table: Mutex<HashMap<String, String>>,
+    hash_table: Mutex<Table>,
+    // Durable copy of the table, recovered when the node starts
+    storage: Option<Storage>,
+    partition: Option<Partition>,
+    // Timestamps of the writes
+    clock: Clock,
+    // Merged vector clock of the values of each key the host has seen, new writes descend from it
+    seen: Mutex<HashMap<Vec<u8>, VectorClock>>,
+    resolver: Mutex<Resolver>,
+    linearizable: bool,
+    // Id of the next command of the host, and the broadcast commands still waiting to be applied
+    seq: AtomicU32,
+    pending: Mutex<HashMap<u32, Sender<Applied>>>,
+    // Commands delivered while the host catches up, applied over the state it receives, none once it caught up
+    backlog: Mutex<Option<Vec<Command>>>,
+    // Tables taken when the state transfer requests of the other nodes were delivered, by origin and id,
+    // only the latest request of each node, none if the host was catching up too
+    transfers: Mutex<Transfers>,
+    repairs: Mutex<Repairs>,
+    // Cleared when the listener stops, the anti-entropy stops with it
+    listening: AtomicBool,
+}
+
+struct Partition {
+    // Rebuilt when the living members change
+    ring: Mutex<Ring>,
+    // How many nodes keep each key
+    replication: usize,
 }
 
 impl DistrHash {
     pub fn new(
         communication: Arc<ReliableCommunication>,
-    ) -> (Arc<Self>, thread::JoinHandle<()>) {
+        linearizable: bool,
+        storage: Option<Storage>,
+    ) -> Result<(Arc<Self>, thread::JoinHandle<()>), Error> {
+        Self::start(communication, None, linearizable, storage)
+    }
+
+    /// Partitioned table, reads and writes of a key go to its replicas and wait for a quorum of them
+    /// The keys are copied to new replicas whenever a replica dies or the members change
+    pub fn partitioned(
+        communication: Arc<ReliableCommunication>,
+        replication: usize,
+        linearizable: bool,
+        storage: Option<Storage>,
+    ) -> Result<(Arc<Self>, thread::JoinHandle<()>), Error> {
+        let partition = Partition {
+            ring: Mutex::new(Ring::new(&[], VIRTUAL_NODES)),
+            replication: replication.max(1),
+        };
+        Self::start(communication, Some(partition), linearizable, storage)
+    }
+
+    /// The table kept in the storage is recovered before the node answers requests or applies commands,
+    /// and the node catches up with the group before it's returned
+    fn start(
+        communication: Arc<ReliableCommunication>,
+        partition: Option<Partition>,
+        linearizable: bool,
+        storage: Option<Storage>,
+    ) -> Result<(Arc<Self>, thread::JoinHandle<()>), Error> {
+        if partition.is_none() && linearizable && !communication.totally_ordered() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Linearizable replicated tables need the atomic broadcast (AB)"));
+        }
+        let clock = Clock::new(communication.host.agent_number);
+        let table = match &storage {
+            Some(storage) => storage.load()?,
+            None => HashMap::new(),
+        };
+        for sibling in table.values().flatten() {
+            clock.observe(sibling.timestamp);
+        }
+        if storage.is_some() {
+            debug!("Recuperou {} chaves do disco", table.len());
+        }
+        // Only the replicated mode applies commands delivered by the broadcast, they wait until the host caught up
+        let backlog = partition.is_none().then(Vec::new);
         let instance= Arc::new(DistrHash {
+            clock,
             communication,
-            hash_table: Mutex::new(HashMap::new()),
+            hash_table: Mutex::new(table),
+            storage,
+            partition,
+            seen: Mutex::new(HashMap::new()),
+            resolver: Mutex::new(Resolver::Lww),
+            linearizable,
+            seq: AtomicU32::new(0),
+            pending: Mutex::new(HashMap::new()),
+            backlog: Mutex::new(backlog),
+            transfers: Mutex::new(HashMap::new()),
+            repairs: Mutex::new(Repairs::default()),
+            listening: AtomicBool::new(true),
         });
+        instance.communication.register_handler({
+            let instance = instance.clone();
+            move |_, request| instance.handle(request)
+        });
+        if instance.partition.is_some() {
+            let events = instance.communication.subscribe();
+            thread::spawn({
+                let instance = instance.clone();
+                move || {
+                    let mut ring = Ring::new(&instance.living(), VIRTUAL_NODES);
+                    for event in events {
+                        if let MembershipEvent::NodeSuspected(_) | MembershipEvent::LeaderChanged(_) = event {
+                            continue;
+                        }
+                        ring = instance.rebalance(&ring);
+                    }
+                }
+            });
+        }
         let listener_handle = thread::spawn({
             let instance = instance.clone();
             move || {
                 instance.listener();
             }
         });
-        (instance, listener_handle)
+        instance.catch_up();
+        // Replicated and linearizable, the broadcast already keeps the replicas equal, and values copied out of order would break it
+        if instance.partition.is_some() || !instance.linearizable {
+            thread::spawn({
+                let instance = instance.clone();
+                move || {
+                    while instance.listening.load(Ordering::Relaxed) {
+                        thread::sleep(ANTI_ENTROPY_PERIOD);
+                        instance.anti_entropy();
+                    }
+                }
+            });
+        }
+        Ok((instance, listener_handle))
+    }
+
+    /// Copies the state of the group before the host serves any operation, asking every other living node at once
+    /// Replicated, the request is broadcast, so the replicas take their tables at the point of the order where
+    /// the host starts keeping the commands it delivers, which are applied over the table afterwards
+    /// Partitioned, the host merges the keys in the ranges of the ring it replicates, the only ones the nodes send
+    fn catch_up(&self) {
+        let host = self.communication.host.agent_number;
+        let donors: Vec<usize> = self.living().into_iter().filter(|node| *node != host).collect();
+        if self.partition.is_some() {
+            let request = self.command(formatter::SYNC, &[], Versioned::default(), Arg::Ranges(self.ranges())).to_bytes();
+            let mut received = 0;
+            for (donor, answer) in self.scatter(&donors, request) {
+                let state = answer.and_then(|response| match response.split_first() {
+                    Some((&OK, state)) => formatter::from_table(state),
+                    _ => Err(Error::new(ErrorKind::InvalidData, "Invalid response")),
+                });
+                let state = match state {
+                    Ok(state) => state,
+                    Err(e) => {
+                        debug!("Agente {donor} não enviou o estado: {e}");
+                        continue;
+                    }
+                };
+                for (key, siblings) in state {
+                    received += 1;
+                    for sibling in siblings {
+                        self.store(key.clone(), sibling);
+                    }
+                }
+            }
+            debug!("Recebeu {received} chaves dos outros agentes");
+            return;
+        }
+        let state = match self.transfer(donors) {
+            Ok(state) => state,
+            Err(e) => {
+                debug!("Nenhum agente enviou o estado: {e}");
+                None
+            }
+        };
+        let mut backlog = self.backlog.lock().unwrap();
+        if let Some(state) = state {
+            debug!("Recebeu {} chaves dos outros agentes", state.len());
+            let mut table = self.hash_table.lock().unwrap();
+            let dropped: Vec<Vec<u8>> = table.keys().filter(|key| !state.contains_key(*key)).cloned().collect();
+            *table = state;
+            for key in dropped {
+                self.persist(&key, &[], &table);
+            }
+            for (key, siblings) in table.iter() {
+                for sibling in siblings {
+                    self.clock.observe(sibling.timestamp);
+                }
+                self.persist(key, siblings, &table);
+            }
+        }
+        // Without a state every replica was starting, the host keeps its own
+        for command in backlog.take().unwrap_or_default() {
+            let _ = self.apply(command.op, command.key, command.versioned, &command.arg);
+        }
+    }
+
+    /// Broadcasts the state transfer request and asks the replicas for the tables they took when it was delivered,
+    /// all at once and again while it wasn't, the first one that answers gives its state, none if none has one to give
+    fn transfer(&self, mut donors: Vec<usize>) -> Result<Option<Table>, Error> {
+        if donors.is_empty() { return Ok(None); }
+        let command = self.command(formatter::SYNC, &[], Versioned::default(), Arg::None);
+        let request = command.to_bytes();
+        self.ordered(command, false)?;
+        let deadline = Instant::now() + OPERATION_TIMEOUT;
+        while !donors.is_empty() {
+            let mut waiting = Vec::new();
+            for (donor, answer) in self.scatter(&donors, request.clone()) {
+                let response = match answer {
+                    Ok(response) => response,
+                    Err(e) => {
+                        debug!("Agente {donor} não enviou o estado: {e}");
+                        continue;
+                    }
+                };
+                match response.split_first() {
+                    Some((&OK, state)) => return formatter::from_table(state).map(Some),
+                    // The replica is catching up too
+                    Some((&FAILED, _)) => {}
+                    Some((&NOT_YET, _)) => waiting.push(donor),
+                    _ => {
+                        debug!("Resposta inválida do agente {donor} ao pedido de estado");
+                    }
+                }
+            }
+            if !waiting.is_empty() && Instant::now() >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, "The state transfer request wasn't delivered in time"));
+            }
+            donors = waiting;
+            thread::sleep(CATCH_UP_RETRY);
+        }
+        Ok(None)
+    }
+
+    /// How much the anti-entropy of the host compared and repaired so far
+    pub fn repairs(&self) -> Repairs {
+        *self.repairs.lock().unwrap()
+    }
+
+    /// Replaces the resolver used by the reads, last writer wins by default
+    pub fn set_resolver(&self, resolver: Resolver) {
+        *self.resolver.lock().unwrap() = resolver;
+    }
+
+    /// The new value descends from every value of the key the host has seen, so it replaces them
+    /// In the partitioned mode, succeeds once w replicas kept the value
+    pub fn write(&self, key: &[u8], value: &[u8], w: usize) -> Result<(), Error> {
+        let versioned = self.version(key, value);
+        self.put_versioned(key, versioned, w)
+    }
+
+    /// Same as write, but reads stop seeing the value once the time to live passes
+    pub fn write_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration, w: usize) -> Result<(), Error> {
+        let mut versioned = self.version(key, value);
+        versioned.expires = version::now() + ttl.as_micros() as u64;
+        self.put_versioned(key, versioned, w)
+    }
+
+    /// Deletes the key, writing a tombstone that replaces the values it descends from
+    pub fn delete(&self, key: &[u8], w: usize) -> Result<(), Error> {
+        let mut versioned = self.version(key, &[]);
+        versioned.deleted = true;
+        self.put_versioned(key, versioned, w)
+    }
+
+    /// Replaces the value of the key if it's the expected one, returns whether it did
+    pub fn compare_and_swap(&self, key: &[u8], expected: &[u8], new: &[u8]) -> Result<bool, Error> {
+        Ok(self.conditional(formatter::CAS, key, new, Arg::Expected(Some(expected.to_vec())))?.is_some())
+    }
+
+    /// Writes the value only if the key has no visible value, returns whether it did
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, Error> {
+        Ok(self.conditional(formatter::CAS, key, value, Arg::Expected(None))?.is_some())
+    }
+
+    /// Adds the amount to the number kept by the key, a missing key counts as 0, returns the new number
+    /// Fails if the value of the key isn't a number or the new number doesn't fit in an i64
+    pub fn increment(&self, key: &[u8], delta: i64) -> Result<i64, Error> {
+        match self.conditional(formatter::INCREMENT, key, &[], Arg::Delta(delta))? {
+            Some(value) => std::str::from_utf8(&value)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The replica wrote a value that isn't a number")),
+            None => Err(Error::other("The increment wasn't applied")),
+        }
+    }
+
+    /// Conditional writes are totally ordered: in the replicated mode by the atomic broadcast, so every node applies
+    /// them in the same order, and in the partitioned mode by the owner of the key, that copies the result to the other replicas
+    /// Returns the value written, none if the condition didn't hold, and fails in the replicated mode without the atomic broadcast
+    fn conditional(&self, kind: u8, key: &[u8], value: &[u8], arg: Arg) -> Result<Option<Vec<u8>>, Error> {
+        if self.partition.is_none() && !self.communication.totally_ordered() {
+            return Err(Error::new(ErrorKind::Unsupported, "Replicated conditional writes need the atomic broadcast (AB)"));
+        }
+        let command = self.command(kind, key, self.version(key, value), arg);
+        let applied = match self.partition {
+            None => self.ordered(command, true)?,
+            Some(_) => {
+                let owner = match self.replicas(key).first() {
+                    Some(owner) => *owner,
+                    None => return Err(Error::new(ErrorKind::NotConnected, "No replica for the key")),
+                };
+                let response = if owner == self.communication.host.agent_number {
+                    self.handle(command.to_bytes())
+                } else {
+                    self.communication.call(owner, command.to_bytes())?
+                };
+                match response.first().and_then(|code| Refusal::from_code(*code)) {
+                    Some(refusal) => refusal.into_result()?,
+                    None => Some(Self::from_response(response)?),
+                }
+            }
+        };
+        let written = match applied.as_ref().and_then(|siblings| siblings.first()) {
+            Some(written) => written,
+            None => return Ok(None),
+        };
+        self.seen.lock().unwrap().insert(key.to_vec(), written.clock.clone());
+        Ok(Some(written.value.clone()))
+    }
+
+    fn put_versioned(&self, key: &[u8], versioned: Versioned, w: usize) -> Result<(), Error> {
+        self.seen.lock().unwrap().insert(key.to_vec(), versioned.clock.clone());
+        let command = self.command(formatter::WRITE, key, versioned, Arg::None);
+        if self.partition.is_none() {
+            self.ordered(command, self.linearizable)?;
+            return Ok(());
+        }
+        let request = command.to_bytes();
+        let replicas = self.replicas(key);
+        let w = w.clamp(1, self.replication());
+        let mut acks = 0;
+        let mut error = Error::new(ErrorKind::NotConnected, "Not enough replicas for the write quorum");
+        for (_, answer) in self.scatter(&replicas, request) {
+            match answer {
+                Ok(response) if response.first() == Some(&OK) => {
+                    acks += 1;
+                    if acks >= w { return Ok(()); }
+                }
+                Ok(_) => error = Error::other("Replica refused the write"),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// The value of the key, the resolver picks one if there are siblings
+    pub fn read(&self, key: &[u8], r: usize) -> Result<Option<Vec<u8>>, Error> {
+        let siblings = self.read_siblings(key, r)?;
+        Ok(self.resolver.lock().unwrap().resolve(key, &siblings))
+    }
+
+    /// All the concurrent values of the key, none if it doesn't exist
+    /// In the partitioned mode, asks r replicas and merges their values (all replicas when linearizable)
+    /// The replicas found without some of the values are repaired in background
+    pub fn read_siblings(&self, key: &[u8], r: usize) -> Result<Vec<Versioned>, Error> {
+        let siblings = if self.partition.is_none() {
+            if self.linearizable {
+                let command = self.command(formatter::READ, key, Versioned::default(), Arg::None);
+                self.ordered(command, true)?.unwrap_or_default()
+            } else {
+                self.hash_table.lock().unwrap().get(key).cloned().unwrap_or_default()
+            }
+        } else {
+            self.quorum_read(key, r)?
+        };
+        let mut seen = self.seen.lock().unwrap();
+        let clock = seen.entry(key.to_vec()).or_default();
+        for sibling in &siblings {
+            clock.merge(&sibling.clock);
+        }
+        Ok(siblings)
+    }
+
+    fn quorum_read(&self, key: &[u8], r: usize) -> Result<Vec<Versioned>, Error> {
+        let request = self.command(formatter::READ, key, Versioned::default(), Arg::None).to_bytes();
+        let replicas = self.replicas(key);
+        let r = if self.linearizable { self.replication() } else { r.clamp(1, self.replication()) };
+        let mut answers = self.scatter(&replicas, request).into_iter();
+        let mut received = Vec::new();
+        let mut error = Error::new(ErrorKind::NotConnected, "Not enough replicas for the read quorum");
+        for (replica, answer) in answers.by_ref() {
+            match answer.and_then(Self::from_response) {
+                Ok(siblings) => received.push((replica, siblings)),
+                Err(e) => error = e,
+            }
+            if received.len() >= r { break; }
+        }
+        if received.len() < r { return Err(error); }
+        let mut merged = Vec::new();
+        for sibling in received.iter().flat_map(|(_, siblings)| siblings) {
+            version::reconcile(&mut merged, sibling.clone());
+        }
+        for sibling in &merged {
+            self.clock.observe(sibling.timestamp);
+        }
+        self.repair(key, &merged, received, answers);
+        Ok(merged)
+    }
+
+    /// Writes the merged values in the replicas that answered without some of them, including the ones that answer late
+    fn repair(
+        &self,
+        key: &[u8],
+        merged: &[Versioned],
+        received: Vec<(usize, Vec<Versioned>)>,
+        late: impl Iterator<Item = (usize, Result<Vec<u8>, Error>)> + Send + 'static,
+    ) {
+        let host = self.communication.host.agent_number;
+        let clocks: Vec<VectorClock> = merged.iter().map(|sibling| sibling.clock.clone()).collect();
+        let is_stale = move |siblings: &[Versioned]| {
+            clocks.iter().any(|clock| !siblings.iter().any(|sibling| sibling.clock == *clock))
+        };
+        let mut stale = Vec::new();
+        for (replica, siblings) in received {
+            if !is_stale(&siblings) { continue; }
+            if replica == host {
+                for sibling in merged {
+                    self.store(key.to_vec(), sibling.clone());
+                }
+            } else {
+                stale.push(replica);
+            }
+        }
+        let requests: Vec<Vec<u8>> = merged
+            .iter()
+            .map(|sibling| self.command(formatter::WRITE, key, sibling.clone(), Arg::None).to_bytes())
+            .collect();
+        let communication = self.communication.clone();
+        thread::spawn(move || {
+            let late = late.filter_map(|(replica, answer)| match answer.and_then(Self::from_response) {
+                Ok(siblings) if is_stale(&siblings) => Some(replica),
+                _ => None,
+            });
+            for replica in stale.into_iter().chain(late) {
+                debug!("Read-repair da réplica {replica}");
+                for request in &requests {
+                    let _ = communication.call(replica, request.clone());
+                }
+            }
+        });
+    }
+
+    /// Sends the request to every replica at once, the answers are yielded as they arrive
+    /// The host answers its own request right away
+    fn scatter(&self, replicas: &[usize], request: Vec<u8>) -> Receiver<(usize, Result<Vec<u8>, Error>)> {
+        let (answers_tx, answers_rx) = mpsc::channel();
+        for replica in replicas.iter().copied() {
+            if replica == self.communication.host.agent_number {
+                let _ = answers_tx.send((replica, Ok(self.handle(request.clone()))));
+                continue;
+            }
+            match self.communication.call_async(replica, request.clone(), OPERATION_TIMEOUT) {
+                Ok(call) => {
+                    let answers_tx = answers_tx.clone();
+                    thread::spawn(move || {
+                        let _ = answers_tx.send((replica, call.wait()));
+                    });
+                }
+                Err(e) => {
+                    let _ = answers_tx.send((replica, Err(e)));
+                }
+            }
+        }
+        answers_rx
     }
 
-    pub fn write(&self, key: &String, msg: &String) -> Result<(), Error> {
-        let bytes = formatter::to_bytes(key, msg)?;
-        self.communication.broadcast(bytes);
-        Ok(())
+    fn from_response(response: Vec<u8>) -> Result<Vec<Versioned>, Error> {
+        match response.split_first() {
+            Some((&OK, siblings)) => formatter::from_siblings(siblings),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Invalid response")),
+        }
+    }
+
+    /// A new value of the key, descending from the values the host has seen or keeps
+    fn version(&self, key: &[u8], value: &[u8]) -> Versioned {
+        let timestamp = self.clock.next();
+        let mut clock = self.seen.lock().unwrap().get(key).cloned().unwrap_or_default();
+        if let Some(siblings) = self.hash_table.lock().unwrap().get(key) {
+            for sibling in siblings {
+                clock.merge(&sibling.clock);
+            }
+        }
+        clock.set(timestamp.node, timestamp.counter);
+        Versioned { clock, timestamp, value: value.to_vec(), ..Default::default() }
     }
 
-    pub fn read(&self, key: &String) -> Option<String> {
-        let table = self.hash_table.lock().unwrap();
-        table.get(key).cloned()
+    /// A command of the host, with a new id
+    fn command(&self, op: u8, key: &[u8], versioned: Versioned, arg: Arg) -> Command {
+        Command {
+            op,
+            origin: self.communication.host.agent_number,
+            id: self.seq.fetch_add(1, Ordering::Relaxed),
+            key: key.to_vec(),
+            versioned,
+            arg,
+        }
     }
 
+    /// Broadcasts a command, if asked waits until the listener applies it and returns the siblings of the key then,
+    /// none if the condition of the command didn't hold, an error if it couldn't be applied
+    fn ordered(&self, command: Command, wait: bool) -> Result<Option<Vec<Versioned>>, Error> {
+        let seq = command.id;
+        let bytes = command.to_bytes();
+        if !wait {
+            self.communication.broadcast(bytes)?;
+            return Ok(Some(vec![]));
+        }
+        let (applied_tx, applied_rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(seq, applied_tx);
+        if let Err(e) = self.communication.broadcast(bytes) {
+            self.pending.lock().unwrap().remove(&seq);
+            return Err(e);
+        }
+        match applied_rx.recv_timeout(OPERATION_TIMEOUT) {
+            Ok(Ok(siblings)) => Ok(Some(siblings)),
+            Ok(Err(refusal)) => refusal.into_result(),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&seq);
+                Err(Error::new(ErrorKind::TimedOut, "The command wasn't delivered in time"))
+            }
+        }
+    }
+
+    fn replication(&self) -> usize {
+        self.partition.as_ref().map_or(1, |partition| partition.replication)
+    }
+
+    /// The replica set of the key, the ring is rebuilt when the living members change
+    fn replicas(&self, key: &[u8]) -> Vec<usize> {
+        let partition = match &self.partition {
+            Some(partition) => partition,
+            None => return vec![],
+        };
+        self.ring(partition).replicas(key, partition.replication)
+    }
+
+    /// The ranges of the ring the host replicates
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        match &self.partition {
+            Some(partition) => self.ring(partition).ranges(self.communication.host.agent_number, partition.replication),
+            None => vec![],
+        }
+    }
+
+    /// The ring of the living members, rebuilt if they changed
+    fn ring<'a>(&self, partition: &'a Partition) -> MutexGuard<'a, Ring> {
+        let living = self.living();
+        let mut ring = partition.ring.lock().unwrap();
+        if ring.nodes() != living.as_slice() {
+            *ring = Ring::new(&living, VIRTUAL_NODES);
+        }
+        ring
+    }
+
+    fn living(&self) -> Vec<usize> {
+        self.communication
+            .members()
+            .iter()
+            .filter(|node| node.is_member() && !node.is_dead())
+            .map(|node| node.agent_number)
+            .collect()
+    }
+
+    /// Adds the value to the siblings of the key, unless a value that descends from it is already stored,
+    /// returns whether it did
+    fn store(&self, key: Vec<u8>, versioned: Versioned) -> bool {
+        self.clock.observe(versioned.timestamp);
+        let mut table = self.hash_table.lock().unwrap();
+        let siblings = table.entry(key.clone()).or_default();
+        if !version::reconcile(siblings, versioned) { return false; }
+        let siblings = siblings.clone();
+        self.persist(&key, &siblings, &table);
+        true
+    }
+
+    /// Logs the change of the key in the storage, if there's one, with the table still locked
+    fn persist(&self, key: &[u8], siblings: &[Versioned], table: &Table) {
+        if let Some(storage) = &self.storage {
+            if let Err(e) = storage.append(key, siblings, table) {
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+
+    /// Stores the value in a replica, locally if it's the host
+    fn put(&self, replica: usize, key: &[u8], versioned: &Versioned) -> Result<(), Error> {
+        if replica == self.communication.host.agent_number {
+            self.store(key.to_vec(), versioned.clone());
+            return Ok(());
+        }
+        let request = self.command(formatter::WRITE, key, versioned.clone(), Arg::None).to_bytes();
+        match self.communication.call(replica, request)?.first() {
+            Some(&OK) => Ok(()),
+            _ => Err(Error::other("Replica refused the write")),
+        }
+    }
+
+    /// Copies the keys the host replicated in the previous ring to the replicas that joined their sets,
+    /// then drops the keys the host doesn't replicate anymore, returning the new ring
+    fn rebalance(&self, previous: &Ring) -> Ring {
+        let replication = match &self.partition {
+            Some(partition) => partition.replication,
+            None => return Ring::new(&[], VIRTUAL_NODES),
+        };
+        let ring = Ring::new(&self.living(), VIRTUAL_NODES);
+        if ring.nodes() == previous.nodes() { return ring; }
+        let host = self.communication.host.agent_number;
+        let entries: Vec<(Vec<u8>, Vec<Versioned>)> = self.hash_table
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, siblings)| (key.clone(), siblings.clone()))
+            .collect();
+        let mut copied = 0;
+        for (key, siblings) in entries {
+            let before = previous.replicas(&key, replication);
+            let after = ring.replicas(&key, replication);
+            let mut placed = true;
+            for replica in after.iter().filter(|replica| !before.contains(replica) && **replica != host) {
+                for sibling in &siblings {
+                    if let Err(e) = self.put(*replica, &key, sibling) {
+                        eprintln!("Error: {}", e);
+                        placed = false;
+                    }
+                }
+                copied += 1;
+            }
+            if placed && !after.contains(&host) {
+                let mut table = self.hash_table.lock().unwrap();
+                table.remove(&key);
+                self.persist(&key, &[], &table);
+            }
+        }
+        debug!("Replicou {copied} chaves após mudança nos membros");
+        ring
+    }
+
+    /// Answers the requests for the keys replicated by the host, the conditional writes for the keys it owns
+    /// and the state transfers
+    fn handle(&self, request: Vec<u8>) -> Vec<u8> {
+        let command = match Command::from_bytes(&request) {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return vec![INVALID];
+            }
+        };
+        if command.op == formatter::SYNC {
+            let state = match (&self.partition, &command.arg) {
+                (Some(_), Arg::Ranges(ranges)) => {
+                    let asked: Table = self
+                        .hash_table
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|(key, _)| Ring::in_ranges(key, ranges))
+                        .map(|(key, siblings)| (key.clone(), siblings.clone()))
+                        .collect();
+                    Some(formatter::to_table(&asked))
+                }
+                (Some(_), _) => return vec![INVALID],
+                (None, _) => match self.transfers.lock().unwrap().remove(&(command.origin, command.id)) {
+                    Some(state) => state,
+                    None => return vec![NOT_YET],
+                },
+            };
+            return match state {
+                Some(state) => [vec![OK], state].concat(),
+                None => vec![FAILED],
+            };
+        }
+        let conditional = matches!(command.op, formatter::CAS | formatter::INCREMENT);
+        match self.apply(command.op, command.key.clone(), command.versioned, &command.arg) {
+            Ok(siblings) => {
+                if conditional {
+                    self.replicate(&command.key, &siblings);
+                }
+                let mut response = vec![OK];
+                response.extend(formatter::to_siblings(&siblings));
+                response
+            }
+            Err(refusal) => vec![refusal.code()],
+        }
+    }
+
+    /// Applies a command or request, returning the siblings of the key after it, or why it wasn't applied
+    /// A conditional write descends from every sibling and replaces them, its result depends on the order it's applied
+    fn apply(&self, kind: u8, key: Vec<u8>, mut versioned: Versioned, arg: &Arg) -> Applied {
+        if kind == formatter::READ {
+            return Ok(self.hash_table.lock().unwrap().get(&key).cloned().unwrap_or_default());
+        }
+        self.clock.observe(versioned.timestamp);
+        let mut table = self.hash_table.lock().unwrap();
+        let siblings = table.entry(key.clone()).or_default();
+        match kind {
+            // The broadcast orders the writes, each one replaces the values before it
+            formatter::WRITE if self.linearizable && self.partition.is_none() => *siblings = vec![versioned],
+            formatter::WRITE => {
+                if !version::reconcile(siblings, versioned) {
+                    return Ok(siblings.clone());
+                }
+            }
+            // Already in the values the anti-entropy copied from a replica that applied it
+            formatter::CAS | formatter::INCREMENT if siblings.iter().any(|sibling| sibling.clock.descends(&versioned.clock)) => {
+                return Ok(siblings.clone());
+            }
+            formatter::CAS | formatter::INCREMENT => {
+                let current = self.resolver.lock().unwrap().resolve(&key, siblings);
+                match arg {
+                    Arg::Expected(expected) if kind == formatter::CAS => {
+                        if current != *expected { return Err(Refusal::Failed); }
+                    }
+                    Arg::Delta(delta) if kind == formatter::INCREMENT => {
+                        let current: i64 = match current {
+                            Some(current) => std::str::from_utf8(&current)
+                                .ok()
+                                .and_then(|current| current.parse().ok())
+                                .ok_or(Refusal::NotANumber)?,
+                            None => 0,
+                        };
+                        versioned.value = current.checked_add(*delta).ok_or(Refusal::Overflow)?.to_string().into_bytes();
+                    }
+                    _ => return Err(Refusal::Invalid),
+                }
+                for sibling in siblings.iter() {
+                    versioned.clock.merge(&sibling.clock);
+                }
+                *siblings = vec![versioned];
+            }
+            _ => return Err(Refusal::Invalid),
+        }
+        let siblings = siblings.clone();
+        self.persist(&key, &siblings, &table);
+        Ok(siblings)
+    }
+
+    /// Copies the result of a conditional write to the other replicas of the key, in background
+    /// The RPC handler doesn't wait for them, since they may be waiting for this node
+    fn replicate(&self, key: &[u8], siblings: &[Versioned]) {
+        let host = self.communication.host.agent_number;
+        let replicas: Vec<usize> = self.replicas(key).into_iter().filter(|replica| *replica != host).collect();
+        let requests: Vec<Vec<u8>> = siblings
+            .iter()
+            .map(|sibling| self.command(formatter::WRITE, key, sibling.clone(), Arg::None).to_bytes())
+            .collect();
+        let communication = self.communication.clone();
+        thread::spawn(move || {
+            for replica in replicas {
+                for request in &requests {
+                    if let Err(e) = communication.call(replica, request.clone()) {
+                        eprintln!("Error: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Applies the commands in the order they are delivered, answering the ones the host is waiting for,
+    /// and the anti-entropy messages
+    /// It stops once no command arrives and no key is repaired for a while, unless a command of the host is still pending
     fn listener(&self) {
+        let mut active = Instant::now();
         loop {
             let mut buffer = vec![];
-            if !self.communication.receive(&mut buffer) { break ; }
-            match formatter::from_bytes(buffer) {
-                Ok((key, value)) => {
-                    let mut table = self.hash_table.lock().unwrap();
-                    table.insert(key, value);
+            let received = self.communication.receive(&mut buffer);
+            if received && !Exchange::is_exchange(&buffer) {
+                active = Instant::now();
+            }
+            if active.elapsed() >= LISTENER_IDLE
+                && self.pending.lock().unwrap().is_empty()
+                && self.backlog.lock().unwrap().is_none() { break; }
+            if !received { continue; }
+            if Exchange::is_exchange(&buffer) {
+                match Exchange::from_bytes(&buffer) {
+                    Ok(exchange) => {
+                        if self.exchange(exchange) {
+                            active = Instant::now();
+                        }
+                    }
+                    Err(e) => eprintln!("Error: {}", e),
+                }
+                continue;
+            }
+            match Command::from_bytes(&buffer) {
+                Ok(command) if command.op == formatter::SYNC => self.synchronize(command),
+                Ok(command) => {
+                    if let Some(backlog) = self.backlog.lock().unwrap().as_mut() {
+                        backlog.push(command);
+                        continue;
+                    }
+                    let applied = self.apply(command.op, command.key, command.versioned, &command.arg);
+                    if command.origin != self.communication.host.agent_number { continue; }
+                    if let Some(applied_tx) = self.pending.lock().unwrap().remove(&command.id) {
+                        let _ = applied_tx.send(applied);
+                    }
                 }
                 Err(e) => {
                     eprintln!("Error: {}", e);
                 }
             }
         }
+        self.listening.store(false, Ordering::Relaxed);
     }
 
+    /// Starts a comparison with a random living replica, sending it the root of the tree of the keys they share
+    fn anti_entropy(&self) {
+        let host = self.communication.host.agent_number;
+        let peers: Vec<usize> = self.living().into_iter().filter(|node| *node != host).collect();
+        if peers.is_empty() { return; }
+        let peer = peers[rand::random::<usize>() % peers.len()];
+        let tree = MerkleTree::new(self.shared(peer).iter(), MERKLE_DEPTH);
+        self.repairs.lock().unwrap().rounds += 1;
+        self.send(peer, Exchange {
+            op: formatter::NODES,
+            origin: host,
+            level: 0,
+            nodes: vec![(0, tree.root())],
+            table: HashMap::new(),
+            reply: false,
+        });
+    }
+
+    /// Answers an anti-entropy message: the nodes received are compared with the ones of the host, and the children
+    /// of the ones that differ are sent back, or the keys in them once they are leaves
+    /// The keys received are stored, and the host answers with its own keys in the same leaves if asked
+    /// Returns whether some key was repaired
+    fn exchange(&self, message: Exchange) -> bool {
+        // The state received when catching up replaces the table
+        if self.backlog.lock().unwrap().is_some() { return false; }
+        let host = self.communication.host.agent_number;
+        let peer = message.origin;
+        match message.op {
+            formatter::NODES => {
+                let tree = MerkleTree::new(self.shared(peer).iter(), MERKLE_DEPTH);
+                let differ = tree.differ(message.level, &message.nodes);
+                if differ.is_empty() { return false; }
+                let answer = if message.level == tree.depth() {
+                    self.repairs.lock().unwrap().ranges += differ.len() as u32;
+                    self.keys(peer, differ, true)
+                } else {
+                    Exchange {
+                        op: formatter::NODES,
+                        origin: host,
+                        level: message.level + 1,
+                        nodes: tree.children(message.level, &differ),
+                        table: HashMap::new(),
+                        reply: false,
+                    }
+                };
+                self.send(peer, answer);
+                false
+            }
+            formatter::KEYS => {
+                let mut repaired = 0;
+                for (key, siblings) in message.table {
+                    if self.partition.is_some() && !self.replicas(&key).contains(&host) { continue; }
+                    let mut changed = false;
+                    for sibling in siblings {
+                        changed |= self.store(key.clone(), sibling);
+                    }
+                    repaired += changed as u32;
+                }
+                if repaired > 0 {
+                    debug!("Anti-entropia reparou {repaired} chaves com o agente {peer}");
+                }
+                self.repairs.lock().unwrap().keys_repaired += repaired;
+                if message.reply {
+                    let leaves = message.nodes.iter().map(|(leaf, _)| *leaf).collect();
+                    self.send(peer, self.keys(peer, leaves, false));
+                }
+                repaired > 0
+            }
+            _ => false,
+        }
+    }
+
+    /// The keys the host shares with the peer in the leaves
+    fn keys(&self, peer: usize, leaves: Vec<u32>, reply: bool) -> Exchange {
+        let table: Table = self
+            .shared(peer)
+            .into_iter()
+            .filter(|(key, _)| leaves.contains(&MerkleTree::leaf(key, MERKLE_DEPTH)))
+            .collect();
+        self.repairs.lock().unwrap().keys_sent += table.len() as u32;
+        Exchange {
+            op: formatter::KEYS,
+            origin: self.communication.host.agent_number,
+            level: MERKLE_DEPTH,
+            nodes: leaves.into_iter().map(|leaf| (leaf, 0)).collect(),
+            table,
+            reply,
+        }
+    }
+
+    /// The keys kept by both the host and the peer, in the partitioned mode the ones they both replicate
+    fn shared(&self, peer: usize) -> Table {
+        let host = self.communication.host.agent_number;
+        let table = self.hash_table.lock().unwrap().clone();
+        table
+            .into_iter()
+            .filter(|(_, siblings)| !siblings.is_empty())
+            .filter(|(key, _)| {
+                if self.partition.is_none() { return true; }
+                let replicas = self.replicas(key);
+                replicas.contains(&host) && replicas.contains(&peer)
+            })
+            .collect()
+    }
+
+    fn send(&self, peer: usize, message: Exchange) {
+        if self.communication.send(peer, message.to_bytes()) == 0 {
+            debug!("Falha ao enviar anti-entropia para o agente {peer}");
+        }
+    }
+
+    /// A state transfer request delivered: the commands the host delivered before its own request are in the state
+    /// it will receive, and every other replica takes its table now, in case it's asked for it
+    fn synchronize(&self, command: Command) {
+        if command.origin == self.communication.host.agent_number {
+            if let Some(backlog) = self.backlog.lock().unwrap().as_mut() {
+                backlog.clear();
+            }
+            return;
+        }
+        let state = match *self.backlog.lock().unwrap() {
+            Some(_) => None,
+            None => Some(formatter::to_table(&self.hash_table.lock().unwrap())),
+        };
+        let mut transfers = self.transfers.lock().unwrap();
+        // The node caught up with a single replica, the tables of its earlier requests are never asked for
+        transfers.retain(|(origin, _), _| *origin != command.origin);
+        transfers.insert((command.origin, command.id), state);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use relcomm::node::Node;
+    use logger::{initializate_test_folder, free_ports};
+
+    /// A partitioned table kept by the host alone, its operations never leave the process
+    fn alone() -> Arc<DistrHash> {
+        initializate_test_folder!();
+        let host = Node::new(SocketAddr::from(([127, 0, 0, 1], free_ports!(1)[0])), 0);
+        let communication = ReliableCommunication::new(host.clone(), vec![host]).expect("Erro ao criar o Agent");
+        DistrHash::partitioned(communication, 1, false, None).expect("Erro ao criar a tabela").0
+    }
+
+    /// A replicated table of a group with the host alone, its commands still go through the broadcast
+    fn replicated() -> Arc<DistrHash> {
+        initializate_test_folder!();
+        let host = Node::new(SocketAddr::from(([127, 0, 0, 1], free_ports!(1)[0])), 0);
+        let communication = ReliableCommunication::new(host.clone(), vec![host]).expect("Erro ao criar o Agent");
+        DistrHash::new(communication, true, None).expect("Erro ao criar a tabela").0
+    }
+
+    /// The nodes of a group, none of them keeping a table yet
+    fn group(size: usize) -> Vec<Arc<ReliableCommunication>> {
+        initializate_test_folder!();
+        let nodes: Vec<Node> = free_ports!(size)
+            .into_iter()
+            .enumerate()
+            .map(|(i, port)| Node::new(SocketAddr::from(([127, 0, 0, 1], port)), i))
+            .collect();
+        nodes
+            .iter()
+            .map(|node| ReliableCommunication::new(node.clone(), nodes.clone()).expect("Erro ao criar o Agent"))
+            .collect()
+    }
+
+    /// Starts the tables of the nodes at once, each one catches up with the others
+    fn start<F>(communications: Vec<Arc<ReliableCommunication>>, table: F) -> Vec<Arc<DistrHash>>
+    where
+        F: Fn(Arc<ReliableCommunication>) -> Result<(Arc<DistrHash>, thread::JoinHandle<()>), Error> + Copy + Send + 'static,
+    {
+        communications
+            .into_iter()
+            .map(|communication| thread::spawn(move || table(communication).expect("Erro ao criar a tabela").0))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|starting| starting.join().expect("Thread da tabela falhou"))
+            .collect()
+    }
+
+    #[test]
+    fn nodes_catch_up_with_the_keys_of_their_ranges_only() {
+        let mut communications = group(3);
+        let late = communications.pop().unwrap();
+        let tables = start(communications, |communication| DistrHash::partitioned(communication, 2, false, None));
+        let keys: Vec<Vec<u8>> = (0..40u8).map(|i| vec![b'k', i, 0xff]).collect();
+        for key in &keys {
+            // Acknowledged by every replica already keeping a table
+            let w = tables[0].replicas(key).iter().filter(|replica| **replica != 2).count();
+            tables[0].write(key, key, w).unwrap();
+        }
+        // Both donors are asked at once, and send only the keys the late node replicates
+        let started = Instant::now();
+        let table = DistrHash::partitioned(late, 2, false, None).unwrap().0;
+        assert!(started.elapsed() < OPERATION_TIMEOUT);
+        let kept = table.hash_table.lock().unwrap().clone();
+        let replicated: Vec<&Vec<u8>> = keys.iter().filter(|key| table.replicas(key).contains(&2)).collect();
+        assert!(!replicated.is_empty());
+        assert_eq!(kept.len(), replicated.len());
+        for key in replicated {
+            assert_eq!(kept[key].first().map(|sibling| sibling.value.clone()), Some(key.clone()));
+        }
+    }
+
+    #[test]
+    fn quorums_that_overlap_see_the_last_write() {
+        // Every node replicates every key, the last one is alive but keeps no table, so it never answers
+        let mut communications = group(3);
+        let _down = communications.pop().unwrap();
+        let tables = start(communications, |communication| DistrHash::partitioned(communication, 3, false, None));
+        // W = 2 and R = 2 of N = 3, both quorums share a replica
+        for i in 0..5u8 {
+            tables[(i % 2) as usize].write(b"chave", &[i], 2).unwrap();
+            assert_eq!(tables[((i + 1) % 2) as usize].read(b"chave", 2).unwrap(), Some(vec![i]));
+        }
+        assert!(tables[0].write(b"chave", b"todos", 3).is_err());
+        assert!(tables[0].read(b"chave", 3).is_err());
+    }
+
+    #[test]
+    fn reads_repair_the_replicas_missing_the_value() {
+        let tables = start(group(2), |communication| DistrHash::partitioned(communication, 2, false, None));
+        // Kept by a single replica, as if the write to the other one was lost
+        let versioned = tables[0].version(b"chave", b"valor");
+        tables[0].store(b"chave".to_vec(), versioned);
+        assert_eq!(tables[1].read(b"chave", 2).unwrap(), Some(b"valor".to_vec()));
+        let deadline = Instant::now() + OPERATION_TIMEOUT;
+        while !tables[1].hash_table.lock().unwrap().contains_key(b"chave".as_slice()) {
+            assert!(Instant::now() < deadline, "A réplica não foi reparada");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn anti_entropy_repairs_diverging_replicas() {
+        let tables = start(group(2), |communication| DistrHash::partitioned(communication, 2, false, None));
+        // Each replica missed the writes of the other
+        for (i, table) in tables.iter().enumerate() {
+            for key in 0..10u8 {
+                table.store(vec![i as u8, key], table.version(&[i as u8, key], &[key]));
+            }
+        }
+        let deadline = Instant::now() + 10 * ANTI_ENTROPY_PERIOD;
+        while tables.iter().any(|table| table.hash_table.lock().unwrap().len() < 20) {
+            assert!(Instant::now() < deadline, "As réplicas não convergiram");
+            thread::sleep(Duration::from_millis(50));
+        }
+        let repairs: u32 = tables.iter().map(|table| table.repairs().keys_repaired).sum();
+        assert_eq!(repairs, 20);
+        assert!(tables.iter().map(|table| table.repairs().ranges).sum::<u32>() > 0);
+    }
+
+    #[test]
+    fn linearizable_reads_see_the_last_write_of_any_node() {
+        let tables = start(group(3), |communication| DistrHash::new(communication, true, None));
+        for i in 0..9usize {
+            let value = format!("valor {i}").into_bytes();
+            tables[i % 3].write(b"chave", &value, 1).unwrap();
+            assert_eq!(tables[(i + 1) % 3].read(b"chave", 1).unwrap(), Some(value));
+        }
+    }
+
+    #[test]
+    fn writes_replace_the_siblings_they_saw() {
+        let table = alone();
+        table.write(b"chave", b"a", 1).unwrap();
+        // Written by a node that never saw the value of the host
+        let mut concurrent = Versioned { value: b"b".to_vec(), ..Default::default() };
+        concurrent.clock.set(7, 1);
+        concurrent.timestamp = version::Timestamp { counter: version::now() + 1_000_000, node: 7 };
+        assert!(table.store(b"chave".to_vec(), concurrent));
+        assert_eq!(table.read_siblings(b"chave", 1).unwrap().len(), 2);
+        assert_eq!(table.read(b"chave", 1).unwrap(), Some(b"b".to_vec()));
+        table.set_resolver(Resolver::from_name("CONCAT").unwrap());
+        assert_eq!(table.read(b"chave", 1).unwrap(), Some(b"ab".to_vec()));
+        table.write(b"chave", b"c", 1).unwrap();
+        assert_eq!(table.read_siblings(b"chave", 1).unwrap().len(), 1);
+        assert_eq!(table.read(b"chave", 1).unwrap(), Some(b"c".to_vec()));
+    }
+
+    #[test]
+    fn deletes_hide_the_value() {
+        let table = alone();
+        table.write(b"chave", b"valor", 1).unwrap();
+        assert_eq!(table.read(b"chave", 1).unwrap(), Some(b"valor".to_vec()));
+        table.delete(b"chave", 1).unwrap();
+        assert_eq!(table.read(b"chave", 1).unwrap(), None);
+        // The tombstone replaces the value, it isn't a sibling of it
+        assert_eq!(table.read_siblings(b"chave", 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn compare_and_swap_needs_the_expected_value() {
+        let table = alone();
+        assert!(!table.compare_and_swap(b"chave", b"antes", b"depois").unwrap());
+        assert!(table.put_if_absent(b"chave", b"antes").unwrap());
+        assert!(!table.put_if_absent(b"chave", b"outro").unwrap());
+        assert!(!table.compare_and_swap(b"chave", b"outro", b"depois").unwrap());
+        assert!(table.compare_and_swap(b"chave", b"antes", b"depois").unwrap());
+        assert_eq!(table.read(b"chave", 1).unwrap(), Some(b"depois".to_vec()));
+        table.delete(b"chave", 1).unwrap();
+        assert!(table.put_if_absent(b"chave", b"de novo").unwrap());
+    }
+
+    #[test]
+    fn increments_tell_their_failures_apart() {
+        let table = alone();
+        assert_eq!(table.increment(b"contador", 5).unwrap(), 5);
+        assert_eq!(table.increment(b"contador", -7).unwrap(), -2);
+        assert_eq!(table.read(b"contador", 1).unwrap(), Some(b"-2".to_vec()));
+
+        table.write(b"texto", b"nove", 1).unwrap();
+        let error = table.increment(b"texto", 1).unwrap_err();
+        assert!(error.to_string().contains("isn't a number"), "{error}");
+
+        table.write(b"grande", i64::MAX.to_string().as_bytes(), 1).unwrap();
+        let error = table.increment(b"grande", 1).unwrap_err();
+        assert!(error.to_string().contains("overflows"), "{error}");
+        // Neither failure changed the value
+        assert_eq!(table.read(b"texto", 1).unwrap(), Some(b"nove".to_vec()));
+        assert_eq!(table.read(b"grande", 1).unwrap(), Some(i64::MAX.to_string().into_bytes()));
+    }
+
+    #[test]
+    fn ordered_conditional_writes_tell_their_failures_apart() {
+        let table = replicated();
+        assert!(table.put_if_absent(b"contador", b"1").unwrap());
+        assert!(!table.compare_and_swap(b"contador", b"2", b"3").unwrap());
+        assert_eq!(table.increment(b"contador", 2).unwrap(), 3);
+        table.write(b"texto", b"nove", 1).unwrap();
+        assert!(table.increment(b"texto", 1).unwrap_err().to_string().contains("isn't a number"));
+        table.write(b"grande", i64::MIN.to_string().as_bytes(), 1).unwrap();
+        assert!(table.increment(b"grande", -1).unwrap_err().to_string().contains("overflows"));
+        assert_eq!(table.read(b"contador", 1).unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn refusals_keep_their_code() {
+        for refusal in [Refusal::Invalid, Refusal::Failed, Refusal::NotANumber, Refusal::Overflow] {
+            assert_eq!(Refusal::from_code(refusal.code()), Some(refusal));
+        }
+        assert_eq!(Refusal::from_code(OK), None);
+        assert!(matches!(Refusal::Failed.into_result(), Ok(None)));
+        assert!(Refusal::Overflow.into_result().is_err());
+    }
+
+    #[test]
+    fn values_expire_after_their_ttl() {
+        let table = alone();
+        table.write_with_ttl(b"sessao", b"dono", Duration::from_millis(300), 1).unwrap();
+        table.write(b"fixa", b"valor", 1).unwrap();
+        assert_eq!(table.read(b"sessao", 1).unwrap(), Some(b"dono".to_vec()));
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(table.read(b"sessao", 1).unwrap(), None);
+        assert_eq!(table.read(b"fixa", 1).unwrap(), Some(b"valor".to_vec()));
+        // An expired key counts as absent
+        assert!(table.put_if_absent(b"sessao", b"outro dono").unwrap());
+    }
 }
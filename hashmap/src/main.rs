@@ -2,10 +2,16 @@ mod config;
 mod hashmap;
 mod agent;
 mod formatter;
+mod ring;
+mod checker;
+mod version;
+mod resolver;
+mod storage;
+mod merkle;
 
 use logger::{initializate_folders, debug_file};
 
-use config::{AGENT_NUM, TESTS_NUM};
+use config::{STORAGE_DIR, TESTS_NUM};
 use agent::create_agents;
 
 fn main() {
@@ -14,6 +20,10 @@ fn main() {
     if args.len() == 1 {
         initializate_folders!(TESTS_NUM);
         for t in 0..TESTS_NUM {
+            // Cada teste começa sem estado salvo, só agentes reiniciados durante o teste recuperam do disco
+            if std::fs::metadata(STORAGE_DIR).is_ok() {
+                std::fs::remove_dir_all(STORAGE_DIR).expect("Erro ao deletar a pasta de armazenamento");
+            }
             let mut children = Vec::new();
             for i in 0..(t+5) {
                 let c = std::process::Command::new(std::env::current_exe().unwrap())
@@ -27,6 +37,15 @@ fn main() {
             for mut c in children {
                 c.wait().expect("Falha ao esperar processo filho");
             }
+            // Verificar o histórico de operações de todos os agentes
+            let folder = format!("tests/test_{t}");
+            let history = checker::load(&folder).expect("Falha ao ler o histórico dos agentes");
+            let violations = checker::check(&history);
+            let mut msg = format!("{} operations, {} violations\n", history.len(), violations.len());
+            for violation in violations.iter().take(10) {
+                msg += &format!("{violation}\n");
+            }
+            debug_file!(format!("{folder}/Resultado.txt"), &msg.as_bytes());
         }
     } else if args.len() == 3 {
         // Sub-processo: Execução do agente
@@ -37,18 +56,19 @@ fn main() {
             .parse()
             .expect("Falha ao converter agent_id para usize");
 
-        let agent = create_agents(
-            agent_id,
-            AGENT_NUM,
-        );
-        let time = match agent {
+        let agent = create_agents(agent_id);
+        let (time, history, repairs) = match agent {
             Ok(agent) => agent.run(),
             Err(e) => panic!("Falha ao criar agente {}: {}", agent_id, e),
         };
+        let history: String = history.iter().map(|operation| operation.to_line()).collect();
+        debug_file!(format!("tests/test_{test_id}/history_{agent_id}.txt"), &history.as_bytes());
         // Output the results to a file
         let file_path = format!("tests/test_{test_id}/Resultado.txt");
-        let msg = format!("AGENTE {agent_id} -> {} seconds to broadcast {} messages\n", time.as_secs_f32(),
+        let mut msg = format!("AGENTE {agent_id} -> {} seconds to broadcast {} messages\n", time.as_secs_f32(),
         config::MSG_NUM);
+        msg += &format!("AGENTE {agent_id} -> anti-entropy: {} rounds, {} ranges differed, {} keys sent, {} keys repaired\n",
+            repairs.rounds, repairs.ranges, repairs.keys_sent, repairs.keys_repaired);
         println!("{},", time.as_secs_f32());
         debug_file!(file_path, &msg.as_bytes());
     } else {
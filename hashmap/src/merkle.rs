@@ -0,0 +1,172 @@
+use crate::ring::hash;
+use crate::version::Versioned;
+
+/// Merkle tree over the keys of a table: each key falls in a leaf by its hash, a leaf hashes the versions of the
+/// values of its keys, and each node above hashes its two children
+/// Two replicas with the same root keep the same values, otherwise they compare the children of the nodes that
+/// differ, level by level, and exchange only the keys in the leaves that differ
+pub struct MerkleTree {
+    // The root at level 0, the leaves at the last level
+    levels: Vec<Vec<u64>>,
+}
+
+impl MerkleTree {
+    pub fn new<'a>(entries: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<Versioned>)>, depth: u8) -> Self {
+        let mut leaves = vec![0; 1 << depth];
+        for (key, siblings) in entries {
+            if siblings.is_empty() { continue; }
+            // The siblings are sorted so replicas that keep them in other orders get the same hash,
+            // and the keys of a leaf are combined by xor, since the tables are iterated in any order
+            let mut versions: Vec<Vec<u8>> = siblings.iter().map(|sibling| sibling.meta_to_bytes()).collect();
+            versions.sort();
+            let mut bytes = (key.len() as u32).to_be_bytes().to_vec();
+            bytes.extend_from_slice(key);
+            for version in versions {
+                bytes.extend((version.len() as u32).to_be_bytes());
+                bytes.extend(version);
+            }
+            leaves[Self::leaf(key, depth) as usize] ^= hash(&bytes);
+        }
+        let mut levels = vec![leaves];
+        while levels[0].len() > 1 {
+            let parents = levels[0]
+                .chunks(2)
+                .map(|children| hash(&[children[0].to_be_bytes(), children[1].to_be_bytes()].concat()))
+                .collect();
+            levels.insert(0, parents);
+        }
+        MerkleTree { levels }
+    }
+
+    /// The leaf of the key in a tree of the depth
+    pub fn leaf(key: &[u8], depth: u8) -> u32 {
+        (hash(key) % (1 << depth)) as u32
+    }
+
+    pub fn depth(&self) -> u8 {
+        (self.levels.len() - 1) as u8
+    }
+
+    pub fn root(&self) -> u64 {
+        self.levels[0][0]
+    }
+
+    /// The nodes at the level whose hashes differ from the ones given
+    pub fn differ(&self, level: u8, nodes: &[(u32, u64)]) -> Vec<u32> {
+        let hashes = match self.levels.get(level as usize) {
+            Some(hashes) => hashes,
+            None => return vec![],
+        };
+        nodes
+            .iter()
+            .filter(|(index, hash)| hashes.get(*index as usize).is_some_and(|own| own != hash))
+            .map(|(index, _)| *index)
+            .collect()
+    }
+
+    /// The children of the nodes at the level, with their hashes
+    pub fn children(&self, level: u8, indices: &[u32]) -> Vec<(u32, u64)> {
+        let children = match self.levels.get(level as usize + 1) {
+            Some(children) => children,
+            None => return vec![],
+        };
+        indices
+            .iter()
+            .flat_map(|index| [2 * index, 2 * index + 1])
+            .filter_map(|child| children.get(child as usize).map(|hash| (child, *hash)))
+            .collect()
+    }
+}
+
+/// How much the anti-entropy of a node compared and repaired
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Repairs {
+    // Comparisons the node started
+    pub rounds: u32,
+    // Leaves found different, whose keys were exchanged
+    pub ranges: u32,
+    pub keys_sent: u32,
+    // Keys whose values changed with the keys received
+    pub keys_repaired: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatter::Table;
+    use crate::version::Timestamp;
+
+    const DEPTH: u8 = 4;
+
+    fn table(keys: impl Iterator<Item = u32>, counter: u64) -> Table {
+        keys.map(|key| {
+            let versioned = Versioned { timestamp: Timestamp { counter, node: 0 }, ..Default::default() };
+            (key.to_be_bytes().to_vec(), vec![versioned])
+        })
+        .collect()
+    }
+
+    /// The leaves where the trees differ, found as the replicas do: from the root down, only under the nodes that differ
+    fn diff(own: &MerkleTree, other: &MerkleTree) -> Vec<u32> {
+        let mut differing = own.differ(0, &[(0, other.root())]);
+        for level in 0..own.depth() {
+            let children = other.children(level, &differing);
+            differing = own.differ(level + 1, &children);
+        }
+        differing.sort();
+        differing
+    }
+
+    #[test]
+    fn equal_tables_have_the_same_root() {
+        let mut pushed = table(0..50, 1);
+        let table = table(0..50, 1);
+        // The sibling order doesn't matter either
+        let key = 7u32.to_be_bytes().to_vec();
+        let other = Versioned { timestamp: Timestamp { counter: 2, node: 1 }, ..Default::default() };
+        pushed.get_mut(&key).unwrap().push(other.clone());
+        let mut with_other = table.clone();
+        with_other.get_mut(&key).unwrap().insert(0, other);
+        assert_eq!(MerkleTree::new(pushed.iter(), DEPTH).root(), MerkleTree::new(with_other.iter(), DEPTH).root());
+        assert_ne!(MerkleTree::new(table.iter(), DEPTH).root(), MerkleTree::new(with_other.iter(), DEPTH).root());
+        // Keys without siblings count as missing
+        let mut emptied = table.clone();
+        emptied.insert(b"vazia".to_vec(), vec![]);
+        assert_eq!(MerkleTree::new(emptied.iter(), DEPTH).root(), MerkleTree::new(table.iter(), DEPTH).root());
+        assert!(diff(&MerkleTree::new(table.iter(), DEPTH), &MerkleTree::new(emptied.iter(), DEPTH)).is_empty());
+    }
+
+    #[test]
+    fn diverging_trees_differ_only_in_the_leaves_of_the_changed_keys() {
+        let own = table(0..100, 1);
+        let mut other = own.clone();
+        // A newer version, a key the other lacks and a key only the other has
+        other.extend(table([3].into_iter(), 2));
+        other.remove(40u32.to_be_bytes().as_slice());
+        other.extend(table([500].into_iter(), 1));
+        let mut expected: Vec<u32> = [3u32, 40, 500].iter().map(|key| MerkleTree::leaf(&key.to_be_bytes(), DEPTH)).collect();
+        expected.sort();
+        expected.dedup();
+        let (own, other) = (MerkleTree::new(own.iter(), DEPTH), MerkleTree::new(other.iter(), DEPTH));
+        assert_eq!(own.depth(), DEPTH);
+        assert_eq!(diff(&own, &other), expected);
+        assert_eq!(diff(&other, &own), expected);
+    }
+
+    #[test]
+    fn leaves_depend_only_on_the_bytes() {
+        // The low bits of the position of the key on the ring, the same in every build
+        assert_eq!(MerkleTree::leaf(b"key1", DEPTH), 0x8);
+        let empty = MerkleTree::new(Table::new().iter(), 1);
+        assert_eq!(empty.root(), hash(&[0; 16]));
+    }
+
+    #[test]
+    fn unknown_levels_and_nodes_are_ignored() {
+        let tree = MerkleTree::new(table(0..10, 1).iter(), DEPTH);
+        assert!(tree.differ(DEPTH + 1, &[(0, 1)]).is_empty());
+        assert!(tree.differ(0, &[(5, 1)]).is_empty());
+        assert!(tree.children(DEPTH, &[0]).is_empty());
+        assert_eq!(tree.children(0, &[0]).len(), 2);
+    }
+}
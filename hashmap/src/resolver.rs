@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use crate::version::{self, Versioned};
+
+/// Merges the values of the siblings of the key into the value returned
+pub type MergeFn = Arc<dyn Fn(&[u8], &[Vec<u8>]) -> Vec<u8> + Send + Sync>;
+
+/// Picks the value returned by a read when the key has concurrent values (siblings)
+#[derive(Clone)]
+pub enum Resolver {
+    /// Last writer wins, the sibling with the newest timestamp
+    Lww,
+    /// Custom merge of the key and the values of the living siblings, from the oldest timestamp to the newest
+    Merge(MergeFn),
+}
+
+impl Resolver {
+    /// "LWW", or "CONCAT" to merge by joining the values of the siblings
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "LWW" => Some(Resolver::Lww),
+            "CONCAT" => Some(Resolver::Merge(Arc::new(|_, values| values.concat()))),
+            _ => None,
+        }
+    }
+
+    /// The visible value among the siblings, none if the key was deleted or expired
+    pub fn resolve(&self, key: &[u8], siblings: &[Versioned]) -> Option<Vec<u8>> {
+        let now = version::now();
+        match self {
+            // A delete or an expired value newer than the others hides them
+            Resolver::Lww => siblings
+                .iter()
+                .max_by_key(|sibling| sibling.timestamp)
+                .filter(|sibling| sibling.is_live(now))
+                .map(|sibling| sibling.value.clone()),
+            Resolver::Merge(merge) => {
+                let mut siblings: Vec<&Versioned> = siblings.iter().filter(|sibling| sibling.is_live(now)).collect();
+                match siblings.len() {
+                    0 => None,
+                    1 => Some(siblings[0].value.clone()),
+                    _ => {
+                        siblings.sort_by_key(|sibling| sibling.timestamp);
+                        let values: Vec<Vec<u8>> = siblings.into_iter().map(|sibling| sibling.value.clone()).collect();
+                        Some(merge(key, &values))
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::Timestamp;
+
+    fn sibling(counter: u64, value: &str) -> Versioned {
+        Versioned { timestamp: Timestamp { counter, node: 0 }, value: value.into(), ..Default::default() }
+    }
+
+    #[test]
+    fn last_writer_wins() {
+        let siblings = [sibling(2, "b"), sibling(3, "c"), sibling(1, "a")];
+        assert_eq!(Resolver::Lww.resolve(b"chave", &siblings), Some(b"c".to_vec()));
+        // Ties are broken by the node
+        let tied = [sibling(3, "c"), Versioned { timestamp: Timestamp { counter: 3, node: 1 }, value: b"d".to_vec(), ..Default::default() }];
+        assert_eq!(Resolver::Lww.resolve(b"chave", &tied), Some(b"d".to_vec()));
+        assert_eq!(Resolver::Lww.resolve(b"chave", &[]), None);
+    }
+
+    #[test]
+    fn newer_deletes_and_expired_values_hide_the_key() {
+        let mut deleted = sibling(4, "");
+        deleted.deleted = true;
+        assert_eq!(Resolver::Lww.resolve(b"chave", &[sibling(3, "c"), deleted.clone()]), None);
+        assert_eq!(Resolver::Lww.resolve(b"chave", &[sibling(5, "e"), deleted]), Some(b"e".to_vec()));
+        let mut expired = sibling(6, "f");
+        expired.expires = 1;
+        assert_eq!(Resolver::Lww.resolve(b"chave", &[sibling(5, "e"), expired]), None);
+    }
+
+    #[test]
+    fn merges_see_the_living_siblings_from_the_oldest() {
+        let concat = Resolver::from_name("CONCAT").unwrap();
+        let mut deleted = sibling(2, "x");
+        deleted.deleted = true;
+        let siblings = [sibling(3, "c"), deleted, sibling(1, "a")];
+        assert_eq!(concat.resolve(b"chave", &siblings), Some(b"ac".to_vec()));
+        assert_eq!(concat.resolve(b"chave", &siblings[..2]), Some(b"c".to_vec()));
+        let keyed = Resolver::Merge(Arc::new(|key, values| [key.to_vec(), values.len().to_string().into_bytes()].concat()));
+        assert_eq!(keyed.resolve(b"k", &siblings), Some(b"k2".to_vec()));
+        assert!(Resolver::from_name("OUTRO").is_none());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+
+/// Consistent-hashing ring, every node is placed on several points (virtual nodes) so the keys spread evenly
+/// When a node enters or leaves, only the keys between its points and the previous ones change owner
+pub struct Ring {
+    points: BTreeMap<u64, usize>,
+    nodes: Vec<usize>,
+}
+
+impl Ring {
+    pub fn new(nodes: &[usize], virtual_nodes: usize) -> Self {
+        let mut points = BTreeMap::new();
+        for node in nodes {
+            for i in 0..virtual_nodes {
+                let point = [(*node as u32).to_be_bytes(), (i as u32).to_be_bytes()].concat();
+                points.insert(hash(&point), *node);
+            }
+        }
+        Ring {
+            points,
+            nodes: nodes.to_vec(),
+        }
+    }
+
+    pub fn nodes(&self) -> &[usize] {
+        &self.nodes
+    }
+
+    /// The replica set of the key: the first n distinct nodes clockwise from the hash of the key
+    /// The first one, at the point right after the hash of the key, is its owner
+    pub fn replicas(&self, key: &[u8], n: usize) -> Vec<usize> {
+        self.replicas_from(hash(key), n)
+    }
+
+    /// The positions of the keys the node replicates, as inclusive ranges
+    /// The keys from right after a point up to the next one have the same replica set
+    pub fn ranges(&self, node: usize, n: usize) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::new();
+        let mut previous = self.points.keys().next_back().copied();
+        for point in self.points.keys().copied() {
+            let after = previous.replace(point).unwrap();
+            if !self.replicas_from(point, n).contains(&node) { continue; }
+            if after < point {
+                ranges.push((after + 1, point));
+            } else {
+                // The range of the first point wraps around the end of the ring
+                if after < u64::MAX {
+                    ranges.push((after + 1, u64::MAX));
+                }
+                ranges.push((0, point));
+            }
+        }
+        ranges
+    }
+
+    /// Whether the key is in one of the ranges
+    pub fn in_ranges(key: &[u8], ranges: &[(u64, u64)]) -> bool {
+        let hash = hash(key);
+        ranges.iter().any(|(start, end)| (*start..=*end).contains(&hash))
+    }
+
+    fn replicas_from(&self, hash: u64, n: usize) -> Vec<usize> {
+        let mut replicas = Vec::new();
+        for (_, node) in self.points.range(hash..).chain(self.points.range(..hash)) {
+            if replicas.len() == n.min(self.nodes.len()) { break; }
+            if !replicas.contains(node) {
+                replicas.push(*node);
+            }
+        }
+        replicas
+    }
+}
+
+/// FNV-1a over the bytes, with the bits mixed at the end (as in MurmurHash3) so close inputs land far apart
+/// Positions are exchanged between nodes, so they depend only on the bytes, never on the build
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> impl Iterator<Item = [u8; 4]> {
+        (0..1000u32).map(|i| i.to_be_bytes())
+    }
+
+    #[test]
+    fn positions_depend_only_on_the_bytes() {
+        // FNV-1a of the empty input, mixed, fixed in every build and platform
+        assert_eq!(hash(&[]), 0xefd01f60ba992926);
+        assert_eq!(hash(b"key1"), 0xdde145d7536e77b8);
+        assert_eq!(Ring::new(&[0, 1, 2], 64).replicas(b"key1", 2), vec![2, 1]);
+    }
+
+    #[test]
+    fn keys_spread_over_every_node() {
+        let ring = Ring::new(&[0, 1, 2, 3], 64);
+        let mut owned = [0; 4];
+        for key in keys() {
+            owned[ring.replicas(&key, 1)[0]] += 1;
+        }
+        assert!(owned.iter().all(|count| *count > 1000 / 8), "{owned:?}");
+    }
+
+    #[test]
+    fn placement_doesnt_depend_on_the_order_of_the_nodes() {
+        let ring = Ring::new(&[0, 1, 2], 64);
+        let shuffled = Ring::new(&[2, 0, 1], 64);
+        assert!(keys().all(|key| ring.replicas(&key, 1) == shuffled.replicas(&key, 1)));
+    }
+
+    #[test]
+    fn a_new_node_only_takes_keys() {
+        let before = Ring::new(&[0, 1, 2], 64);
+        let after = Ring::new(&[0, 1, 2, 3], 64);
+        let mut moved = 0;
+        for key in keys() {
+            let (old, new) = (before.replicas(&key, 1)[0], after.replicas(&key, 1)[0]);
+            if old != new {
+                assert_eq!(new, 3);
+                moved += 1;
+            }
+        }
+        // About a quarter of the keys, the share of the new node
+        assert!((100..450).contains(&moved), "{moved}");
+    }
+
+    #[test]
+    fn replica_sets_are_distinct_nodes_led_by_the_owner() {
+        let ring = Ring::new(&[0, 1, 2, 3, 4], 64);
+        for key in keys() {
+            let replicas = ring.replicas(&key, 3);
+            assert_eq!(replicas.len(), 3);
+            assert_eq!(replicas[0], ring.replicas(&key, 1)[0]);
+            assert!(replicas.iter().all(|replica| replicas.iter().filter(|other| *other == replica).count() == 1));
+        }
+        // Never more replicas than nodes
+        assert_eq!(Ring::new(&[0, 1], 64).replicas(b"chave", 3).len(), 2);
+        assert!(Ring::new(&[], 64).replicas(b"chave", 3).is_empty());
+    }
+
+    #[test]
+    fn a_dead_node_is_replaced_by_the_next_one() {
+        let before = Ring::new(&[0, 1, 2, 3, 4], 64);
+        let after = Ring::new(&[0, 1, 3, 4], 64);
+        for key in keys() {
+            let (old, new) = (before.replicas(&key, 3), after.replicas(&key, 3));
+            let kept: Vec<usize> = old.iter().copied().filter(|replica| *replica != 2).collect();
+            // The surviving replicas keep the key, in the same order, so only the missing copy must be made
+            assert_eq!(new[..kept.len()], kept[..], "{old:?} {new:?}");
+            if kept.len() == old.len() {
+                assert_eq!(new, old);
+            }
+        }
+    }
+
+    #[test]
+    fn ranges_hold_exactly_the_keys_the_node_replicates() {
+        let ring = Ring::new(&[0, 1, 2, 3], 16);
+        for node in 0..4 {
+            let ranges = ring.ranges(node, 2);
+            for i in 0..500u32 {
+                let key = i.to_be_bytes();
+                assert_eq!(Ring::in_ranges(&key, &ranges), ring.replicas(&key, 2).contains(&node), "chave {i}");
+            }
+        }
+        // A node alone replicates the whole ring
+        let ranges = Ring::new(&[5], 16).ranges(5, 2);
+        assert!((0..500u32).all(|i| Ring::in_ranges(&i.to_be_bytes(), &ranges)));
+        assert!(Ring::new(&[0, 1], 16).ranges(7, 2).is_empty());
+    }
+}
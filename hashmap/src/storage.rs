@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Error, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::formatter::Table;
+use crate::ring::hash;
+use crate::version::Versioned;
+
+const WAL: &str = "wal";
+const SNAPSHOT: &str = "snapshot";
+
+/// Durable copy of the table in a folder of the local disk: a snapshot of the table plus an append-only log (WAL)
+/// Each log record has a key with all its siblings after a change, none if the key was dropped, so replaying
+/// a record twice gives the same table, and records already in the snapshot may be replayed again
+/// Each record carries a checksum, a record cut or garbled by a crash ends the replay, and is dropped so the records
+/// appended afterwards are replayed
+pub struct Storage {
+    folder: PathBuf,
+    wal: Mutex<File>,
+    // Records appended since the last snapshot, and how many trigger a new snapshot
+    appended: Mutex<usize>,
+    snapshot_every: usize,
+    sync: bool,
+}
+
+impl Storage {
+    /// Opens the folder, creating it if needed, sync makes every record reach the disk before returning
+    pub fn open(folder: impl Into<PathBuf>, snapshot_every: usize, sync: bool) -> Result<Self, Error> {
+        let folder = folder.into();
+        fs::create_dir_all(&folder)?;
+        let wal = OpenOptions::new().create(true).append(true).open(folder.join(WAL))?;
+        Ok(Storage {
+            folder,
+            wal: Mutex::new(wal),
+            appended: Mutex::new(0),
+            snapshot_every: snapshot_every.max(1),
+            sync,
+        })
+    }
+
+    /// The table kept on disk: the snapshot with the log replayed over it, the log is cut after its last whole record
+    pub fn load(&self) -> Result<Table, Error> {
+        let mut table = HashMap::new();
+        for file in [SNAPSHOT, WAL] {
+            let path = self.folder.join(file);
+            if !path.exists() { continue; }
+            let mut bytes = Vec::new();
+            BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+            let mut start = 0;
+            while let Some((key, siblings)) = Self::from_bytes(&bytes, &mut start) {
+                if siblings.is_empty() {
+                    table.remove(&key);
+                } else {
+                    table.insert(key, siblings);
+                }
+            }
+            if file == WAL && start < bytes.len() {
+                self.wal.lock().unwrap().set_len(start as u64)?;
+            }
+        }
+        Ok(table)
+    }
+
+    /// Logs the siblings of the key after a change, taking a snapshot of the table once enough records were logged
+    /// Called with the table locked, so the records are in the order the changes happened
+    pub fn append(&self, key: &[u8], siblings: &[Versioned], table: &Table) -> Result<(), Error> {
+        let mut wal = self.wal.lock().unwrap();
+        wal.write_all(&Self::to_bytes(key, siblings))?;
+        if self.sync {
+            wal.sync_data()?;
+        }
+        let mut appended = self.appended.lock().unwrap();
+        *appended += 1;
+        if *appended < self.snapshot_every { return Ok(()); }
+        self.snapshot(&mut wal, table)?;
+        *appended = 0;
+        Ok(())
+    }
+
+    /// Writes the whole table to a new snapshot and empties the log
+    /// The snapshot replaces the old one only once it's complete, so a crash keeps the old snapshot and the log
+    fn snapshot(&self, wal: &mut File, table: &Table) -> Result<(), Error> {
+        let temporary = self.folder.join(format!("{SNAPSHOT}.tmp"));
+        let mut file = File::create(&temporary)?;
+        let mut bytes = Vec::new();
+        for (key, siblings) in table {
+            bytes.extend(Self::to_bytes(key, siblings));
+        }
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temporary, self.folder.join(SNAPSHOT))?;
+        wal.set_len(0)?;
+        wal.sync_all()
+    }
+
+    /// Length and checksum of the record, then the key and the siblings, each prefixed by its length
+    fn to_bytes(key: &[u8], siblings: &[Versioned]) -> Vec<u8> {
+        let mut record = (key.len() as u32).to_be_bytes().to_vec();
+        record.extend_from_slice(key);
+        record.extend((siblings.len() as u32).to_be_bytes());
+        for sibling in siblings {
+            record.extend(sibling.to_bytes());
+        }
+        let mut bytes = (record.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(hash(&record).to_be_bytes());
+        bytes.extend(record);
+        bytes
+    }
+
+    /// The record at the start, which is moved past it only if the whole record is valid
+    fn from_bytes(bytes: &[u8], start: &mut usize) -> Option<(Vec<u8>, Vec<Versioned>)> {
+        let len = u32::from_be_bytes(bytes.get(*start..*start + 4)?.try_into().ok()?) as usize;
+        let checksum = u64::from_be_bytes(bytes.get(*start + 4..*start + 12)?.try_into().ok()?);
+        let record = bytes.get(*start + 12..*start + 12 + len)?;
+        if hash(record) != checksum { return None; }
+        let key_len = u32::from_be_bytes(record.get(0..4)?.try_into().ok()?) as usize;
+        let key = record.get(4..4 + key_len)?.to_vec();
+        let mut position = 4 + key_len;
+        let count = u32::from_be_bytes(record.get(position..position + 4)?.try_into().ok()?);
+        position += 4;
+        let siblings = (0..count)
+            .map(|_| Versioned::from_bytes(record, &mut position))
+            .collect::<Option<Vec<Versioned>>>()?;
+        if position != record.len() { return None; }
+        *start += 12 + len;
+        Some((key, siblings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::Timestamp;
+
+    /// An empty folder of the test
+    fn folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("hashmap_storage_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        folder
+    }
+
+    fn versioned(counter: u64, value: &[u8]) -> Versioned {
+        Versioned { timestamp: Timestamp { counter, node: 0 }, value: value.to_vec(), ..Default::default() }
+    }
+
+    /// Applies the change to the table and logs it, as the table does
+    fn change(storage: &Storage, table: &mut Table, key: &[u8], siblings: Vec<Versioned>) {
+        if siblings.is_empty() {
+            table.remove(key);
+        } else {
+            table.insert(key.to_vec(), siblings.clone());
+        }
+        storage.append(key, &siblings, table).unwrap();
+    }
+
+    fn values(table: &Table) -> Vec<(Vec<u8>, Vec<Vec<u8>>)> {
+        let mut values: Vec<(Vec<u8>, Vec<Vec<u8>>)> = table
+            .iter()
+            .map(|(key, siblings)| (key.clone(), siblings.iter().map(|sibling| sibling.value.clone()).collect()))
+            .collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn the_log_is_replayed_over_the_snapshot() {
+        let folder = folder("replay");
+        let mut table = Table::new();
+        {
+            let storage = Storage::open(&folder, 3, false).unwrap();
+            change(&storage, &mut table, &[0xff, 0x00], vec![versioned(1, b"a"), versioned(2, b"b")]);
+            change(&storage, &mut table, b"outra", vec![versioned(3, b"c")]);
+            change(&storage, &mut table, b"apagada", vec![versioned(4, b"d")]);
+            // Taken by the third record, the rest is only in the log
+            change(&storage, &mut table, b"apagada", vec![]);
+            change(&storage, &mut table, b"outra", vec![versioned(5, &[0x80])]);
+        }
+        let loaded = Storage::open(&folder, 3, false).unwrap().load().unwrap();
+        assert_eq!(values(&loaded), values(&table));
+        assert!(!loaded.contains_key(b"apagada".as_slice()));
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    /// Logs two records, lets the crash damage the log, and checks that only the first record is replayed,
+    /// and that a record appended afterwards is replayed too
+    fn recovers_from(name: &str, crash: impl Fn(&mut Vec<u8>)) {
+        let folder = folder(name);
+        let mut table = Table::new();
+        {
+            let storage = Storage::open(&folder, 100, true).unwrap();
+            change(&storage, &mut table, b"inteira", vec![versioned(1, b"a")]);
+            change(&storage, &mut table, b"danificada", vec![versioned(2, b"b")]);
+        }
+        let mut bytes = fs::read(folder.join(WAL)).unwrap();
+        crash(&mut bytes);
+        fs::write(folder.join(WAL), bytes).unwrap();
+
+        let storage = Storage::open(&folder, 100, true).unwrap();
+        let mut loaded = storage.load().unwrap();
+        assert_eq!(values(&loaded), vec![(b"inteira".to_vec(), vec![b"a".to_vec()])], "{name}");
+        change(&storage, &mut loaded, b"depois", vec![versioned(3, b"c")]);
+        drop(storage);
+        let reloaded = Storage::open(&folder, 100, true).unwrap().load().unwrap();
+        assert_eq!(values(&reloaded), values(&loaded), "{name}");
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn a_torn_record_ends_the_replay_and_is_dropped() {
+        recovers_from("torn", |bytes| bytes.truncate(bytes.len() - 3));
+    }
+
+    #[test]
+    fn a_garbled_record_ends_the_replay_and_is_dropped() {
+        // The last record replaced by zeros, its length included
+        recovers_from("zeros", |bytes| {
+            let first = 12 + u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+            bytes[first..].fill(0);
+        });
+        // A byte of the value changed, the record still parses
+        recovers_from("flipped", |bytes| {
+            let last = bytes.len() - 1;
+            bytes[last] ^= 1;
+        });
+        // Zeros written after the last record
+        recovers_from("padded", |bytes| {
+            let first = 12 + u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+            bytes.truncate(first);
+            bytes.extend([0; 64]);
+        });
+    }
+}
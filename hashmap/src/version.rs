@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Wall-clock timestamp of a write, greater means newer, ties are broken by the node that wrote it
+/// Only used to pick a winner among concurrent values, causality is given by the vector clocks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub counter: u64,
+    pub node: usize,
+}
+
+impl Timestamp {
+    pub const SIZE: usize = 12;
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = self.counter.to_be_bytes().to_vec();
+        bytes.extend((self.node as u32).to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Timestamp {
+            counter: u64::from_be_bytes(bytes.get(0..8)?.try_into().ok()?),
+            node: u32::from_be_bytes(bytes.get(8..12)?.try_into().ok()?) as usize,
+        })
+    }
+}
+
+/// Hands out timestamps newer than every timestamp the node has seen
+/// The counter follows the wall clock, so a write usually gets a newer timestamp than the writes that completed before it
+pub struct Clock {
+    node: usize,
+    last: Mutex<u64>,
+}
+
+impl Clock {
+    pub fn new(node: usize) -> Self {
+        Clock { node, last: Mutex::new(0) }
+    }
+
+    pub fn next(&self) -> Timestamp {
+        let mut last = self.last.lock().unwrap();
+        *last = now().max(*last + 1);
+        Timestamp { counter: *last, node: self.node }
+    }
+
+    pub fn observe(&self, timestamp: Timestamp) {
+        let mut last = self.last.lock().unwrap();
+        *last = timestamp.counter.max(*last);
+    }
+}
+
+/// Counter of the writes of each node that a value has seen
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VectorClock(BTreeMap<usize, u64>);
+
+impl VectorClock {
+    /// Whether every write seen by the other clock was also seen by this one
+    pub fn descends(&self, other: &VectorClock) -> bool {
+        other.0.iter().all(|(node, counter)| self.0.get(node).is_some_and(|own| own >= counter))
+    }
+
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, counter) in &other.0 {
+            let own = self.0.entry(*node).or_insert(0);
+            *own = (*own).max(*counter);
+        }
+    }
+
+    /// Counts a write of the node, the counter must be greater than the previous ones of the node
+    pub fn set(&mut self, node: usize, counter: u64) {
+        self.0.insert(node, counter);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.0.len() as u16).to_be_bytes().to_vec();
+        for (node, counter) in &self.0 {
+            bytes.extend((*node as u32).to_be_bytes());
+            bytes.extend(counter.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8], start: &mut usize) -> Option<Self> {
+        let len = u16::from_be_bytes(bytes.get(*start..*start + 2)?.try_into().ok()?);
+        *start += 2;
+        let mut clock = BTreeMap::new();
+        for _ in 0..len {
+            let node = u32::from_be_bytes(bytes.get(*start..*start + 4)?.try_into().ok()?) as usize;
+            let counter = u64::from_be_bytes(bytes.get(*start + 4..*start + 12)?.try_into().ok()?);
+            clock.insert(node, counter);
+            *start += 12;
+        }
+        Some(VectorClock(clock))
+    }
+}
+
+/// Microseconds since the epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+/// A value with the version of the write that produced it
+/// Deletes are kept as tombstones, so they replace the values they descend from like any write
+#[derive(Clone, Debug, Default)]
+pub struct Versioned {
+    pub clock: VectorClock,
+    pub timestamp: Timestamp,
+    pub value: Vec<u8>,
+    pub deleted: bool,
+    // When the value stops being visible, in microseconds since the epoch, 0 if never
+    pub expires: u64,
+}
+
+impl Versioned {
+    /// Whether reads see the value at the time given
+    pub fn is_live(&self, now: u64) -> bool {
+        !self.deleted && (self.expires == 0 || self.expires > now)
+    }
+
+    /// The version without the value
+    pub fn meta_to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.timestamp.to_bytes();
+        bytes.push(self.deleted as u8);
+        bytes.extend(self.expires.to_be_bytes());
+        bytes.extend(self.clock.to_bytes());
+        bytes
+    }
+
+    /// The version read from the bytes, with an empty value
+    pub fn meta_from_bytes(bytes: &[u8], start: &mut usize) -> Option<Self> {
+        let timestamp = Timestamp::from_bytes(bytes.get(*start..)?)?;
+        *start += Timestamp::SIZE;
+        let deleted = *bytes.get(*start)? != 0;
+        let expires = u64::from_be_bytes(bytes.get(*start + 1..*start + 9)?.try_into().ok()?);
+        *start += 9;
+        let clock = VectorClock::from_bytes(bytes, start)?;
+        Some(Versioned { clock, timestamp, value: Vec::new(), deleted, expires })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.meta_to_bytes();
+        bytes.extend((self.value.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.value);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8], start: &mut usize) -> Option<Self> {
+        let mut versioned = Self::meta_from_bytes(bytes, start)?;
+        let len = u32::from_be_bytes(bytes.get(*start..*start + 4)?.try_into().ok()?) as usize;
+        *start += 4;
+        versioned.value = bytes.get(*start..*start + len)?.to_vec();
+        *start += len;
+        Some(versioned)
+    }
+}
+
+/// Adds the value to the siblings unless one of them already descends from it, dropping the ones it descends from
+/// The siblings left are concurrent, and the result doesn't depend on the order the values arrive
+pub fn reconcile(siblings: &mut Vec<Versioned>, incoming: Versioned) -> bool {
+    if siblings.iter().any(|sibling| sibling.clock.descends(&incoming.clock)) { return false; }
+    siblings.retain(|sibling| !incoming.clock.descends(&sibling.clock));
+    siblings.push(incoming);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(writes: &[(usize, u64)], counter: u64, value: &str) -> Versioned {
+        let mut clock = VectorClock::default();
+        for (node, count) in writes {
+            clock.set(*node, *count);
+        }
+        Versioned { clock, timestamp: Timestamp { counter, node: writes[0].0 }, value: value.into(), ..Default::default() }
+    }
+
+    #[test]
+    fn clocks_order_only_the_writes_that_saw_each_other() {
+        let first = written(&[(0, 1)], 1, "a");
+        let after = written(&[(0, 1), (1, 1)], 2, "b");
+        let concurrent = written(&[(2, 1)], 3, "c");
+        assert!(after.clock.descends(&first.clock));
+        assert!(!first.clock.descends(&after.clock));
+        assert!(!after.clock.descends(&concurrent.clock) && !concurrent.clock.descends(&after.clock));
+        let mut merged = after.clock.clone();
+        merged.merge(&concurrent.clock);
+        assert!(merged.descends(&after.clock) && merged.descends(&concurrent.clock));
+        let mut start = 0;
+        assert_eq!(VectorClock::from_bytes(&merged.to_bytes(), &mut start), Some(merged));
+    }
+
+    #[test]
+    fn concurrent_writes_become_siblings() {
+        let mut siblings = Vec::new();
+        assert!(reconcile(&mut siblings, written(&[(0, 1)], 1, "a")));
+        assert!(reconcile(&mut siblings, written(&[(1, 1)], 2, "b")));
+        assert_eq!(siblings.len(), 2);
+        // Already seen, or older than a sibling
+        assert!(!reconcile(&mut siblings, written(&[(1, 1)], 2, "b")));
+        assert!(!reconcile(&mut siblings, written(&[(0, 0)], 0, "velho")));
+        // A write that saw both replaces them
+        assert!(reconcile(&mut siblings, written(&[(0, 1), (1, 1), (2, 1)], 3, "c")));
+        let values: Vec<&[u8]> = siblings.iter().map(|sibling| sibling.value.as_slice()).collect();
+        assert_eq!(values, vec![b"c".as_slice()]);
+    }
+
+    #[test]
+    fn siblings_dont_depend_on_the_arrival_order() {
+        let writes = [written(&[(0, 1)], 1, "a"), written(&[(1, 1)], 2, "b"), written(&[(0, 1), (2, 1)], 3, "c")];
+        let values = |order: &[usize]| {
+            let mut siblings = Vec::new();
+            for i in order {
+                reconcile(&mut siblings, writes[*i].clone());
+            }
+            let mut values: Vec<Vec<u8>> = siblings.into_iter().map(|sibling| sibling.value).collect();
+            values.sort();
+            values
+        };
+        assert_eq!(values(&[0, 1, 2]), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(values(&[2, 1, 0]), values(&[0, 1, 2]));
+        assert_eq!(values(&[1, 2, 0]), values(&[0, 1, 2]));
+    }
+
+    #[test]
+    fn versions_keep_their_fields_in_bytes() {
+        let mut versioned = written(&[(0, 4), (3, 2)], 9, "valor");
+        versioned.deleted = true;
+        versioned.expires = 77;
+        let mut start = 0;
+        let decoded = Versioned::from_bytes(&versioned.to_bytes(), &mut start).unwrap();
+        assert_eq!((decoded.clock, decoded.timestamp, decoded.value), (versioned.clock, versioned.timestamp, versioned.value));
+        assert_eq!((decoded.deleted, decoded.expires), (true, 77));
+    }
+}
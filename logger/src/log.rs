@@ -90,3 +90,26 @@ macro_rules! debug {
         std::io::Write::write_all(&mut file, msf.as_bytes()).expect("Erro ao escrever no arquivo");
     };
 }
+
+/// creates the tests folder of the crate, where the debug messages of its unit tests are written
+#[macro_export]
+macro_rules! initializate_test_folder {
+    () => {
+        std::fs::create_dir_all("tests").expect("Erro ao criar a pasta 'tests'");
+    };
+}
+
+/// reserves the given number of local ports for the unit tests
+/// the ports are picked by the system, so tests running in parallel never share them
+#[macro_export]
+macro_rules! free_ports {
+    ($ports_num:expr) => {{
+        let sockets: Vec<std::net::UdpSocket> = (0..$ports_num)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").expect("Erro ao reservar uma porta"))
+            .collect();
+        sockets
+            .iter()
+            .map(|socket| socket.local_addr().expect("Erro ao ler a porta reservada").port())
+            .collect::<Vec<u16>>()
+    }};
+}
@@ -0,0 +1,231 @@
+/*
+A camada de anti-entropia (Anti-Entropy) complementa a difusão por fofoca:
+periodicamente cada processo envia para um par aleatório o resumo das difusões que já entregou,
+e o par responde reenviando as difusões que estiverem faltando.
+*/
+use std::thread;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::collections::{HashMap, VecDeque};
+
+use logger::debug;
+use crate::config::{GOSSIP_HISTORY, GOSSIP_INTERVAL};
+use crate::header::Header;
+use crate::multicast::Multicast;
+use crate::node::Node;
+use crate::rec_aux::{SendRequest, SendRequestData, RecAux};
+
+/// Delivered broadcasts of each origin and group id, stored as (first seq_num, next seq_num, message)
+pub type History = Arc<Mutex<HashMap<(SocketAddr, u32), VecDeque<(u32, u32, Vec<u8>)>>>>;
+
+pub struct AntiEntropy {
+    host: Node,
+    group: Arc<Mutex<Vec<Node>>>,
+    history: History,
+    reg_to_snd_tx: Sender<SendRequest>,
+}
+
+impl RecAux for AntiEntropy {}
+
+impl AntiEntropy {
+    /// Constructor
+    pub fn new(
+        host: Node,
+        group: Arc<Mutex<Vec<Node>>>,
+        history: History,
+        reg_to_snd_tx: Sender<SendRequest>,
+    ) -> Self {
+        Self {
+            host,
+            group,
+            history,
+            reg_to_snd_tx,
+        }
+    }
+
+    /// Thread that periodically sends the digest of the delivered broadcasts to a random peer
+    /// The peer will answer by repairing any broadcast this node is missing
+    pub fn run(&self) {
+        loop {
+            thread::sleep(GOSSIP_INTERVAL);
+            let peer = match Self::get_friends(&self.group, &self.host, 1, None).first() {
+                Some(peer) => *peer,
+                None => continue,
+            };
+            let (request, _) = SendRequest::new(
+                Self::get_digest(&self.history),
+                SendRequestData::Pull { dst_addr: peer },
+            );
+            if let Err(e) = self.reg_to_snd_tx.send(request) {
+                debug!("Erro ao enviar pedido de anti-entropia: {e}");
+                break;
+            }
+        }
+    }
+
+    /// Stores a delivered broadcast, forgetting the oldest ones of the origin when the history is full
+    pub fn store(history: &History, origin: SocketAddr, group_id: u32,
+        seq_num: u32, next_seq_num: u32, message: Vec<u8>) {
+        let mut history = history.lock().expect("Erro ao obter lock do histórico em store");
+        let msgs = history.entry((origin, group_id)).or_default();
+        msgs.push_back((seq_num, next_seq_num, message));
+        if msgs.len() > GOSSIP_HISTORY {
+            msgs.pop_front();
+        }
+    }
+
+    /// Forgets the broadcasts of an origin that restarted, its new broadcasts are numbered from zero again
+    pub fn forget(history: &History, origin: SocketAddr) {
+        history
+            .lock()
+            .expect("Erro ao obter lock do histórico em forget")
+            .retain(|(addr, _), _| *addr != origin);
+    }
+
+    /// The digest tells, for each origin and group id, the sequence number of the next broadcast expected from it
+    pub fn get_digest(history: &History) -> Vec<u8> {
+        let history = history.lock().expect("Erro ao obter lock do histórico em get_digest");
+        let mut digest = Vec::new();
+        for ((origin, group_id), msgs) in history.iter() {
+            if let Some((_, next_seq_num, _)) = msgs.back() {
+                digest.extend(Header::addr_to_bytes(*origin));
+                digest.extend(group_id.to_be_bytes());
+                digest.extend(next_seq_num.to_be_bytes());
+            }
+        }
+        digest
+    }
+
+    pub fn parse_digest(digest: &[u8]) -> HashMap<(SocketAddr, u32), u32> {
+        let mut expected = HashMap::new();
+        let mut start = 0;
+        while start + 14 <= digest.len() {
+            let origin = Header::addr_from_bytes(digest, &mut start);
+            let group_id = Header::u32_from_bytes(digest, &mut start);
+            let seq_num = Header::u32_from_bytes(digest, &mut start);
+            expected.insert((origin, group_id), seq_num);
+        }
+        expected
+    }
+
+    /// Answers a pull request, resending to the requester every broadcast it's missing
+    /// Only broadcasts that continue exactly where the requester's digest stops are sent,
+    /// since anything after a gap would be refused by its listener anyway
+    /// Multicasts are only repaired if the requester is one of their members
+    pub fn handle_pull(history: &History, reg_to_snd_tx: &Sender<SendRequest>,
+        dst: &Node, digest: &[u8]) {
+        let dst_addr = dst.addr;
+        let expected = Self::parse_digest(digest);
+        let history = history.lock().expect("Erro ao obter lock do histórico em handle_pull");
+        for ((origin, group_id), msgs) in history.iter() {
+            let mut next = *expected.get(&(*origin, *group_id)).unwrap_or(&0);
+            for (seq_num, next_seq_num, message) in msgs.iter() {
+                if *seq_num != next { continue; }
+                if *group_id != Multicast::GROUP_ID {
+                    match Multicast::from_bytes(*group_id, message) {
+                        Some((multicast, _)) if multicast.contains(dst.agent_number) => {}
+                        _ => break,
+                    }
+                }
+                debug!("Reparando mensagem de Agent {} com seq_num {} para Agent {}",
+                    Self::get_agnt(origin), seq_num, Self::get_agnt(&dst_addr));
+                let (request, _) = SendRequest::new(
+                    message.clone(),
+                    SendRequestData::Repair {
+                        dst_addr,
+                        origin: *origin,
+                        seq_num: *seq_num,
+                        group_id: *group_id,
+                    },
+                );
+                if let Err(e) = reg_to_snd_tx.send(request) {
+                    debug!("Erro ao enviar reparo: {e}");
+                    return;
+                }
+                next = *next_seq_num;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver};
+    use logger::{initializate_test_folder, free_ports};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn repairs(reg_to_snd_rx: &Receiver<SendRequest>) -> Vec<(SocketAddr, u32, u32)> {
+        reg_to_snd_rx
+            .try_iter()
+            .filter_map(|request| match request.options {
+                SendRequestData::Repair { origin, seq_num, group_id, .. } => Some((origin, group_id, seq_num)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn digest_tells_the_next_broadcast_of_each_stream() {
+        let ports = free_ports!(2);
+        let history: History = Arc::new(Mutex::new(HashMap::new()));
+        AntiEntropy::store(&history, addr(ports[0]), 0, 0, 1, b"a".to_vec());
+        AntiEntropy::store(&history, addr(ports[0]), 0, 1, 3, b"bc".to_vec());
+        AntiEntropy::store(&history, addr(ports[1]), 7, 0, 1, b"d".to_vec());
+        let digest = AntiEntropy::parse_digest(&AntiEntropy::get_digest(&history));
+        assert_eq!(digest, HashMap::from([((addr(ports[0]), 0), 3), ((addr(ports[1]), 7), 1)]));
+    }
+
+    #[test]
+    fn history_forgets_the_oldest_broadcasts_and_the_restarted_origins() {
+        let ports = free_ports!(1);
+        let history: History = Arc::new(Mutex::new(HashMap::new()));
+        for seq_num in 0..=GOSSIP_HISTORY as u32 {
+            AntiEntropy::store(&history, addr(ports[0]), 0, seq_num, seq_num + 1, vec![]);
+        }
+        {
+            let history = history.lock().expect("Erro no lock do histórico");
+            let msgs = &history[&(addr(ports[0]), 0)];
+            assert_eq!(msgs.len(), GOSSIP_HISTORY);
+            assert_eq!(msgs.front().map(|(seq_num, _, _)| *seq_num), Some(1));
+        }
+        AntiEntropy::forget(&history, addr(ports[0]));
+        assert!(AntiEntropy::get_digest(&history).is_empty());
+    }
+
+    #[test]
+    fn pull_repairs_only_what_continues_the_digest() {
+        initializate_test_folder!();
+        let ports = free_ports!(2);
+        let history: History = Arc::new(Mutex::new(HashMap::new()));
+        for (seq_num, next_seq_num) in [(0, 1), (1, 2), (2, 3), (5, 6)] {
+            AntiEntropy::store(&history, addr(ports[0]), 0, seq_num, next_seq_num, vec![]);
+        }
+        let (reg_to_snd_tx, reg_to_snd_rx) = mpsc::channel();
+        let requester = Node::new(addr(ports[1]), 1);
+        let mut digest = Header::addr_to_bytes(addr(ports[0]));
+        digest.extend(0u32.to_be_bytes());
+        digest.extend(1u32.to_be_bytes());
+        AntiEntropy::handle_pull(&history, &reg_to_snd_tx, &requester, &digest);
+        // The requester has the first broadcast, and the one after the gap would be refused
+        assert_eq!(repairs(&reg_to_snd_rx), vec![(addr(ports[0]), 0, 1), (addr(ports[0]), 0, 2)]);
+    }
+
+    #[test]
+    fn pull_repairs_multicasts_only_to_their_members() {
+        initializate_test_folder!();
+        let ports = free_ports!(3);
+        let history: History = Arc::new(Mutex::new(HashMap::new()));
+        let multicast = Multicast::new(&[0, 2]);
+        AntiEntropy::store(&history, addr(ports[0]), multicast.id, 0, 1, multicast.to_bytes(b"m"));
+        let (reg_to_snd_tx, reg_to_snd_rx) = mpsc::channel();
+        AntiEntropy::handle_pull(&history, &reg_to_snd_tx, &Node::new(addr(ports[1]), 1), &[]);
+        assert!(repairs(&reg_to_snd_rx).is_empty());
+        AntiEntropy::handle_pull(&history, &reg_to_snd_tx, &Node::new(addr(ports[2]), 2), &[]);
+        assert_eq!(repairs(&reg_to_snd_rx), vec![(addr(ports[0]), multicast.id, 0)]);
+    }
+}
@@ -1,9 +1,13 @@
 /*
 A camada de comunicação mais baixa, representa os canais de comunicação (channels)
 e implementa sockets para comunicação entre os processos participantes.
+Os canais também lembram quando cada processo foi ouvido pela última vez e quando lhe foi enviado algo,
+para que qualquer pacote sirva de evidência de vida e os heartbeats só sejam enviados em enlaces ociosos.
 */
-use std::net::UdpSocket;
-use std::sync::Arc;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::packet::Packet;
 use crate::config::LOSS_RATE;
@@ -11,16 +15,77 @@ use crate::rec_aux::RecAux;
 use logger::debug;
 
 // Estrutura básica para a camada de comunicação por canais
-#[derive(Clone)]
 pub struct Channel {
-    socket: Arc<UdpSocket>
+    socket: Arc<UdpSocket>,
+    // Incarnation of this process, stamped on every packet sent
+    epoch: u32,
+    // When the last valid packet arrived from each address, and when the last packet was sent to it
+    heard: Mutex<HashMap<SocketAddr, Instant>>,
+    sent: Mutex<HashMap<SocketAddr, Instant>>,
+    // Addresses on the other side of a simulated partition
+    blocked: Mutex<HashSet<SocketAddr>>,
 }
 
 impl Channel {
     /// Constructor
+    /// The epoch is taken from the clock, so a process that restarts on the same address always has a newer one
     pub fn new(bind_addr: std::net::SocketAddr) -> Result<Arc<Self>, std::io::Error> {
         let socket = Arc::new(UdpSocket::bind(bind_addr)?);
-        Ok(Arc::new(Self { socket }))
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |time| time.as_millis() as u32)
+            .max(1);
+        Ok(Arc::new(Self {
+            socket,
+            epoch,
+            heard: Mutex::new(HashMap::new()),
+            sent: Mutex::new(HashMap::new()),
+            blocked: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// When the last valid packet from the address arrived, of any kind
+    pub fn heard_from(&self, addr: &SocketAddr) -> Option<Instant> {
+        self.heard
+            .lock()
+            .expect("Erro ao consultar canal: Mutex lock dos recebidos falhou")
+            .get(addr)
+            .copied()
+    }
+
+    /// Records that a valid packet from the address just arrived
+    /// Called by the listener only for packets of the current incarnation of their sender,
+    /// so packets of a previous incarnation are never evidence that the sender is alive
+    pub fn hear(&self, addr: SocketAddr) {
+        self.heard
+            .lock()
+            .expect("Erro ao receber pacote: Mutex lock dos recebidos falhou")
+            .insert(addr, Instant::now());
+    }
+
+    /// Drops every packet to and from the addresses from now on, replacing the previous ones
+    pub fn block(&self, addrs: HashSet<SocketAddr>) {
+        *self.blocked.lock().expect("Erro ao particionar canal: Mutex lock dos bloqueados falhou") = addrs;
+    }
+
+    fn is_blocked(&self, addr: &SocketAddr) -> bool {
+        self.blocked
+            .lock()
+            .expect("Erro ao consultar canal: Mutex lock dos bloqueados falhou")
+            .contains(addr)
+    }
+
+    /// Whether nothing was sent to the address for at least the given period
+    pub fn is_idle(&self, addr: &SocketAddr, period: Duration) -> bool {
+        self.sent
+            .lock()
+            .expect("Erro ao consultar canal: Mutex lock dos enviados falhou")
+            .get(addr)
+            .is_none_or(|sent| sent.elapsed() >= period)
     }
 
     /// Validates the received message
@@ -51,19 +116,31 @@ impl Channel {
             if rand::random::<f32>() < LOSS_RATE { continue; }
             // Verifica se o pacote foi corrompido
             if !self.validate_message(&packet) { continue; }
+            // Simula partições da rede
+            if self.is_blocked(&packet.header.src_addr) { continue; }
 
             return Ok(packet);
         }
     }
 
     /// Wrapper for UdpSocket::send_to, with some print statements
+    /// Stamps the packet with the epoch of this process
     pub fn send(&self, packet: &Packet) -> bool {
         // Simula perda de pacotes, usand o parâmetro LOSS_RATE
-        if rand::random::<f32>() < LOSS_RATE {
+        if rand::random::<f32>() < LOSS_RATE || self.is_blocked(&packet.header.dst_addr) {
             return false;
         }
+        let mut packet = packet.clone();
+        packet.header.epoch = self.epoch;
+        packet.header.checksum = Packet::checksum(&packet.header, &packet.data);
         match self.socket.send_to(&packet.to_bytes(), packet.header.dst_addr) {
-            Ok(_) => true,
+            Ok(_) => {
+                self.sent
+                    .lock()
+                    .expect("Erro ao enviar pacote: Mutex lock dos enviados falhou")
+                    .insert(packet.header.dst_addr, Instant::now());
+                true
+            },
             Err(_) => { false }
         }
     }
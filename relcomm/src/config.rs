@@ -4,8 +4,48 @@ pub const TIMEOUT: Duration = Duration::from_millis(5);
 pub const TIMEOUT_LIMIT: u32 = 10;
 pub const MESSAGE_TIMEOUT: Duration = Duration::from_millis(2000);
 pub const BROADCAST_TIMEOUT: Duration = Duration::from_millis(500);
+pub const URB_PENDING_TIMEOUT: Duration = Duration::from_millis(5000);
 pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
 pub const HEARTBEAT_MISS_LIMIT: i32 = 5;
+pub const HEARTBEAT_IDLE: Duration = Duration::from_millis(50);
+pub const FAILURE_DETECTOR: &str = "MISS";
+pub const PHI_SUSPECT_THRESHOLD: f64 = 2.;
+pub const PHI_DEAD_THRESHOLD: f64 = 8.;
+pub const PHI_WINDOW: usize = 100;
+pub const PHI_MIN_STD_DEV: Duration = Duration::from_millis(50);
+pub const PHI_ACCEPTABLE_PAUSE: Duration = Duration::from_millis(100);
+pub const SWIM_PERIOD: Duration = Duration::from_millis(200);
+pub const SWIM_PING_TIMEOUT: Duration = Duration::from_millis(50);
+pub const SWIM_PING_REQ: usize = 3;
+pub const SWIM_SUSPECT_TIMEOUT: Duration = Duration::from_millis(600);
+pub const SWIM_RETRANSMIT_MULT: u32 = 3;
+pub const SWIM_PIGGYBACK: usize = 8;
 pub const GOSSIP_RATE: usize = 3;
+pub const GOSSIP_TTL: u8 = 4;
+pub const GOSSIP: &str = "PUSHPULL";
+pub const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+pub const GOSSIP_HISTORY: usize = 64;
+pub const BRD_AHEAD_LIMIT: u32 = 256;
 pub const W_SIZE: usize = 5;
 pub const LOSS_RATE: f32= 0.;
+pub const RPC_TIMEOUT: Duration = Duration::from_millis(2000);
+pub const RPC_CANCEL_TIMEOUT: Duration = Duration::from_millis(10000);
+pub const JOIN_TIMEOUT: Duration = Duration::from_millis(2000);
+pub const VIEW_FLUSH_TIMEOUT: Duration = Duration::from_millis(1000);
+pub const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+pub const AB_QUORUM: bool = false;
+pub const SEEDS: &[&str] = &[
+    "127.0.0.1:3000",
+    "127.0.0.1:3001",
+    "127.0.0.1:3002",
+    "127.0.0.1:3003",
+    "127.0.0.1:3004",
+    "127.0.0.1:3005",
+    "127.0.0.1:3006",
+    "127.0.0.1:3007",
+    "127.0.0.1:3008",
+    "127.0.0.1:3009",
+];
+pub const DISCOVERY_MULTICAST: Option<&str> = None;
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_millis(1000);
+pub const DISCOVERY_INTERVAL: Duration = Duration::from_millis(100);
@@ -0,0 +1,297 @@
+/*
+A descoberta (Discovery) produz a lista inicial de membros do grupo, para que cada processo não precise montá-la à mão.
+A lista parte dos nós semente (seeds) do arquivo de configuração, numerados na ordem em que aparecem,
+e pode ser completada por anúncios em multicast UDP na rede local: durante um tempo, cada processo anuncia o seu endereço
+e os endereços que já conhece, e junta os que ouvir dos outros.
+Os endereços descobertos assim vêm depois das sementes, ordenados, então processos que ouviram os mesmos anúncios
+concordam sobre o número de cada membro.
+Como cada processo pode ter ouvido anúncios diferentes até o fim do tempo, antes de numerar os membros eles confirmam
+a lista: cada um anuncia a lista que tem, junta a dos outros, e só termina quando todos os processos da lista confirmaram
+a mesma lista. Sem acordo dentro do tempo limite a descoberta falha, em vez de cada processo numerar os membros à sua maneira.
+Só um processo por máquina consegue escutar a porta do grupo multicast: os demais anunciam de uma porta qualquer
+e recebem de volta, de quem escuta, os endereços que ele conhece.
+*/
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind};
+use std::time::Instant;
+
+use logger::debug;
+use crate::config::{SEEDS, DISCOVERY_MULTICAST, DISCOVERY_TIMEOUT, DISCOVERY_INTERVAL};
+use crate::header::Header;
+use crate::node::Node;
+
+/// First bytes of every announcement, so other traffic on the multicast group is ignored
+const MAGIC: &[u8] = b"RELCOMM";
+
+/// Kinds of discovery messages, the byte after the magic
+const ANNOUNCE: u8 = 0;
+const REPLY: u8 = 1;
+const CONFIRM: u8 = 2;
+
+pub struct Discovery {
+    seeds: Vec<SocketAddr>,
+    multicast: Option<SocketAddrV4>,
+}
+
+impl Discovery {
+    /// Constructor, the multicast group is optional
+    pub fn new(seeds: Vec<SocketAddr>, multicast: Option<SocketAddrV4>) -> Self {
+        Self { seeds, multicast }
+    }
+
+    /// Uses the seeds and the multicast group of the config file
+    pub fn from_config() -> Result<Self, Error> {
+        let seeds = SEEDS
+            .iter()
+            .map(|seed| seed.parse().map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Invalid seed {seed}"))))
+            .collect::<Result<Vec<SocketAddr>, Error>>()?;
+        let multicast = match DISCOVERY_MULTICAST {
+            Some(group) => Some(group.parse().map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid discovery multicast group {group}"))
+            })?),
+            None => None,
+        };
+        Ok(Self::new(seeds, multicast))
+    }
+
+    pub fn seeds(&self) -> &[SocketAddr] {
+        &self.seeds
+    }
+
+    /// The initial member list: the seeds followed by the addresses announced on the multicast group, if there's one
+    /// Fails if the processes heard on the multicast group didn't confirm the same addresses in time
+    pub fn members(&self, host_addr: SocketAddr) -> Result<Vec<Node>, Error> {
+        let mut addrs = self.seeds.clone();
+        if let Some(group) = self.multicast {
+            let announced = self.announce(host_addr, group)?;
+            addrs.extend(announced.into_iter().filter(|addr| !self.seeds.contains(addr)));
+        }
+        debug!("Descobriu {} membros", addrs.len());
+        Ok(addrs
+            .into_iter()
+            .enumerate()
+            .map(|(agent_number, addr)| Node::new(addr, agent_number))
+            .collect())
+    }
+
+    /// Announces the host on the multicast group until the timeout, then confirms the addresses heard (the host included)
+    /// Announcements carry all the addresses known, so processes that started a little later still learn the earlier ones
+    /// Whoever listens on the group answers each announcement with the addresses it knows
+    fn announce(&self, host_addr: SocketAddr, group: SocketAddrV4) -> Result<BTreeSet<SocketAddr>, Error> {
+        let (socket, listening) = match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port())) {
+            Ok(socket) => {
+                socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+                (socket, true)
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                debug!("Outro processo escuta o grupo da descoberta, anunciando de outra porta");
+                (UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?, false)
+            }
+            Err(e) => return Err(e),
+        };
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_read_timeout(Some(DISCOVERY_INTERVAL))?;
+        let mut known = BTreeSet::from([host_addr]);
+        let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+        let mut next_announcement = Instant::now();
+        while Instant::now() < deadline {
+            if Instant::now() >= next_announcement {
+                if let Err(e) = socket.send_to(&Self::to_bytes(ANNOUNCE, &known), group) {
+                    debug!("Erro ao anunciar na descoberta: {e}");
+                }
+                next_announcement = Instant::now() + DISCOVERY_INTERVAL;
+            }
+            let (kind, addrs, src) = match Self::receive(&socket)? {
+                Some(message) => message,
+                None => continue,
+            };
+            known.extend(addrs);
+            if listening && kind == ANNOUNCE {
+                let _ = socket.send_to(&Self::to_bytes(REPLY, &known), src);
+            }
+        }
+        let confirmed = self.confirm(&socket, listening, host_addr, group, known);
+        if listening {
+            let _ = socket.leave_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED);
+        }
+        confirmed
+    }
+
+    /// Announces the addresses known until every one of them confirmed the same addresses
+    /// Addresses that are new to some process are added by everyone, and the confirmations start over
+    /// Whoever listens on the group passes the confirmations on to the processes that announce from other ports
+    /// After the agreement the host still confirms for a few intervals, for the processes that didn't hear it yet
+    fn confirm(&self, socket: &UdpSocket, listening: bool, host_addr: SocketAddr, group: SocketAddrV4,
+        mut known: BTreeSet<SocketAddr>) -> Result<BTreeSet<SocketAddr>, Error> {
+        let mut agreed = BTreeSet::from([host_addr]);
+        let mut others = BTreeSet::new();
+        let mut agreed_at: Option<Instant> = None;
+        let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+        let mut next_confirmation = Instant::now();
+        while Instant::now() < deadline {
+            if agreed_at.is_some_and(|agreed_at| agreed_at.elapsed() >= DISCOVERY_INTERVAL * 3) {
+                return Ok(known);
+            }
+            if Instant::now() >= next_confirmation {
+                let confirmation = Self::confirmation(host_addr, &known);
+                for dst in others.iter().copied().chain([SocketAddr::V4(group)]) {
+                    if let Err(e) = socket.send_to(&confirmation, dst) {
+                        debug!("Erro ao confirmar na descoberta: {e}");
+                    }
+                }
+                next_confirmation = Instant::now() + DISCOVERY_INTERVAL;
+            }
+            let (kind, addrs, src) = match Self::receive(socket)? {
+                Some(message) => message,
+                None => continue,
+            };
+            if listening && src.port() != group.port() {
+                others.insert(src);
+            }
+            let before = known.len();
+            match (kind, addrs.split_first()) {
+                (CONFIRM, Some((sender, list))) => {
+                    let list: BTreeSet<SocketAddr> = list.iter().copied().collect();
+                    if listening {
+                        let confirmation = Self::confirmation(*sender, &list);
+                        for dst in others.iter().filter(|dst| **dst != src) {
+                            let _ = socket.send_to(&confirmation, dst);
+                        }
+                    }
+                    known.extend(list.iter().copied());
+                    if known.len() == before && list == known {
+                        agreed.insert(*sender);
+                    }
+                }
+                _ => known.extend(addrs),
+            }
+            if known.len() != before {
+                debug!("Descoberta ouviu endereços novos, confirmando de novo");
+                agreed = BTreeSet::from([host_addr]);
+                agreed_at = None;
+            }
+            if agreed_at.is_none() && known.is_subset(&agreed) {
+                agreed_at = Some(Instant::now());
+            }
+        }
+        if agreed_at.is_some() {
+            return Ok(known);
+        }
+        let missing: Vec<&SocketAddr> = known.difference(&agreed).collect();
+        debug!("Descoberta sem acordo, não confirmaram: {missing:?}");
+        Err(Error::new(ErrorKind::TimedOut, format!("Discovery didn't agree on the members, missing {missing:?}")))
+    }
+
+    /// Receives a discovery message, None if nothing arrived in an interval or it wasn't a discovery message
+    fn receive(socket: &UdpSocket) -> Result<Option<(u8, Vec<SocketAddr>, SocketAddr)>, Error> {
+        let mut buffer = [0; 1024];
+        match socket.recv_from(&mut buffer) {
+            Ok((size, src)) => Ok(Self::from_bytes(&buffer[..size]).map(|(kind, addrs)| (kind, addrs, src))),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The addresses known, after the address of the host that confirms them
+    fn confirmation(host_addr: SocketAddr, known: &BTreeSet<SocketAddr>) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(CONFIRM);
+        bytes.extend(Header::addr_to_bytes(host_addr));
+        bytes.extend(Self::to_bytes(CONFIRM, known).split_off(MAGIC.len() + 1));
+        bytes
+    }
+
+    fn to_bytes(kind: u8, addrs: &BTreeSet<SocketAddr>) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(kind);
+        for addr in addrs.iter().filter(|addr| addr.is_ipv4()) {
+            bytes.extend(Header::addr_to_bytes(*addr));
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<(u8, Vec<SocketAddr>)> {
+        if !bytes.starts_with(MAGIC) {
+            return None;
+        }
+        let kind = *bytes.get(MAGIC.len())?;
+        let mut addrs = Vec::new();
+        let mut start = MAGIC.len() + 1;
+        while start + 6 <= bytes.len() {
+            addrs.push(Header::addr_from_bytes(bytes, &mut start));
+        }
+        Some((kind, addrs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use logger::{initializate_test_folder, free_ports};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn seeds_are_numbered_in_order() {
+        initializate_test_folder!();
+        let ports = free_ports!(3);
+        let seeds = vec![addr(ports[2]), addr(ports[0]), addr(ports[1])];
+        let members = Discovery::new(seeds.clone(), None).members(addr(ports[0])).expect("Erro na descoberta");
+        assert_eq!(members.iter().map(|node| node.addr).collect::<Vec<SocketAddr>>(), seeds);
+        assert_eq!(members.iter().map(|node| node.agent_number).collect::<Vec<usize>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn messages_keep_their_addresses_and_ignore_other_traffic() {
+        let ports = free_ports!(2);
+        let known = BTreeSet::from([addr(ports[0]), addr(ports[1])]);
+        assert_eq!(
+            Discovery::from_bytes(&Discovery::to_bytes(REPLY, &known)),
+            Some((REPLY, known.iter().copied().collect()))
+        );
+        let confirmation = Discovery::confirmation(addr(ports[1]), &known);
+        let mut confirmed = vec![addr(ports[1])];
+        confirmed.extend(known.iter().copied());
+        assert_eq!(Discovery::from_bytes(&confirmation), Some((CONFIRM, confirmed)));
+        assert_eq!(Discovery::from_bytes(b"OUTRO TRAFEGO"), None);
+    }
+
+    #[test]
+    fn processes_agree_on_the_announced_members() {
+        initializate_test_folder!();
+        let ports = free_ports!(5);
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 99), ports[0]);
+        let seeds = vec![addr(ports[1])];
+        let discoveries: Vec<_> = ports[2..]
+            .iter()
+            .copied()
+            .map(|port| {
+                // Processes start a little apart, so each one hears a different part of the announcements
+                thread::sleep(DISCOVERY_TIMEOUT / 3);
+                let discovery = Discovery::new(seeds.clone(), Some(group));
+                thread::spawn(move || discovery.members(addr(port)))
+            })
+            .collect();
+        let lists: Vec<Vec<(usize, SocketAddr)>> = discoveries
+            .into_iter()
+            .map(|discovery| {
+                discovery
+                    .join()
+                    .expect("Thread da descoberta falhou")
+                    .expect("Erro na descoberta")
+                    .into_iter()
+                    .map(|node| (node.agent_number, node.addr))
+                    .collect()
+            })
+            .collect();
+        // The seed comes first, then the announced addresses in order
+        let mut announced: Vec<SocketAddr> = ports[2..].iter().map(|port| addr(*port)).collect();
+        announced.sort();
+        let expected: Vec<(usize, SocketAddr)> = seeds.into_iter().chain(announced).enumerate().collect();
+        assert!(lists.iter().all(|list| *list == expected), "{lists:?}");
+    }
+}
@@ -0,0 +1,276 @@
+/*
+A eleição de líder (Bully) escolhe o líder do grupo por mandatos (terms), no lugar do "primeiro vivo do vetor".
+Quem não conhece um líder vivo para o seu mandato inicia uma eleição: pergunta aos membros vivos com prioridade maior
+(número de agente menor) e, se nenhum responder dentro do tempo limite, anuncia-se líder de um mandato novo.
+Quem recebe uma eleição de alguém com prioridade menor responde e inicia a sua própria, de modo que o membro vivo
+de maior prioridade acaba vencendo.
+Os anúncios são aceitos se o mandato for maior que o conhecido, ou igual com um líder de prioridade maior (desempate),
+assim há um único líder acordado por mandato. Os pedidos de difusão atômica levam o mandato a que se destinam,
+e só são aceitos pelo líder desse mandato.
+Opcionalmente, a eleição exige quórum: sem alcançar a maioria dos membros não há líder, e a partição minoritária
+para de ordenar e entregar difusões atômicas, evitando que cada lado de uma partição tenha o seu líder (split-brain).
+*/
+use std::thread;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+
+use logger::debug;
+use crate::config::{AB_QUORUM, ELECTION_TIMEOUT, HEARTBEAT_INTERVAL};
+use crate::node::Node;
+use crate::membership::{Events, MembershipEvent};
+use crate::rec_aux::{SendRequest, SendRequestData, RecAux};
+
+/// Kinds of election messages, they travel as membership messages after the view kinds
+pub const ELECTION: u8 = 7;
+pub const ANSWER: u8 = 8;
+pub const COORDINATOR: u8 = 9;
+
+struct State {
+    // Highest term known, incremented by whoever becomes leader
+    term: u32,
+    leader: Option<usize>,
+    // When the election of this node started, and whether a node with higher priority answered it
+    election: Option<(Instant, bool)>,
+}
+
+pub struct Election {
+    host: Node,
+    group: Arc<Mutex<Vec<Node>>>,
+    reg_to_snd_tx: Sender<SendRequest>,
+    state: Mutex<State>,
+    // Whether a majority of the members must be alive to have a leader
+    quorum: AtomicBool,
+    events: Events,
+}
+
+impl RecAux for Election {}
+
+impl Election {
+    /// Constructor, no leader is known until the first election
+    pub fn new(host: Node, group: Arc<Mutex<Vec<Node>>>, reg_to_snd_tx: Sender<SendRequest>, events: Events) -> Self {
+        Self {
+            host,
+            group,
+            reg_to_snd_tx,
+            state: Mutex::new(State {
+                term: 0,
+                leader: None,
+                election: None,
+            }),
+            quorum: AtomicBool::new(AB_QUORUM),
+            events,
+        }
+    }
+
+    /// Thread that starts an election whenever the leader is unknown or dead
+    pub fn run(&self) {
+        loop {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            self.check();
+        }
+    }
+
+    /// Requires (or not) a majority of living members to elect and keep a leader
+    pub fn set_quorum(&self, enabled: bool) {
+        self.quorum.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Whether a majority of the members is alive, always true if the quorum isn't required
+    pub fn has_quorum(&self) -> bool {
+        if !self.quorum.load(Ordering::Relaxed) { return true; }
+        let members = self.group
+            .lock()
+            .expect("Erro ao verificar quórum: Mutex lock do grupo falhou")
+            .iter()
+            .filter(|node| node.is_member())
+            .count();
+        Self::get_livings(&self.group).len() > members / 2
+    }
+
+    /// The current term and its leader, None while an election is going on or without quorum
+    pub fn leader(&self) -> Option<(u32, Node)> {
+        if !self.has_quorum() { return None; }
+        let state = self.lock();
+        let leader = state.leader?;
+        Self::get_livings(&self.group)
+            .into_iter()
+            .find(|node| node.agent_number == leader)
+            .map(|node| (state.term, node))
+    }
+
+    /// Highest term known, even while an election is going on
+    pub fn term(&self) -> u32 {
+        self.lock().term
+    }
+
+    /// Whether the host is the leader of the term, and still has quorum
+    pub fn is_leader(&self, term: u32) -> bool {
+        if !self.has_quorum() { return false; }
+        let state = self.lock();
+        state.term == term && state.leader == Some(self.host.agent_number)
+    }
+
+    /// Handles an election message received by the listener
+    pub fn handle(&self, src: SocketAddr, message: &[u8]) {
+        let term = match message.get(1..5) {
+            Some(bytes) => u32::from_be_bytes(bytes.try_into().expect("Slice de tamanho 4")),
+            None => return,
+        };
+        let mut state = self.lock();
+        match message[0] {
+            ELECTION => {
+                state.term = state.term.max(term);
+                if self.leads(&state) {
+                    let message = Self::coordinator(state.term, self.host.agent_number);
+                    self.send(src, message);
+                    return;
+                }
+                let mut message = vec![ANSWER];
+                message.extend(state.term.to_be_bytes());
+                self.send(src, message);
+                if state.election.is_none() {
+                    self.start(&mut state);
+                }
+            }
+            ANSWER => {
+                state.term = state.term.max(term);
+                if let Some(election) = state.election.as_mut() {
+                    // Waits for the announcement of whoever answered
+                    *election = (Instant::now(), true);
+                }
+            }
+            COORDINATOR => {
+                let leader = match message.get(5..9) {
+                    Some(bytes) => u32::from_be_bytes(bytes.try_into().expect("Slice de tamanho 4")) as usize,
+                    None => return,
+                };
+                let newer = term > state.term
+                    || (term == state.term && state.leader.is_none_or(|current| leader <= current));
+                if !newer {
+                    // The sender is behind, tells it the current term and leader
+                    if let Some(current) = state.leader {
+                        let message = Self::coordinator(state.term, current);
+                        self.send(src, message);
+                    }
+                    return;
+                }
+                if state.leader != Some(leader) || state.term != term {
+                    debug!("Agent {leader} é o líder do mandato {term}");
+                }
+                state.term = term;
+                self.set_leader(&mut state, leader);
+                state.election = None;
+                // A living member with higher priority than the leader takes over
+                if leader > self.host.agent_number && self.is_living() {
+                    self.start(&mut state);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn check(&self) {
+        if !self.is_living() { return; }
+        let mut state = self.lock();
+        let leader_alive = state.leader.is_some_and(|leader| {
+            Self::get_livings(&self.group).iter().any(|node| node.agent_number == leader)
+        });
+        if leader_alive || !self.has_quorum() {
+            state.election = None;
+            return;
+        }
+        match state.election {
+            None => self.start(&mut state),
+            Some((started, answered)) => {
+                // Whoever answered has the same time to announce itself
+                let limit = if answered { ELECTION_TIMEOUT * 2 } else { ELECTION_TIMEOUT };
+                if started.elapsed() < limit { return; }
+                if answered {
+                    self.start(&mut state);
+                } else {
+                    self.become_leader(&mut state);
+                }
+            }
+        }
+    }
+
+    /// Asks the living members with higher priority, becoming the leader if there's none
+    /// Without quorum nobody is elected, the election starts again once the majority is back
+    fn start(&self, state: &mut State) {
+        if !self.has_quorum() {
+            state.election = None;
+            return;
+        }
+        let higher: Vec<SocketAddr> = Self::get_livings(&self.group)
+            .iter()
+            .filter(|node| node.agent_number < self.host.agent_number)
+            .map(|node| node.addr)
+            .collect();
+        if higher.is_empty() {
+            self.become_leader(state);
+            return;
+        }
+        debug!("Iniciando eleição no mandato {}", state.term);
+        state.election = Some((Instant::now(), false));
+        let mut message = vec![ELECTION];
+        message.extend(state.term.to_be_bytes());
+        for dst_addr in higher {
+            self.send(dst_addr, message.clone());
+        }
+    }
+
+    /// Starts a new term with the host as leader and announces it to every living member
+    fn become_leader(&self, state: &mut State) {
+        state.term += 1;
+        self.set_leader(state, self.host.agent_number);
+        state.election = None;
+        debug!("Agent {} é o líder do mandato {}", self.host.agent_number, state.term);
+        let message = Self::coordinator(state.term, self.host.agent_number);
+        for node in Self::get_livings(&self.group) {
+            if node.addr == self.host.addr { continue; }
+            self.send(node.addr, message.clone());
+        }
+    }
+
+    /// Elections in progress don't change the leader, only the next one elected, which members are told about
+    fn set_leader(&self, state: &mut State, leader: usize) {
+        let is_member = self.group
+            .lock()
+            .expect("Erro na eleição: Mutex lock do grupo falhou")
+            .get(self.host.agent_number)
+            .is_some_and(|node| node.is_member());
+        if state.leader != Some(leader) && is_member {
+            self.events.emit(MembershipEvent::LeaderChanged(leader));
+        }
+        state.leader = Some(leader);
+    }
+
+    fn leads(&self, state: &State) -> bool {
+        state.leader == Some(self.host.agent_number)
+    }
+
+    fn is_living(&self) -> bool {
+        Self::get_livings(&self.group).iter().any(|node| node.addr == self.host.addr)
+    }
+
+    fn coordinator(term: u32, leader: usize) -> Vec<u8> {
+        let mut message = vec![COORDINATOR];
+        message.extend(term.to_be_bytes());
+        message.extend((leader as u32).to_be_bytes());
+        message
+    }
+
+    fn send(&self, dst_addr: SocketAddr, message: Vec<u8>) {
+        let (request, _) = SendRequest::new(message, SendRequestData::Membership { dst_addr });
+        if let Err(e) = self.reg_to_snd_tx.send(request) {
+            debug!("Erro ao enviar mensagem de eleição: {e}");
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Erro na eleição: Mutex lock do estado falhou")
+    }
+}
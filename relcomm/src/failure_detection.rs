@@ -3,32 +3,66 @@ A camada de detecção de defeitos (Failure Detection) permite que processos
     participantes na comunicação monitorem uns aos outros
 e sinalizem os protocolos de comunicação confiável sobre possíveis saídas de processos do grupo
 (intencionais ou não-intencionais, no caso de falhas).
+Há dois detectores: a contagem de heartbeats perdidos, e o detector de acúmulo phi (phi-accrual),
+que guarda o histórico dos intervalos entre heartbeats de cada processo e calcula um nível contínuo de suspeita.
+Qualquer pacote recebido de um processo (dados, ACKs ou heartbeats) conta como evidência de que ele está vivo,
+então os heartbeats só são enviados pelos enlaces que ficaram ociosos.
+Um processo declarado morto, porém, só volta a ser vivo com um heartbeat, e nunca pelos outros pacotes.
 */
 use std::{thread, vec};
-use std::sync::mpsc::Receiver;
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use logger::debug;
 
-use crate::config::{HEARTBEAT_INTERVAL, HEARTBEAT_MISS_LIMIT};
+use crate::config::{HEARTBEAT_INTERVAL, HEARTBEAT_MISS_LIMIT, HEARTBEAT_IDLE, PHI_WINDOW, PHI_MIN_STD_DEV, PHI_ACCEPTABLE_PAUSE};
 use crate::node::{Node, NodeState};
 use crate::channels::Channel;
 use crate::packet::Packet;
+use crate::membership::Events;
+
+/// Failure detector used by an instance
+/// HeartbeatMiss: a node is suspect after missing one heartbeat and dead after missing HEARTBEAT_MISS_LIMIT in a row
+/// PhiAccrual: a node is suspect or dead once its suspicion level (phi) reaches the given thresholds
+/// Swim: nodes probe one random member per period, directly and through others, and gossip suspicions (see swim.rs)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Detector {
+    HeartbeatMiss,
+    PhiAccrual {
+        suspect: f64,
+        dead: f64,
+    },
+    Swim,
+}
 
 pub struct FailureDetection {
     group: Arc<Mutex<Vec<Node>>>,
+    detector: Detector,
     hb_miss_cnt: Vec<i32>,
+    // Intervals between the last heartbeats of each node, used by the phi-accrual detector
+    intervals: Vec<VecDeque<Duration>>,
+    last_arrival: Vec<Instant>,
+    events: Events,
 }
 
 impl FailureDetection {
     /// Creates a new FailureDetection instance
-    pub fn new(group: Arc<Mutex<Vec<Node>>>) -> Self {
-        let agent_num = group.lock().expect("Failed to lock group on new").len();
-        Self {
+    pub fn new(group: Arc<Mutex<Vec<Node>>>, detector: Detector, events: Events) -> Self {
+        let mut failure_detection = Self {
             group,
-            hb_miss_cnt: vec![-1; agent_num],
-        }
+            detector,
+            hb_miss_cnt: Vec::new(),
+            intervals: Vec::new(),
+            last_arrival: Vec::new(),
+            events,
+        };
+        let agent_num = failure_detection.group.lock().expect("Failed to lock group on new").len();
+        failure_detection.resize(agent_num);
+        failure_detection
     }
 
+    /// Builds the heartbeats for the current members of the group
     pub fn get_hbs(group: &Arc<Mutex<Vec<Node>>>, host: &Node) -> (Vec<Packet>, usize) {
         let group = group
             .lock()
@@ -37,62 +71,323 @@ impl FailureDetection {
         if len == 0 {
             panic!("No agents in the group");
         }
-        let heart_beats = group.iter().map(|node| {
-            Packet::heart_beat(&host, node.addr)
+        let heart_beats = group.iter().filter(|node| node.is_member()).map(|node| {
+            Packet::heart_beat(host, node.addr)
         }).collect::<Vec<Packet>>();
         (heart_beats, len)
     }
 
+    /// Keeps one entry per node, since the group grows when nodes join
+    /// Histories start with one expected interval, so nodes that never send a heartbeat are eventually suspected
+    fn resize(&mut self, agent_num: usize) {
+        let now = Instant::now();
+        self.hb_miss_cnt.resize(agent_num, -1);
+        self.intervals.resize(agent_num, VecDeque::from([HEARTBEAT_INTERVAL]));
+        self.last_arrival.resize(agent_num, now);
+    }
+
     /// Starts the failure detection process
     /// This function will run in a separate thread
-    /// It will send heartbeats to all nodes in the group
-    /// wait for heartbeats from all nodes in the group
-    /// and mark nodes as suspect or dead if they don't respond
-    pub fn run(&mut self, hb_rx: Receiver<Packet>, channel: Arc<Channel>,
-        heart_beats: Vec<Packet>, agent_num: usize) {
+    /// It will send heartbeats to all nodes in the group that weren't sent anything recently
+    /// wait for heartbeats (or any other packet) from all nodes in the group
+    /// and mark nodes as suspect or dead according to the detector
+    pub fn run(&mut self, hb_rx: Receiver<Packet>, channel: Arc<Channel>, host: Node) {
         loop {
-            // Broadcast heartbeats
+            let (heart_beats, agent_num) = Self::get_hbs(&self.group, &host);
+            self.resize(agent_num);
+            // Broadcast heartbeats, links that carried other packets recently already tell the destination this node is alive
             for pkt in heart_beats.iter() {
-                channel.send(pkt);
+                if channel.is_idle(&pkt.header.dst_addr, HEARTBEAT_IDLE) {
+                    channel.send(pkt);
+                }
             }
-            thread::sleep(HEARTBEAT_INTERVAL);
-            let mut group = self.group
+            let mut arrivals = Self::wait_heartbeats(&hb_rx, &self.group, &self.events, agent_num);
+            let group = Arc::clone(&self.group);
+            let mut group = group
                 .lock()
                 .expect("Failed to lock group on failure_detection loop");
-            let hb_miss = Self::process_heartbeats(&mut group, &mut self.hb_miss_cnt, &hb_rx);
-            for i in 0..agent_num {
-                if hb_miss[i] == 1 {
-                    self.hb_miss_cnt[i] += 1;
-                    if self.hb_miss_cnt[i] >= HEARTBEAT_MISS_LIMIT {
-                        if group[i].state != NodeState::Dead {
-                            debug!("Agent {} is dead", group[i].agent_number);
-                        }
-                        group[i].state = NodeState::Dead;
-                    } else {
-                        group[i].state = NodeState::Suspect;
+            // Nodes may have joined while waiting for the heartbeats
+            self.resize(group.len());
+            arrivals.extend(Self::piggybacked(&mut group, &channel, &self.events));
+            match self.detector.clone() {
+                Detector::PhiAccrual { suspect, dead } => self.accrue(&mut group, &arrivals, suspect, dead),
+                _ => self.count_misses(&mut group, &arrivals),
+            }
+        }
+    }
+
+    /// Receives heartbeats for one heartbeat interval, returning who sent them and when they arrived
+    /// Their senders are alive as soon as they arrive
+    fn wait_heartbeats(hb_rx: &Receiver<Packet>, group: &Arc<Mutex<Vec<Node>>>, events: &Events, agent_num: usize)
+        -> Vec<(usize, Instant)> {
+        let deadline = Instant::now() + HEARTBEAT_INTERVAL;
+        let mut arrivals = Vec::new();
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match hb_rx.recv_timeout(timeout) {
+                Ok(hb) => {
+                    Self::handle_hb(&hb, group, events);
+                    let id = hb.header.seq_num as usize;
+                    if id < agent_num {
+                        arrivals.push((id, Instant::now()));
                     }
                 }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(timeout);
+                    break;
+                }
+            }
+        }
+        arrivals
+    }
+
+    /// Members that sent any packet recently, which are alive just like the ones that sent a heartbeat
+    /// Their senders skip the heartbeat for up to HEARTBEAT_IDLE after the last packet, so that is tolerated as well
+    /// Dead members stay dead, only a heartbeat or a packet of a new incarnation brings them back
+    fn piggybacked(group: &mut MutexGuard<'_, Vec<Node>>, channel: &Channel, events: &Events)
+        -> Vec<(usize, Instant)> {
+        let mut arrivals = Vec::new();
+        for node in group.iter_mut() {
+            if !node.is_member() || node.is_dead() { continue; }
+            if let Some(heard) = channel.heard_from(&node.addr) {
+                if heard.elapsed() < HEARTBEAT_INTERVAL + HEARTBEAT_IDLE {
+                    events.set_state(node, NodeState::Alive);
+                    arrivals.push((node.agent_number, heard));
+                }
             }
         }
+        arrivals
     }
 
-    fn process_heartbeats(group: &mut MutexGuard<'_, Vec<Node>>,
-        hb_miss_cnt: &mut Vec<i32>, hb_rx: &Receiver<Packet>)
-        -> Vec<i32> {
+    /// Heartbeat-miss detector: counts the intervals each node went without sending a heartbeat
+    fn count_misses(&mut self, group: &mut MutexGuard<'_, Vec<Node>>, arrivals: &[(usize, Instant)]) {
         let mut hb_miss = vec![1; group.len()];
-        while let Ok(hb) = hb_rx.try_recv() {
-            let id = hb.header.seq_num as usize;
-            hb_miss_cnt[id] = 0;
-            hb_miss[id] = 0;
+        for (id, _) in arrivals {
+            if *id >= group.len() { continue; }
+            self.hb_miss_cnt[*id] = 0;
+            hb_miss[*id] = 0;
+        }
+        for i in 0..group.len() {
+            if !group[i].is_member() { continue; }
+            if hb_miss[i] == 1 {
+                self.hb_miss_cnt[i] += 1;
+                if self.hb_miss_cnt[i] >= HEARTBEAT_MISS_LIMIT {
+                    if group[i].state != NodeState::Dead {
+                        debug!("Agent {} is dead", group[i].agent_number);
+                    }
+                    self.events.set_state(&mut group[i], NodeState::Dead);
+                } else {
+                    self.events.set_state(&mut group[i], NodeState::Suspect);
+                }
+            }
+            group[i].suspicion = self.hb_miss_cnt[i].max(0) as f64;
+        }
+    }
+
+    /// Phi-accrual detector: updates the interval histories and the suspicion level of each node
+    fn accrue(&mut self, group: &mut MutexGuard<'_, Vec<Node>>, arrivals: &[(usize, Instant)],
+        suspect: f64, dead: f64) {
+        for (id, arrival) in arrivals {
+            // The same packet may be known both as a heartbeat and as a piggybacked arrival
+            if *id >= group.len() || *arrival <= self.last_arrival[*id] { continue; }
+            let intervals = &mut self.intervals[*id];
+            intervals.push_back(arrival.duration_since(self.last_arrival[*id]));
+            if intervals.len() > PHI_WINDOW {
+                intervals.pop_front();
+            }
+            self.last_arrival[*id] = *arrival;
+        }
+        let now = Instant::now();
+        for i in 0..group.len() {
+            if !group[i].is_member() { continue; }
+            let phi = self.phi(i, now);
+            group[i].suspicion = phi;
+            if phi >= dead {
+                if group[i].state != NodeState::Dead {
+                    debug!("Agent {} is dead, phi {phi:.2}", group[i].agent_number);
+                }
+                self.events.set_state(&mut group[i], NodeState::Dead);
+            } else if phi >= suspect {
+                self.events.set_state(&mut group[i], NodeState::Suspect);
+            }
         }
-        hb_miss
     }
 
-    pub fn handle_hb(hb: &Packet, group_locked: &Arc<Mutex<Vec<Node>>>) {
+    /// Suspicion level of a node: how unlikely it is, given the history of intervals,
+    /// that its next heartbeat is still coming after the time passed since the last one
+    /// Uses a logistic approximation of the normal distribution
+    fn phi(&self, id: usize, now: Instant) -> f64 {
+        let intervals = &self.intervals[id];
+        let n = intervals.len() as f64;
+        let mean = intervals.iter().map(|i| i.as_secs_f64()).sum::<f64>() / n;
+        let variance = intervals.iter().map(|i| (i.as_secs_f64() - mean).powi(2)).sum::<f64>() / n;
+        let std_dev = variance.sqrt().max(PHI_MIN_STD_DEV.as_secs_f64());
+        let mean = mean + PHI_ACCEPTABLE_PAUSE.as_secs_f64();
+        let elapsed = now.duration_since(self.last_arrival[id]).as_secs_f64();
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1. + e)).log10()
+        } else {
+            -(1. - 1. / (1. + e)).log10()
+        }
+    }
+
+    fn handle_hb(hb: &Packet, group_locked: &Arc<Mutex<Vec<Node>>>, events: &Events) {
         let agt_num = hb.header.seq_num as usize;
         let mut group = group_locked
             .lock()
             .expect("Failed to lock group on handle_hb");
-        group[agt_num].state = NodeState::Alive;
+        // Heartbeats from nodes that aren't members (yet) are ignored
+        match group.get_mut(agt_num) {
+            Some(node) if node.addr == hb.header.src_addr && node.is_member() => {
+                events.set_state(node, NodeState::Alive);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PHI_SUSPECT_THRESHOLD, PHI_DEAD_THRESHOLD};
+    use crate::membership::MembershipEvent;
+    use std::net::SocketAddr;
+    use logger::{initializate_test_folder, free_ports};
+
+    fn group(agent_num: usize) -> Arc<Mutex<Vec<Node>>> {
+        let group = free_ports!(agent_num)
+            .into_iter()
+            .enumerate()
+            .map(|(i, port)| {
+                let mut node = Node::new(SocketAddr::from(([127, 0, 0, 1], port)), i);
+                node.state = NodeState::Alive;
+                node
+            })
+            .collect();
+        Arc::new(Mutex::new(group))
+    }
+
+    #[test]
+    fn packets_are_heard_only_once_the_listener_accepts_them() {
+        let group = group(2);
+        let (a, b) = {
+            let group = group.lock().expect("Erro no lock do grupo");
+            (group[0].clone(), group[1].clone())
+        };
+        let sender = Channel::new(a.addr).expect("Erro ao criar o canal");
+        let receiver = Channel::new(b.addr).expect("Erro ao criar o canal");
+        sender.send(&Packet::heart_beat(&a, b.addr));
+        let packet = receiver.receive().expect("Erro ao receber o heartbeat");
+        assert!(receiver.heard_from(&a.addr).is_none());
+        receiver.hear(packet.header.src_addr);
+        assert!(receiver.heard_from(&a.addr).is_some());
+    }
+
+    #[test]
+    fn piggybacking_never_revives_dead_members() {
+        initializate_test_folder!();
+        let group = group(3);
+        let channel = Channel::new(SocketAddr::from(([127, 0, 0, 1], 0))).expect("Erro ao criar o canal");
+        let mut group = group.lock().expect("Erro no lock do grupo");
+        group[1].state = NodeState::Dead;
+        group[2].state = NodeState::Suspect;
+        channel.hear(group[1].addr);
+        channel.hear(group[2].addr);
+        let events = Events::default();
+        let events_rx = events.subscribe();
+        let arrivals = FailureDetection::piggybacked(&mut group, &channel, &events);
+        assert_eq!(arrivals.iter().map(|(id, _)| *id).collect::<Vec<usize>>(), vec![2]);
+        assert_eq!(group[1].state, NodeState::Dead);
+        assert_eq!(group[2].state, NodeState::Alive);
+        assert_eq!(events_rx.try_iter().collect::<Vec<MembershipEvent>>(), vec![MembershipEvent::NodeRecovered(2)]);
+    }
+
+    #[test]
+    fn suspicions_undone_within_an_interval_are_still_announced() {
+        initializate_test_folder!();
+        let events = Events::default();
+        let events_rx = events.subscribe();
+        let mut detection = FailureDetection::new(group(2), Detector::HeartbeatMiss, events);
+        let group = Arc::clone(&detection.group);
+        let (a, b) = {
+            let mut group = group.lock().expect("Erro no lock do grupo");
+            detection.count_misses(&mut group, &[(0, Instant::now())]);
+            assert_eq!(group[1].state, NodeState::Suspect);
+            (group[0].clone(), group[1].clone())
+        };
+        FailureDetection::handle_hb(&Packet::heart_beat(&b, a.addr), &group, &detection.events);
+        assert_eq!(group.lock().expect("Erro no lock do grupo")[1].state, NodeState::Alive);
+        assert_eq!(
+            events_rx.try_iter().collect::<Vec<MembershipEvent>>(),
+            vec![MembershipEvent::NodeSuspected(1), MembershipEvent::NodeRecovered(1)]
+        );
+    }
+
+    /// Detector whose nodes sent a heartbeat every interval for two seconds, the last ones at the given times
+    fn regular(agent_num: usize, last: &[Instant]) -> FailureDetection {
+        initializate_test_folder!();
+        let detector = Detector::PhiAccrual { suspect: PHI_SUSPECT_THRESHOLD, dead: PHI_DEAD_THRESHOLD };
+        let mut detection = FailureDetection::new(group(agent_num), detector, Events::default());
+        for (id, last) in last.iter().enumerate() {
+            detection.intervals[id] = VecDeque::from(vec![HEARTBEAT_INTERVAL; 20]);
+            detection.last_arrival[id] = *last;
+        }
+        detection
+    }
+
+    #[test]
+    fn phi_grows_with_the_silence() {
+        let last = Instant::now();
+        let detection = regular(1, &[last]);
+        let phis: Vec<f64> = [100, 300, 350, 600, 1000]
+            .iter()
+            .map(|millis| detection.phi(0, last + Duration::from_millis(*millis)))
+            .collect();
+        assert!(phis.windows(2).all(|pair| pair[0] < pair[1]), "{phis:?}");
+        assert!(phis[0] < 0.1);
+        assert!(phis[1] < PHI_SUSPECT_THRESHOLD);
+        assert!(phis[2] >= PHI_SUSPECT_THRESHOLD && phis[2] < PHI_DEAD_THRESHOLD);
+        assert!(phis[4] >= PHI_DEAD_THRESHOLD);
+    }
+
+    #[test]
+    fn phi_accrual_suspects_then_kills_a_silent_member() {
+        let now = Instant::now();
+        let mut detection = regular(2, &[now, now - Duration::from_millis(400)]);
+        let events_rx = detection.events.subscribe();
+        let group = Arc::clone(&detection.group);
+        let mut group = group.lock().expect("Erro no lock do grupo");
+        detection.accrue(&mut group, &[], PHI_SUSPECT_THRESHOLD, PHI_DEAD_THRESHOLD);
+        assert_eq!(group[0].state, NodeState::Alive);
+        assert_eq!(group[1].state, NodeState::Suspect);
+        detection.last_arrival[1] -= Duration::from_secs(1);
+        detection.accrue(&mut group, &[], PHI_SUSPECT_THRESHOLD, PHI_DEAD_THRESHOLD);
+        assert_eq!(group[1].state, NodeState::Dead);
+        assert!(group[1].suspicion >= PHI_DEAD_THRESHOLD);
+        assert_eq!(
+            events_rx.try_iter().collect::<Vec<MembershipEvent>>(),
+            vec![MembershipEvent::NodeSuspected(1), MembershipEvent::NodeDied(1)]
+        );
+    }
+
+    #[test]
+    fn phi_accrual_keeps_a_window_of_distinct_arrivals() {
+        let start = Instant::now() - Duration::from_secs(20);
+        let mut detection = regular(1, &[start]);
+        let group = Arc::clone(&detection.group);
+        let mut group = group.lock().expect("Erro no lock do grupo");
+        let arrival = start + HEARTBEAT_INTERVAL;
+        // A packet known both as a heartbeat and as a piggybacked arrival is a single interval
+        detection.accrue(&mut group, &[(0, arrival), (0, arrival)], PHI_SUSPECT_THRESHOLD, PHI_DEAD_THRESHOLD);
+        assert_eq!(detection.intervals[0].len(), 21);
+        let arrivals: Vec<(usize, Instant)> = (2..2 + PHI_WINDOW as u32)
+            .map(|k| (0, start + HEARTBEAT_INTERVAL * k))
+            .collect();
+        detection.accrue(&mut group, &arrivals, PHI_SUSPECT_THRESHOLD, PHI_DEAD_THRESHOLD);
+        assert_eq!(detection.intervals[0].len(), PHI_WINDOW);
+        assert!(detection.intervals[0].iter().all(|interval| *interval == HEARTBEAT_INTERVAL));
     }
 }
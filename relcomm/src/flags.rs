@@ -1,6 +1,6 @@
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-/// ack: 1, last: 2, gsp: 4, syn: 8, fin: 16
+/// ack: 1, last: 2, brd: 4, hb: 8, pll: 16, rpc: 32, mbr: 64, req: 128
 pub struct Flags {
     pub value: u8,
 }
@@ -11,6 +11,10 @@ impl Flags {
     pub const LST: Flags = Flags { value: 2 };
     pub const BRD: Flags = Flags { value: 4 };
     pub const HB: Flags = Flags { value: 8 };
+    pub const PLL: Flags = Flags { value: 16 };
+    pub const RPC: Flags = Flags { value: 32 };
+    pub const MBR: Flags = Flags { value: 64 };
+    pub const REQ: Flags = Flags { value: 128 };
 
     pub fn is_set(&self, flag: Flags) -> bool {
         self.value & flag.value != 0
@@ -30,6 +34,18 @@ impl Flags {
         if self.is_set(Flags::HB) {
             result.push_str("HB ");
         }
+        if self.is_set(Flags::PLL) {
+            result.push_str("PLL ");
+        }
+        if self.is_set(Flags::RPC) {
+            result.push_str("RPC ");
+        }
+        if self.is_set(Flags::MBR) {
+            result.push_str("MBR ");
+        }
+        if self.is_set(Flags::REQ) {
+            result.push_str("REQ ");
+        }
         result
     }
 }
@@ -9,22 +9,30 @@ pub struct Header {
     pub origin: SocketAddr,     // 18 bytes
     pub seq_num: u32,           // 22 bytes
     pub flags: Flags,           // 23 bytes
-    pub checksum: u32,          // 27 bytes
+    pub ttl: u8,                // 24 bytes
+    pub group_id: u32,          // 28 bytes
+    pub epoch: u32,             // 32 bytes
+    pub checksum: u32,          // 36 bytes
 }
 
 // Implementação para que o cabeçalho seja conversível em bytes e vice-versa
 impl Header {
     // Sempre deve-se alterar o tamanho do cabeçalho ao alterar o Header
-    pub const HEADER_SIZE: usize = 27;
-    pub fn new(src_addr: SocketAddr, dst_addr: SocketAddr, origin: SocketAddr,
-            seq_num: u32, flags: Flags, checksum: u32) -> Self {
-        Self {
-            src_addr,
-            dst_addr,
-            origin,
-            seq_num,
-            flags,
-            checksum,
+    pub const HEADER_SIZE: usize = 36;
+    /// Starts the header of a packet from the source to the destination, the other fields are set by the builder
+    pub fn builder(src_addr: SocketAddr, dst_addr: SocketAddr) -> HeaderBuilder {
+        HeaderBuilder {
+            header: Self {
+                src_addr,
+                dst_addr,
+                origin: src_addr,
+                seq_num: 0,
+                flags: Flags::EMP,
+                ttl: 0,
+                group_id: 0,
+                epoch: 0,
+                checksum: 0,
+            },
         }
     }
 
@@ -36,6 +44,9 @@ impl Header {
             origin: self.origin,
             seq_num: self.seq_num,
             flags,
+            ttl: self.ttl,
+            group_id: self.group_id,
+            epoch: 0,
             checksum: 0,
         };
         ack.checksum = Self::checksum(&ack);
@@ -57,6 +68,9 @@ impl Header {
         sum = sum.wrapping_add(Header::sum_addr(header.origin));
         sum = sum.wrapping_add(header.seq_num as u32);
         sum = sum.wrapping_add(header.flags.value as u32);
+        sum = sum.wrapping_add(header.ttl as u32);
+        sum = sum.wrapping_add(header.group_id);
+        sum = sum.wrapping_add(header.epoch);
         sum
     }
 
@@ -76,7 +90,23 @@ impl Header {
         self.flags.is_set(Flags::HB)
     }
 
-    fn addr_to_bytes(addr: SocketAddr) -> Vec<u8> {
+    pub fn is_pull(&self) -> bool {
+        self.flags.is_set(Flags::PLL)
+    }
+
+    pub fn is_rpc(&self) -> bool {
+        self.flags.is_set(Flags::RPC)
+    }
+
+    pub fn is_membership(&self) -> bool {
+        self.flags.is_set(Flags::MBR)
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.flags.is_set(Flags::REQ)
+    }
+
+    pub fn addr_to_bytes(addr: SocketAddr) -> Vec<u8> {
         let mut bytes = Vec::new();
         match addr.ip() {
             IpAddr::V4(ipv4) => bytes.extend_from_slice(&ipv4.octets()),
@@ -93,11 +123,14 @@ impl Header {
         bytes.extend_from_slice(&Header::addr_to_bytes(self.origin));
         bytes.extend_from_slice(&self.seq_num.to_be_bytes());
         bytes.push(self.flags.value);
+        bytes.push(self.ttl);
+        bytes.extend_from_slice(&self.group_id.to_be_bytes());
+        bytes.extend_from_slice(&self.epoch.to_be_bytes());
         bytes.extend_from_slice(&self.checksum.to_be_bytes());
         bytes
     }
 
-    fn addr_from_bytes(bytes: &[u8], start: &mut usize) -> SocketAddr {
+    pub fn addr_from_bytes(bytes: &[u8], start: &mut usize) -> SocketAddr {
         let ip = IpAddr::V4(Ipv4Addr::from([bytes[*start],
             bytes[*start + 1], bytes[*start + 2], bytes[*start + 3]]));
         let port = u16::from_be_bytes([bytes[*start + 4], bytes[*start + 5]]);
@@ -105,7 +138,7 @@ impl Header {
         SocketAddr::new(ip, port)
     }
 
-    fn u32_from_bytes(bytes: &[u8], start: &mut usize) -> u32 {
+    pub fn u32_from_bytes(bytes: &[u8], start: &mut usize) -> u32 {
         let out = u32::from_be_bytes([bytes[*start], bytes[*start + 1], bytes[*start + 2], bytes[*start + 3]]);
         *start += 4;
         out
@@ -119,14 +152,60 @@ impl Header {
         let seq_num = Header::u32_from_bytes(&bytes, &mut start);
         let flags = bytes[start].into();
         start += 1;
+        let ttl = bytes[start];
+        start += 1;
+        let group_id = Header::u32_from_bytes(&bytes, &mut start);
+        let epoch = Header::u32_from_bytes(&bytes, &mut start);
         let checksum = Header::u32_from_bytes(&bytes, &mut start);
-        Header::new(
+        Self {
             src_addr,
             dst_addr,
             origin,
             seq_num,
             flags,
+            ttl,
+            group_id,
+            epoch,
             checksum,
-        )
+        }
+    }
+}
+
+/// Builds a header field by field, the origin defaults to the source and the other fields to zero
+/// The epoch is the incarnation of the process that sends the packet, it's stamped by the channel when sending
+pub struct HeaderBuilder {
+    header: Header,
+}
+
+impl HeaderBuilder {
+    pub fn origin(mut self, origin: SocketAddr) -> Self {
+        self.header.origin = origin;
+        self
+    }
+
+    pub fn seq_num(mut self, seq_num: u32) -> Self {
+        self.header.seq_num = seq_num;
+        self
+    }
+
+    /// Adds the flags to the ones already set
+    pub fn flags(mut self, flags: Flags) -> Self {
+        self.header.flags = self.header.flags | flags;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.header.ttl = ttl;
+        self
+    }
+
+    pub fn group_id(mut self, group_id: u32) -> Self {
+        self.header.group_id = group_id;
+        self
+    }
+
+    /// The header without checksum, which also covers the data of the packet
+    pub fn build(self) -> Header {
+        self.header
     }
 }
@@ -4,7 +4,15 @@ mod rec_listener;
 mod rec_sender;
 mod rec_aux;
 mod channels;
-mod failure_detection;
+pub mod failure_detection;
+mod swim;
+mod anti_entropy;
+mod multicast;
+pub mod rpc;
+pub mod membership;
+pub mod view;
+pub mod election;
+pub mod discovery;
 mod packet;
 mod header;
 mod flags;
@@ -0,0 +1,392 @@
+/*
+O protocolo de membros (Membership) permite que processos entrem e saiam do grupo sem reiniciá-lo.
+Pedidos de entrada (join) e saída (leave) são encaminhados ao líder eleito, que é o único a alterar a lista de membros:
+a cada mudança ele incrementa a versão da lista e envia a lista completa para todos os membros,
+que adotam qualquer lista com versão maior que a sua, concordando assim sobre quem faz parte do grupo.
+A versão é o par (mandato do líder, contador), então as listas de um líder novo sempre superam as dos anteriores,
+mesmo que o contador dele esteja atrás. Uma lista que troca o endereço do próprio processo é recusada inteira.
+Um processo fora do grupo (que ainda não entrou ou que já saiu) não considera nenhum outro processo como membro.
+As aplicações podem consultar os membros e o líder, e assinar os eventos de membros (entradas, saídas, suspeitas, mortes,
+recuperações e troca de líder), que são emitidos por quem muda o estado do grupo (detectores, membros, eleição e listener)
+no momento da mudança, então nem as mudanças desfeitas logo em seguida passam despercebidas.
+*/
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use logger::debug;
+use crate::header::Header;
+use crate::node::{Node, NodeState};
+use crate::rec_aux::{SendRequest, SendRequestData, RecAux};
+use crate::view::{ViewSync, FLUSH, FLUSH_OK, INSTALL};
+use crate::election::{Election, ELECTION, ANSWER, COORDINATOR};
+
+/// Kinds of membership messages, the first byte of each message
+const JOIN: u8 = 0;
+const LEAVE: u8 = 1;
+const VIEW: u8 = 2;
+
+/// Changes in the group that applications may subscribe to, identified by the agent number
+#[derive(Clone, Debug, PartialEq)]
+pub enum MembershipEvent {
+    NodeJoined(usize),
+    NodeLeft(usize),
+    NodeSuspected(usize),
+    NodeDied(usize),
+    // A suspected or dead node is alive again, or the node restarted
+    NodeRecovered(usize),
+    LeaderChanged(usize),
+}
+
+/// Version of a member list: the term of the leader that changed it and a counter of the changes in the term,
+/// so the lists of a new leader are always newer than the ones of the previous leaders
+type Version = (u32, u32);
+
+/// Subscribers of the membership events, shared by every thread that changes the state of the group
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Sender<MembershipEvent>>>>,
+}
+
+impl Events {
+    /// Returns a channel that receives every membership event from now on
+    pub fn subscribe(&self) -> Receiver<MembershipEvent> {
+        let (events_tx, events_rx) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .expect("Erro ao assinar eventos: Mutex lock dos assinantes falhou")
+            .push(events_tx);
+        events_rx
+    }
+
+    pub fn emit(&self, event: MembershipEvent) {
+        debug!("Evento de membros: {event:?}");
+        self.subscribers
+            .lock()
+            .expect("Erro ao avisar eventos: Mutex lock dos assinantes falhou")
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Changes the state of the node, emitting the event of the change if it's one the subscribers are told about
+    pub fn set_state(&self, node: &mut Node, state: NodeState) {
+        if let Some(event) = Self::change(node, &state) {
+            self.emit(event);
+        }
+        node.state = state;
+    }
+
+    fn change(node: &Node, state: &NodeState) -> Option<MembershipEvent> {
+        let agent_number = node.agent_number;
+        let previous = &node.state;
+        match (*previous != NodeState::Left, *state != NodeState::Left) {
+            (false, true) => Some(MembershipEvent::NodeJoined(agent_number)),
+            (true, false) => Some(MembershipEvent::NodeLeft(agent_number)),
+            (false, false) => None,
+            (true, true) => match state {
+                NodeState::Dead if *previous != NodeState::Dead => Some(MembershipEvent::NodeDied(agent_number)),
+                NodeState::Suspect if *previous == NodeState::Alive || *previous == NodeState::Unborn =>
+                    Some(MembershipEvent::NodeSuspected(agent_number)),
+                NodeState::Alive if *previous == NodeState::Suspect || *previous == NodeState::Dead =>
+                    Some(MembershipEvent::NodeRecovered(agent_number)),
+                _ => None,
+            },
+        }
+    }
+}
+
+pub struct Membership {
+    host: Node,
+    group: Arc<Mutex<Vec<Node>>>,
+    reg_to_snd_tx: Sender<SendRequest>,
+    views: Arc<ViewSync>,
+    election: Arc<Election>,
+    version: Mutex<Version>,
+    join_waiters: Mutex<Vec<Sender<()>>>,
+    events: Events,
+}
+
+impl RecAux for Membership {}
+
+impl Membership {
+    /// Constructor
+    pub fn new(host: Node, group: Arc<Mutex<Vec<Node>>>, reg_to_snd_tx: Sender<SendRequest>,
+        views: Arc<ViewSync>, election: Arc<Election>, events: Events) -> Self {
+        Self {
+            host,
+            group,
+            reg_to_snd_tx,
+            views,
+            election,
+            version: Mutex::new((0, 0)),
+            join_waiters: Mutex::new(Vec::new()),
+            events,
+        }
+    }
+
+    /// Thread that handles the membership messages received by the listener
+    /// View change messages are passed on to the virtual synchrony, and election messages to the election
+    pub fn run(&self, membership_rx: Receiver<(SocketAddr, Vec<u8>)>) {
+        while let Ok((src, message)) = membership_rx.recv() {
+            match message.first() {
+                Some(&JOIN) | Some(&LEAVE) => self.handle_request(message),
+                Some(&VIEW) => self.adopt(&message),
+                Some(&FLUSH) | Some(&FLUSH_OK) | Some(&INSTALL) => self.views.handle(src, &message),
+                Some(&ELECTION) | Some(&ANSWER) | Some(&COORDINATOR) => self.election.handle(src, &message),
+                _ => {
+                    debug!("Mensagem de membros inválida recebida de Agent {}", Self::get_agnt(&src));
+                }
+            }
+        }
+    }
+
+    /// Returns a channel that receives every membership event from now on
+    pub fn subscribe(&self) -> Receiver<MembershipEvent> {
+        self.events.subscribe()
+    }
+
+    /// Current members of the group, with their states
+    pub fn members(&self) -> Vec<Node> {
+        self.group
+            .lock()
+            .expect("Erro ao listar membros: Mutex lock do grupo falhou")
+            .iter()
+            .filter(|node| node.is_member())
+            .cloned()
+            .collect()
+    }
+
+    /// The elected leader, while an election is going on it's the first living member (the host if there's none)
+    pub fn leader(&self) -> Node {
+        match self.election.leader() {
+            Some((_, leader)) => leader,
+            None => Self::get_leader(&self.group, &self.host, None),
+        }
+    }
+
+    /// State of a node, or None if there's no node with this agent number
+    pub fn state_of(&self, agent_number: usize) -> Option<NodeState> {
+        self.group
+            .lock()
+            .expect("Erro ao obter estado: Mutex lock do grupo falhou")
+            .get(agent_number)
+            .map(|node| node.state.clone())
+    }
+
+    /// Whether the host is currently a member of the group
+    pub fn is_member(&self) -> bool {
+        self.group
+            .lock()
+            .expect("Erro ao verificar membro: Mutex lock do grupo falhou")
+            .get(self.host.agent_number)
+            .is_some_and(|node| node.is_member())
+    }
+
+    /// Asks the seed to let the host in, the returned channel is warned once the host is in the member list
+    /// Returns None if the request couldn't reach the seed
+    pub fn join(&self, seed_addr: SocketAddr) -> Option<Receiver<()>> {
+        let (joined_tx, joined_rx) = std::sync::mpsc::channel();
+        self.join_waiters
+            .lock()
+            .expect("Erro ao entrar no grupo: Mutex lock dos join_waiters falhou")
+            .push(joined_tx);
+        let mut message = vec![JOIN];
+        message.extend((self.host.agent_number as u32).to_be_bytes());
+        message.extend(Header::addr_to_bytes(self.host.addr));
+        match self.send(seed_addr, message).recv() {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(joined_rx),
+        }
+    }
+
+    /// Asks the leader to remove the host from the group, then stops considering anyone a member
+    /// Returns false if the request couldn't reach the leader
+    pub fn leave(&self) -> bool {
+        let mut message = vec![LEAVE];
+        message.extend((self.host.agent_number as u32).to_be_bytes());
+        let leader = self.leader();
+        let left = if leader.addr == self.host.addr {
+            self.handle_request(message);
+            true
+        } else {
+            !matches!(self.send(leader.addr, message).recv(), Ok(0) | Err(_))
+        };
+        if left {
+            self.stand_alone();
+        }
+        left
+    }
+
+    /// Join and leave requests are applied by the leader, anyone else passes them on to it
+    fn handle_request(&self, message: Vec<u8>) {
+        let leader = self.leader();
+        if leader.addr != self.host.addr {
+            debug!("Encaminhando pedido de membros para o líder Agent {}", leader.agent_number);
+            self.send(leader.addr, message);
+            return;
+        }
+        let agent_number = match message.get(1..5) {
+            Some(bytes) => u32::from_be_bytes(bytes.try_into().expect("Slice de tamanho 4")) as usize,
+            None => return,
+        };
+        let mut extra = None;
+        {
+            let mut group = self.group.lock().expect("Erro ao alterar membros: Mutex lock do grupo falhou");
+            if message[0] == JOIN {
+                if message.len() < 11 { return; }
+                let mut start = 5;
+                let addr = Header::addr_from_bytes(&message, &mut start);
+                let len = group.len();
+                match group.get_mut(agent_number) {
+                    Some(node) if node.addr != addr => {
+                        debug!("Pedido de entrada recusado: Agent {agent_number} já pertence a outro endereço");
+                        return;
+                    }
+                    Some(node) => {
+                        if !node.is_member() {
+                            self.events.set_state(node, NodeState::Unborn);
+                        }
+                    }
+                    None if agent_number == len => {
+                        self.events.emit(MembershipEvent::NodeJoined(agent_number));
+                        group.push(Node::new(addr, agent_number));
+                    }
+                    None => {
+                        debug!("Pedido de entrada recusado: Agent {agent_number} deixaria um buraco na lista");
+                        return;
+                    }
+                }
+                debug!("Agent {agent_number} entrou no grupo");
+            } else {
+                match group.get_mut(agent_number) {
+                    Some(node) if node.is_member() => {
+                        self.events.set_state(node, NodeState::Left);
+                        // The node that left also gets the new list, so it knows it's out
+                        extra = Some(node.addr);
+                    }
+                    _ => return,
+                }
+                debug!("Agent {agent_number} saiu do grupo");
+            }
+        }
+        let version = {
+            let term = self.election.term();
+            let mut version = self.version.lock().expect("Erro ao alterar membros: Mutex lock da versão falhou");
+            if term > version.0 {
+                *version = (term, 1);
+            } else {
+                version.1 += 1;
+            }
+            *version
+        };
+        self.send_view(version, extra);
+    }
+
+    /// Sends the member list to every other member
+    fn send_view(&self, (term, counter): Version, extra: Option<SocketAddr>) {
+        let (message, mut targets) = {
+            let group = self.group.lock().expect("Erro ao enviar membros: Mutex lock do grupo falhou");
+            let mut message = vec![VIEW];
+            message.extend(term.to_be_bytes());
+            message.extend(counter.to_be_bytes());
+            message.extend((group.len() as u32).to_be_bytes());
+            for node in group.iter() {
+                message.extend(Header::addr_to_bytes(node.addr));
+                message.push(node.is_member() as u8);
+            }
+            let targets: Vec<SocketAddr> = group.iter()
+                .filter(|node| node.is_member() && node.addr != self.host.addr)
+                .map(|node| node.addr)
+                .collect();
+            (message, targets)
+        };
+        targets.extend(extra);
+        for dst_addr in targets {
+            self.send(dst_addr, message.clone());
+        }
+    }
+
+    /// Adopts a member list that is newer than the current one
+    fn adopt(&self, message: &[u8]) {
+        let (version, entries) = match Self::parse_view(message) {
+            Some(view) => view,
+            None => {
+                debug!("Lista de membros inválida");
+                return;
+            }
+        };
+        {
+            let mut current = self.version.lock().expect("Erro ao adotar membros: Mutex lock da versão falhou");
+            if version <= *current { return; }
+            let mut group = self.group.lock().expect("Erro ao adotar membros: Mutex lock do grupo falhou");
+            // The leader can't move the host to another address, such a list is rejected whole
+            if entries.get(self.host.agent_number).is_some_and(|(addr, _)| *addr != self.host.addr) {
+                debug!("Lista de membros versão {version:?} recusada: troca o endereço do próprio Agent {}",
+                    self.host.agent_number);
+                return;
+            }
+            *current = version;
+            for (agent_number, (addr, member)) in entries.into_iter().enumerate() {
+                // New nodes start out of the group, so the ones that are members are announced as joined
+                if agent_number == group.len() {
+                    let mut node = Node::new(addr, agent_number);
+                    node.state = NodeState::Left;
+                    group.push(node);
+                }
+                let node = &mut group[agent_number];
+                // The leader gave the agent number to another address, the list of the leader prevails
+                if node.addr != addr {
+                    debug!("Agent {agent_number} passou de {} para {addr} na lista de membros", node.addr);
+                    self.events.set_state(node, NodeState::Left);
+                    *node = Node::new(addr, agent_number);
+                    node.state = NodeState::Left;
+                }
+                if !member {
+                    self.events.set_state(node, NodeState::Left);
+                } else if !node.is_member() {
+                    self.events.set_state(node, NodeState::Unborn);
+                }
+            }
+        }
+        debug!("Adotou a lista de membros versão {version:?}");
+        if !self.is_member() {
+            self.stand_alone();
+            return;
+        }
+        for waiter in self.join_waiters
+            .lock()
+            .expect("Erro ao adotar membros: Mutex lock dos join_waiters falhou")
+            .drain(..) {
+            let _ = waiter.send(());
+        }
+    }
+
+    /// Outside the group, no other node is considered a member
+    fn stand_alone(&self) {
+        for node in self.group.lock().expect("Erro ao sair do grupo: Mutex lock do grupo falhou").iter_mut() {
+            self.events.set_state(node, NodeState::Left);
+        }
+    }
+
+    fn parse_view(message: &[u8]) -> Option<(Version, Vec<(SocketAddr, bool)>)> {
+        let term = u32::from_be_bytes(message.get(1..5)?.try_into().ok()?);
+        let counter = u32::from_be_bytes(message.get(5..9)?.try_into().ok()?);
+        let len = u32::from_be_bytes(message.get(9..13)?.try_into().ok()?) as usize;
+        let mut entries = Vec::new();
+        let mut start = 13;
+        for _ in 0..len {
+            if message.len() < start + 7 { return None; }
+            let addr = Header::addr_from_bytes(message, &mut start);
+            entries.push((addr, message[start] != 0));
+            start += 1;
+        }
+        Some(((term, counter), entries))
+    }
+
+    fn send(&self, dst_addr: SocketAddr, message: Vec<u8>) -> Receiver<u32> {
+        let (request, result_rx) = SendRequest::new(message, SendRequestData::Membership { dst_addr });
+        if let Err(e) = self.reg_to_snd_tx.send(request) {
+            debug!("Erro ao enviar mensagem de membros: {e}");
+        }
+        result_rx
+    }
+}
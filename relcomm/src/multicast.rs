@@ -0,0 +1,108 @@
+/*
+Um multicast é uma difusão restrita a um subconjunto dos membros do grupo.
+A lista de membros viaja no início da mensagem, para que qualquer processo que a retransmita
+saiba quem são os destinatários, e o identificador do subconjunto viaja no cabeçalho,
+separando a numeração de sequência de cada subconjunto.
+Grupos nomeados são multicasts permanentes, com seu próprio algoritmo de difusão e sua própria fila de recebimento,
+compartilhando o mesmo socket e as mesmas threads da comunicação confiável.
+*/
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+
+use crate::rec_aux::Broadcast;
+
+/// Subset of the group a multicast is addressed to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Multicast {
+    pub id: u32,
+    pub members: Vec<usize>,
+}
+
+/// A named group, with its members, broadcast algorithm and the queue its messages are delivered to
+pub struct Group {
+    pub multicast: Multicast,
+    pub broadcast: Broadcast,
+    pub messages_tx: Sender<Vec<u8>>,
+}
+
+/// Named groups created on this node, by group id
+pub type Groups = Arc<Mutex<HashMap<u32, Group>>>;
+
+impl Multicast {
+    /// Id of the stream of broadcasts addressed to the whole group
+    pub const GROUP_ID: u32 = 0;
+    /// Bit set on the ids of named groups, so they never collide with the ids of multicasts
+    const NAMED: u32 = 1 << 31;
+
+    /// Constructor, the same set of members always results in the same id
+    pub fn new(members: &[usize]) -> Self {
+        let members = Self::sort_members(members);
+        let bytes: Vec<u8> = members.iter().flat_map(|m| (*m as u32).to_be_bytes()).collect();
+        let mut id = Self::hash(&bytes) & !Self::NAMED;
+        if id == Self::GROUP_ID {
+            id = 1;
+        }
+        Self { id, members }
+    }
+
+    /// Constructor for named groups, the id depends only on the name
+    pub fn named(name: &str, members: &[usize]) -> Self {
+        Self {
+            id: Self::named_id(name),
+            members: Self::sort_members(members),
+        }
+    }
+
+    /// Id of a named group
+    pub fn named_id(name: &str) -> u32 {
+        Self::hash(name.as_bytes()) | Self::NAMED
+    }
+
+    pub fn is_named(id: u32) -> bool {
+        id & Self::NAMED != 0
+    }
+
+    pub fn contains(&self, agent_number: usize) -> bool {
+        self.members.contains(&agent_number)
+    }
+
+    /// Prepends the members list to the message
+    pub fn to_bytes(&self, message: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.members.len() as u32).to_be_bytes());
+        for member in self.members.iter() {
+            bytes.extend_from_slice(&(*member as u32).to_be_bytes());
+        }
+        bytes.extend_from_slice(message);
+        bytes
+    }
+
+    /// Splits a multicast message into its members and the original message
+    pub fn from_bytes(id: u32, bytes: &[u8]) -> Option<(Self, Vec<u8>)> {
+        let len = u32::from_be_bytes(bytes.get(0..4)?.try_into().ok()?) as usize;
+        let end = 4 + 4 * len;
+        let mut members = Vec::new();
+        for chunk in bytes.get(4..end)?.chunks(4) {
+            members.push(u32::from_be_bytes(chunk.try_into().ok()?) as usize);
+        }
+        Some((Self { id, members }, bytes[end..].to_vec()))
+    }
+
+    fn sort_members(members: &[usize]) -> Vec<usize> {
+        let mut members = members.to_vec();
+        members.sort();
+        members.dedup();
+        members
+    }
+
+    /// FNV-1a
+    fn hash(bytes: &[u8]) -> u32 {
+        let mut hash: u32 = 0x811c9dc5;
+        for byte in bytes {
+            hash ^= *byte as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+        hash
+    }
+}
@@ -7,6 +7,8 @@ pub enum NodeState {
     Alive,
     Dead,
     Suspect,
+    // Not a member of the group, either because it left or because it hasn't joined yet
+    Left,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -14,6 +16,10 @@ pub struct Node {
     pub addr: SocketAddr,
     pub agent_number: usize,
     pub state: NodeState,
+    // How much the failure detector suspects the node, higher means more likely to have failed
+    pub suspicion: f64,
+    // Incarnation of the process, it changes when the process restarts (0 while unknown)
+    pub epoch: u32,
 }
 
 impl Node {
@@ -22,6 +28,8 @@ impl Node {
             addr,
             agent_number,
             state: NodeState::Unborn,
+            suspicion: 0.,
+            epoch: 0,
         }
     }
 
@@ -33,6 +41,10 @@ impl Node {
         self.state == NodeState::Unborn
     }
 
+    pub fn is_member(&self) -> bool {
+        self.state != NodeState::Left
+    }
+
     pub fn is_alive(&self) -> bool {
         self.state == NodeState::Alive
     }
@@ -49,6 +61,7 @@ impl Display for NodeState {
             NodeState::Unborn => write!(f, "Unborn"),
             NodeState::Alive => write!(f, "Alive"),
             NodeState::Dead => write!(f, "Dead"),
+            NodeState::Left => write!(f, "Left"),
         }
     }    
 }
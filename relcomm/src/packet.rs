@@ -4,7 +4,7 @@ use std::fmt::{self, Debug, Display, Formatter};
 
 // use crate::config::BUFFER_SIZE;
 use crate::flags::Flags;
-use crate::header::Header;
+use crate::header::{Header, HeaderBuilder};
 use crate::node::Node;
 
 #[derive(Clone)]
@@ -16,41 +16,30 @@ pub struct Packet {
 impl Packet {
     // Tamanho do buffer
     pub const BUFFER_SIZE: usize = 2<<9;
-    pub fn new(
-        src_addr: SocketAddr,
-        dst_addr: SocketAddr,
-        origin: SocketAddr,
-        seq_num: u32,
-        is_last: bool,
-        is_ack: bool,
-        is_brd: bool,
-        data: Vec<u8>
-    ) -> Self {
-        let mut flags:Flags = {
-            Flags::EMP
-        };
-        if is_last {
-            flags = flags | Flags::LST;
-        }
-        if is_ack {
-            flags = flags | Flags::ACK
-        }
-        if is_brd  {
-            flags = flags | Flags::BRD
-        };
-
-        let mut header = Header::new(src_addr, dst_addr, origin, seq_num, flags, 0);
-        let checksum = Self::checksum(&header, &data);
-        header.checksum = checksum;
+    /// Packet with the header and the data, the checksum covers both
+    pub fn new(mut header: Header, data: Vec<u8>) -> Self {
+        header.checksum = Self::checksum(&header, &data);
         Self { header, data }
     }
 
     pub fn heart_beat(host: &Node, dst_addr: SocketAddr) -> Self {
-        let mut header = Header::new(host.addr, dst_addr, host.addr, host.agent_number as u32, Flags::HB, 0);
+        let mut header = Header::builder(host.addr, dst_addr)
+            .seq_num(host.agent_number as u32)
+            .flags(Flags::HB)
+            .build();
         header.checksum = Header::checksum(&header);
         Self { header, data: Vec::new() }
     }
 
+    /// Heartbeat that carries data, used by the SWIM messages
+    pub fn probe(host: &Node, dst_addr: SocketAddr, data: Vec<u8>) -> Self {
+        let header = Header::builder(host.addr, dst_addr)
+            .seq_num(host.agent_number as u32)
+            .flags(Flags::HB)
+            .build();
+        Self::new(header, data)
+    }
+
     pub fn get_ack(&self) -> Self {
         let ack_header = self.header.get_ack();
         Self {header: ack_header, data: Vec::new()}
@@ -76,27 +65,22 @@ impl Packet {
         sum
     }
 
-    pub fn packets_from_message(
-        src_addr: SocketAddr,
-        dst_addr: SocketAddr,
-        origin: SocketAddr,
-        data: Vec<u8>,
-        seq_num: u32,
-        is_brd: bool,
-    ) -> Vec<Self> {
-        let chunks: Vec<&[u8]> = data.chunks(Packet::BUFFER_SIZE - Header::HEADER_SIZE).collect();
+    /// Splits the message in packets with the fields of the header, numbered from its sequence number
+    pub fn packets_from_message(header: HeaderBuilder, data: Vec<u8>) -> Vec<Self> {
+        let header = header.build();
+        let mut chunks: Vec<&[u8]> = data.chunks(Packet::BUFFER_SIZE - Header::HEADER_SIZE).collect();
+        // Empty messages are still sent as a single empty packet
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
 
         chunks.iter().enumerate().map(|(i, chunk)| {
-            Packet::new(
-                src_addr,
-                dst_addr,
-                origin,
-                seq_num + i as u32,
-                i == (chunks.len() - 1),
-                false,
-                is_brd,
-                chunk.to_vec(),
-            )
+            let mut header = header.clone();
+            header.seq_num += i as u32;
+            if i == chunks.len() - 1 {
+                header.flags = header.flags | Flags::LST;
+            }
+            Packet::new(header, chunk.to_vec())
         }).collect()
     }
 }
@@ -111,7 +95,10 @@ impl Debug for Packet {
 impl Display for Packet {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let pkt = if self.header.is_ack() { "ACK" } else if self.header.is_heartbeat() {
-            "Heartbeat"} else if self.header.is_brd() {
+            "Heartbeat"} else if self.header.is_pull() {
+            "Pull" } else if self.header.is_rpc() {
+            "RPC" } else if self.header.is_membership() {
+            "Membership" } else if self.header.is_brd() {
             "Broadcast" } else { "Packet" };
         write!(f, "{pkt} num {}: Agent {} -> Agent {}, origin: {}", self.header.seq_num,
         self.header.src_addr.port() % 100, self.header.dst_addr.port() % 100, self.header.origin.port() % 100)
@@ -1,8 +1,10 @@
 use std::net::SocketAddr;
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use rand::seq::SliceRandom;
 
 use logger::debug;
+use crate::multicast::{Multicast, Groups};
 use crate::node::Node;
 
 #[derive(Clone)]
@@ -11,16 +13,64 @@ pub enum SendRequestData {
     Send {
         dst_addr: SocketAddr,
     },
-    // Creates as many messages as needed to broadcast to the group
-    StartBroadcast {},
-    RequestLeader {},
+    // Creates as many messages as needed to broadcast to the group, or to the members of the multicast
+    StartBroadcast {
+        multicast: Option<Multicast>,
+    },
+    // Asks the leader of the term to broadcast the message, the term travels before the data
+    RequestLeader {
+        dst_addr: SocketAddr,
+        term: u32,
+        multicast: Option<Multicast>,
+    },
     // Creates N messages to gossip to neighbors, keeping the original message information
     Gossip {
         origin: SocketAddr,
         seq_num: u32,
+        ttl: u8,
+        multicast: Option<Multicast>,
+    },
+    // Retransmits a received uniform broadcast to every living member, keeping the original message information
+    Relay {
+        origin: SocketAddr,
+        seq_num: u32,
+        multicast: Option<Multicast>,
+    },
+    // Sends the digest of the delivered broadcasts, asking the destination for the missing ones
+    Pull {
+        dst_addr: SocketAddr,
+    },
+    // Sends a message of the RPC layer (request, response or cancellation) to a specific destination
+    Rpc {
+        dst_addr: SocketAddr,
+    },
+    // Sends a message of the membership protocol (join, leave or view) to a specific destination
+    Membership {
+        dst_addr: SocketAddr,
+    },
+    // Forgets the sequence numbers used with a destination that restarted, the next messages start from zero
+    Reset {
+        dst_addr: SocketAddr,
+    },
+    // Resends a delivered broadcast to a specific destination, keeping the original message information
+    Repair {
+        dst_addr: SocketAddr,
+        origin: SocketAddr,
+        seq_num: u32,
+        group_id: u32,
     },
 }
 
+impl SendRequestData {
+    /// Whether the request numbers its packets with the sequence number counter of the destination
+    /// Gossips and repairs keep the original numbering and broadcasts use the broadcast counter
+    pub fn uses_dst_seq_num(&self) -> bool {
+        matches!(self, SendRequestData::Send { .. } | SendRequestData::Pull { .. }
+            | SendRequestData::Rpc { .. } | SendRequestData::Membership { .. }
+            | SendRequestData::RequestLeader { .. })
+    }
+}
+
 #[derive(Clone)]
 pub struct SendRequest {
     pub result_tx: Sender<u32>,
@@ -48,14 +98,49 @@ pub enum Broadcast {
     URB,
     AB,
 }
+
+impl Broadcast {
+    /// Converts the name used in the config file to the algorithm
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "BEB" => Some(Broadcast::BEB),
+            "URB" => Some(Broadcast::URB),
+            "AB" => Some(Broadcast::AB),
+            _ => None,
+        }
+    }
+}
+
+/// How broadcasts are disseminated between the nodes
+/// Push: every node that receives a broadcast for the first time gossips it while its TTL lasts
+/// Pull: nodes periodically ask a random peer for the broadcasts they are missing
+/// PushPull: both of the above
+#[derive(PartialEq, Clone)]
+pub enum GossipMode {
+    Push,
+    Pull,
+    PushPull,
+}
+
+impl GossipMode {
+    pub fn push(&self) -> bool {
+        *self != GossipMode::Pull
+    }
+
+    pub fn pull(&self) -> bool {
+        *self != GossipMode::Push
+    }
+}
+
 /// This struct contains helper functions that are used by the main, listener and sender thread
 pub trait RecAux {
     /// Creates a broadcast request and sends it to the sender thread
-    fn brd_req(register_to_sender_tx: &Sender<SendRequest>, data: Vec<u8>) -> Receiver<u32>{
+    /// Multicasts are broadcasts restricted to their members, their data already carries the members list
+    fn brd_req(register_to_sender_tx: &Sender<SendRequest>, data: Vec<u8>, multicast: Option<Multicast>) -> Receiver<u32>{
         debug!("Enviando broadcast");
         let (request, request_rx) = SendRequest::new(
             data,
-            SendRequestData::StartBroadcast {},
+            SendRequestData::StartBroadcast { multicast },
         );
         match register_to_sender_tx.send(request) {
             Ok(_) => {}
@@ -68,17 +153,20 @@ pub trait RecAux {
 
     /// Picks the node "friends" and retransmits the message to them
     /// This retransmission preserves the original message information about the origin and sequence number
-    /// The friends are any group of N nodes in the group, where N is the gossip rate.
-    /// Currently it's the next N nodes in the group vector
+    /// The friends are N random living nodes in the group, where N is the gossip rate.
+    /// The ttl is the number of hops the message may still be gossiped after this one
     /// Since gossip algorithms are meant to ensure that the message will be successfully difused,
     /// even if there are failing nodes, this function doesn't need to wait for the result of the gossip.
     /// (It's also important to not block the listener thread when it needs to gossip a message)
-    fn gossip(reg_to_snd_tx: &Sender<SendRequest>, data: Vec<u8>, origin: SocketAddr, seq_num: u32) {
+    fn gossip(reg_to_snd_tx: &Sender<SendRequest>, data: Vec<u8>, origin: SocketAddr,
+        seq_num: u32, ttl: u8, multicast: Option<Multicast>) {
         let (request, _) = SendRequest::new(
             data,
             SendRequestData::Gossip {
                 origin,
                 seq_num,
+                ttl,
+                multicast,
             },
         );
         match reg_to_snd_tx.send(request) {
@@ -89,10 +177,33 @@ pub trait RecAux {
         }
     }
 
+    /// Retransmits the message to every other living member of the group (or of the multicast)
+    /// Used by the uniform reliable broadcast, where every copy received tells that its sender has the message
+    /// Like the gossip, it doesn't wait for the result
+    fn relay(reg_to_snd_tx: &Sender<SendRequest>, data: Vec<u8>, origin: SocketAddr,
+        seq_num: u32, multicast: Option<Multicast>) {
+        let (request, _) = SendRequest::new(
+            data,
+            SendRequestData::Relay {
+                origin,
+                seq_num,
+                multicast,
+            },
+        );
+        match reg_to_snd_tx.send(request) {
+            Ok(_) => {}
+            Err(e) => {
+                debug!("Erro ao retransmitir mensagem: {e}");
+            }
+        }
+    }
+
     /// Returns the node with the highest priority (currently the first one alive in the group vector)
-    fn get_leader(group: &Arc<Mutex<Vec<Node>>>, host: &Node) -> Node {
+    /// The leader of a multicast is the first one alive among its members
+    fn get_leader(group: &Arc<Mutex<Vec<Node>>>, host: &Node, multicast: Option<&Multicast>) -> Node {
         for node in group.lock().expect("Falha ao ler do grupo").iter() {
-            if !node.is_dead() {
+            if multicast.is_some_and(|m| !m.contains(node.agent_number)) { continue; }
+            if !node.is_dead() && node.is_member() {
                 debug!("Agente {} escolheu {} como líder", host.agent_number, node.agent_number);
                 return node.clone();
             }
@@ -100,17 +211,39 @@ pub trait RecAux {
         return host.clone();
     }
 
-    /// Returns a vector with all nodes that are not dead
+    /// Returns a vector with all members that are not dead
     fn get_livings(group: &Arc<Mutex<Vec<Node>>>) -> Vec<Node> {
         let mut livings = Vec::new();
         for node in group.lock().expect("Falha ao ler do grupo").iter() {
-            if !node.is_dead() {
+            if !node.is_dead() && node.is_member() {
                 livings.push(node.clone());
             }
         }
         livings
     }
 
+    /// Returns up to N random living nodes of the group, never including the host
+    /// For multicasts, only its members are considered
+    fn get_friends(group: &Arc<Mutex<Vec<Node>>>, host: &Node, n: usize, multicast: Option<&Multicast>) -> Vec<SocketAddr> {
+        let livings: Vec<SocketAddr> = Self::get_livings(group)
+            .iter()
+            .filter(|node| node.addr != host.addr)
+            .filter(|node| multicast.is_none_or(|m| m.contains(node.agent_number)))
+            .map(|node| node.addr)
+            .collect();
+        livings.choose_multiple(&mut rand::thread_rng(), n).cloned().collect()
+    }
+
+    /// Returns the broadcast algorithm used by the group id
+    /// Named groups have their own, everything else uses the configured one
+    fn get_broadcast(groups: &Groups, default: &Broadcast, group_id: u32) -> Broadcast {
+        groups
+            .lock()
+            .expect("Falha ao ler dos grupos nomeados")
+            .get(&group_id)
+            .map_or(default.clone(), |group| group.broadcast.clone())
+    }
+
     fn get_agnt(addr: &SocketAddr) -> usize {
         addr.port() as usize % 100
     }
@@ -132,3 +265,70 @@ pub trait RecAux {
         result_rx
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use crate::node::NodeState;
+    use logger::free_ports;
+
+    struct Aux;
+
+    impl RecAux for Aux {}
+
+    fn group(agent_num: usize) -> Arc<Mutex<Vec<Node>>> {
+        let group = free_ports!(agent_num)
+            .into_iter()
+            .enumerate()
+            .map(|(i, port)| {
+                let mut node = Node::new(SocketAddr::from(([127, 0, 0, 1], port)), i);
+                node.state = NodeState::Alive;
+                node
+            })
+            .collect();
+        Arc::new(Mutex::new(group))
+    }
+
+    #[test]
+    fn friends_are_other_living_members() {
+        let group = group(6);
+        let (host, dead, left) = {
+            let mut group = group.lock().expect("Erro no lock do grupo");
+            group[1].state = NodeState::Dead;
+            group[2].state = NodeState::Left;
+            (group[0].clone(), group[1].addr, group[2].addr)
+        };
+        let mut seen = HashSet::new();
+        for _ in 0..50 {
+            let friends = Aux::get_friends(&group, &host, 2, None);
+            assert_eq!(friends.len(), 2);
+            assert_eq!(friends.iter().collect::<HashSet<_>>().len(), 2);
+            assert!(!friends.iter().any(|friend| [host.addr, dead, left].contains(friend)));
+            seen.extend(friends);
+        }
+        // The friends are random, every living member is eventually picked
+        assert_eq!(seen.len(), 3);
+        assert_eq!(Aux::get_friends(&group, &host, 10, None).len(), 3);
+    }
+
+    #[test]
+    fn friends_of_a_multicast_are_its_members() {
+        let group = group(5);
+        let (host, members) = {
+            let group = group.lock().expect("Erro no lock do grupo");
+            (group[0].clone(), HashSet::from([group[3].addr, group[4].addr]))
+        };
+        let multicast = Multicast::new(&[0, 3, 4]);
+        let friends = Aux::get_friends(&group, &host, 5, Some(&multicast));
+        assert_eq!(friends.len(), 2);
+        assert_eq!(friends.into_iter().collect::<HashSet<SocketAddr>>(), members);
+    }
+
+    #[test]
+    fn gossip_modes_push_and_pull() {
+        assert!(GossipMode::Push.push() && !GossipMode::Push.pull());
+        assert!(!GossipMode::Pull.push() && GossipMode::Pull.pull());
+        assert!(GossipMode::PushPull.push() && GossipMode::PushPull.pull());
+    }
+}
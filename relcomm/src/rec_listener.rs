@@ -1,14 +1,55 @@
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::Instant;
 
 use logger::debug;
-use crate::failure_detection::FailureDetection;
-use crate::rec_aux::{SendRequest, Broadcast, RecAux};
+use crate::anti_entropy::{AntiEntropy, History};
+use crate::rec_aux::{SendRequest, SendRequestData, Broadcast, GossipMode, RecAux};
 use crate::channels::Channel;
+use crate::multicast::{Multicast, Groups};
 use crate::packet::Packet;
-use crate::node::Node;
+use crate::node::{Node, NodeState};
+use crate::view::{Event, ViewSync, RECOVER};
+use crate::election::Election;
+use crate::membership::{Events, MembershipEvent};
+use crate::config::{BRD_AHEAD_LIMIT, URB_PENDING_TIMEOUT};
+
+/// Uniform broadcast received but not yet delivered, waiting for a majority of the members to have it
+struct PendingUrb {
+    holders: HashSet<SocketAddr>,
+    message: Vec<u8>,
+    payload: Vec<u8>,
+    sequence_number: u32,
+    multicast: Option<Multicast>,
+    checked: Instant,
+}
+
+/// How the epoch of a packet compares to the known incarnation of its sender
+#[derive(PartialEq)]
+enum Incarnation {
+    Current,
+    // The sender restarted since its last packet
+    Restarted,
+    // The packet was sent by a previous incarnation of the sender
+    Stale,
+}
+
+/// Where the listener sends what it receives and what it's told by the sender thread
+pub struct ListenerChannels {
+    // Messages and view events delivered to the user
+    pub messages_tx: Sender<Event>,
+    pub snd_acks_tx: Sender<Packet>,
+    pub brd_acks_tx: Sender<Packet>,
+    // First sequence number of the messages the sender waits ACKs for, by destination, origin and group id
+    pub reg_snd_rx: Receiver<((SocketAddr, SocketAddr, u32), u32)>,
+    pub reg_brd_rx: Receiver<((SocketAddr, SocketAddr, u32), u32)>,
+    pub hb_tx: Sender<Packet>,
+    pub brd_waiters_rx: Receiver<Sender<Vec<u8>>>,
+    pub rpc_tx: Sender<(SocketAddr, Vec<u8>)>,
+    pub membership_tx: Sender<(SocketAddr, Vec<u8>)>,
+}
 
 /// Listener thread that handles the reception of messages
 pub struct RecListener {
@@ -16,59 +57,124 @@ pub struct RecListener {
     group: Arc<Mutex<Vec<Node>>>,
     channel: Arc<Channel>,
     broadcast: Broadcast,
+    gossip: GossipMode,
+    history: History,
+    groups: Groups,
     reg_to_snd_tx: Sender<SendRequest>,
+    views: Arc<ViewSync>,
+    election: Arc<Election>,
+    events: Events,
 }
 
 impl RecAux for RecListener {}
 
 impl RecListener {
     /// Constructor
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         host: Node,
         group: Arc<Mutex<Vec<Node>>>,
         channel: Arc<Channel>,
         broadcast: Broadcast,
+        gossip: GossipMode,
+        history: History,
+        groups: Groups,
         reg_to_snd_tx: Sender<SendRequest>,
+        views: Arc<ViewSync>,
+        election: Arc<Election>,
+        events: Events,
     ) -> Self {
         Self {
             host,
             group,
             channel,
             broadcast,
+            gossip,
+            history,
+            groups,
             reg_to_snd_tx,
+            views,
+            election,
+            events,
         }
     }
 
     /// Thread to handle the reception of messages
-    pub fn run(&self,
-        messages_tx: Sender<Vec<u8>>,
-        snd_acks_tx: Sender<Packet>,
-        brd_acks_tx: Sender<Packet>,
-        reg_snd_rx: Receiver<((SocketAddr, SocketAddr), u32)>,
-        reg_brd_rx: Receiver<((SocketAddr, SocketAddr), u32)>,
-        hb_tx: Sender<Packet>,
-        brd_waiters_rx: Receiver<Sender<Vec<u8>>>,
-    ) {
-        let mut snd_pkts_per_origin: HashMap<SocketAddr, Vec<Packet>> = HashMap::new();
-        let mut brd_pkts_per_origin: HashMap<SocketAddr, Vec<Packet>> = HashMap::new();
-        let mut expected_snd_acks: HashMap<(SocketAddr, SocketAddr), u32> = HashMap::new();
-        let mut expected_brd_acks: HashMap<(SocketAddr, SocketAddr), u32> = HashMap::new();
+    pub fn run(&self, channels: ListenerChannels) {
+        let ListenerChannels {
+            messages_tx,
+            snd_acks_tx,
+            brd_acks_tx,
+            reg_snd_rx,
+            reg_brd_rx,
+            hb_tx,
+            brd_waiters_rx,
+            rpc_tx,
+            membership_tx,
+        } = channels;
+        // Packets and ACKs are kept per origin and group id, since each multicast has its own sequence numbers
+        let mut snd_pkts_per_origin: HashMap<(SocketAddr, u32), Vec<Packet>> = HashMap::new();
+        let mut brd_pkts_per_origin: HashMap<(SocketAddr, u32), Vec<Packet>> = HashMap::new();
+        let mut expected_snd_acks: HashMap<(SocketAddr, SocketAddr, u32), u32> = HashMap::new();
+        let mut expected_brd_acks: HashMap<(SocketAddr, SocketAddr, u32), u32> = HashMap::new();
         let mut broadcast_waiters: Vec<Option<Sender<Vec<u8>>>> = Vec::new();
+        // Uniform broadcasts waiting for a majority, by origin, group id and sequence number of the last packet
+        let mut pending_urbs: HashMap<(SocketAddr, u32, u32), PendingUrb> = HashMap::new();
+        // Where the broadcasts of each origin and group id were when this node recovered, for streams not received since
+        let mut brd_starts: HashMap<(SocketAddr, u32), u32> = HashMap::new();
+        // Broadcast packets that arrived ahead of the expected one, by origin and group id, and those whose turn came,
+        // which are handled before the next packet from the socket
+        let mut brd_ahead: HashMap<(SocketAddr, u32), BTreeMap<u32, Packet>> = HashMap::new();
+        let mut ready: VecDeque<Packet> = VecDeque::new();
         loop {
-            let packet = match self.channel.receive() {
-                Ok(packet) => {packet},
-                Err(e) => {
-                    debug!("Falhou ao receber um pacote do socket, erro: {e}");
-                    continue;
-                }
+            let packet = match ready.pop_front() {
+                Some(packet) => packet,
+                None => match self.channel.receive() {
+                    Ok(packet) => {packet},
+                    Err(e) => {
+                        debug!("Falhou ao receber um pacote do socket, erro: {e}");
+                        continue;
+                    }
+                },
             };
+            match self.check_incarnation(&packet) {
+                Incarnation::Stale => continue,
+                Incarnation::Restarted => {
+                    // Everything kept about the previous incarnation of the sender is forgotten
+                    let src = packet.header.src_addr;
+                    debug!("Agent {} reiniciou, esquecendo a sessão anterior", Self::get_agnt(&src));
+                    snd_pkts_per_origin.retain(|(origin, _), _| *origin != src);
+                    brd_pkts_per_origin.retain(|(origin, _), _| *origin != src);
+                    brd_starts.retain(|(origin, _), _| *origin != src);
+                    expected_snd_acks.retain(|(dst, _, _), _| *dst != src);
+                    expected_brd_acks.retain(|(dst, _, _), _| *dst != src);
+                    pending_urbs.retain(|(origin, _, _), _| *origin != src);
+                    brd_ahead.retain(|(origin, _), _| *origin != src);
+                    AntiEntropy::forget(&self.history, src);
+                    self.views.forget(src);
+                    let (request, _) = SendRequest::new(Vec::new(), SendRequestData::Reset { dst_addr: src });
+                    if let Err(e) = self.reg_to_snd_tx.send(request) {
+                        debug!("Erro ao reiniciar sessão: {e}");
+                    }
+                    // The first living member transfers the state of the group to the recovering node
+                    let source = Self::get_livings(&self.group).into_iter().find(|node| node.addr != src);
+                    if source.is_some_and(|node| node.addr == self.host.addr) {
+                        self.views.send_recovery(src);
+                    }
+                }
+                Incarnation::Current => {}
+            }
+            self.channel.hear(packet.header.src_addr);
+            if !pending_urbs.is_empty() {
+                self.retry_urbs(&mut pending_urbs, &mut broadcast_waiters, &brd_waiters_rx, &messages_tx);
+            }
             let (reg, expected_acks, acks_tx, pkts_per_origin) = if packet.header.is_brd() {
                 (&reg_brd_rx, &mut expected_brd_acks, &brd_acks_tx, &mut brd_pkts_per_origin)
             } else {
                 (&reg_snd_rx, &mut expected_snd_acks, &snd_acks_tx, &mut snd_pkts_per_origin)
             };
+            // Heartbeats go to the failure detector, which alone decides the state of their senders
             if packet.header.is_heartbeat() {
-                FailureDetection::handle_hb(&packet, &self.group);
                 match hb_tx.send(packet) {
                     Ok(_) => {}
                     Err(e) => {
@@ -81,7 +187,7 @@ impl RecListener {
                 while let Ok((key, start_seq)) = reg.try_recv() {
                     expected_acks.insert(key, start_seq);
                 }
-                match expected_acks.get_mut(&(packet.header.src_addr, packet.header.origin)) {
+                match expected_acks.get_mut(&(packet.header.src_addr, packet.header.origin, packet.header.group_id)) {
                     Some(seq_num) => {
                         if packet.header.seq_num < *seq_num { continue; }
                         *seq_num = packet.header.seq_num + 1;
@@ -98,53 +204,171 @@ impl RecListener {
                 }
             } else {
                 // Handle data
+                // Messages of named groups that weren't created here yet are ignored,
+                // they will be resent or repaired once the group exists
+                if Multicast::is_named(packet.header.group_id) && !self.has_group(packet.header.group_id) {
+                    continue;
+                }
                 let packets = pkts_per_origin
-                    .entry(packet.header.origin)
+                    .entry((packet.header.origin, packet.header.group_id))
                     .or_insert(Vec::new());
-                let expected = packets.last().map_or(0, |p| p.header.seq_num + 1);
+                let start = if packet.header.is_brd() {
+                    *brd_starts.get(&(packet.header.origin, packet.header.group_id)).unwrap_or(&0)
+                } else {
+                    0
+                };
+                let expected = packets.last().map_or(start, |p| p.header.seq_num + 1);
 
                 // Ignore the packet if the sequence number is higher than expected
+                // except for broadcasts: their friends are picked for each one, so a node skipped by a broadcast
+                // gets the next one straight from its origin before the gossip brings the skipped one
+                // They're acknowledged and kept until their turn
                 if packet.header.seq_num > expected {
-                    // debug!("expected seq_num {expected}, recebeu {packet}");
+                    if packet.header.is_brd() && packet.header.seq_num - expected <= BRD_AHEAD_LIMIT
+                        && !self.awaits_quorum(&packet) {
+                        self.channel.send(&packet.get_ack());
+                        brd_ahead
+                            .entry((packet.header.origin, packet.header.group_id))
+                            .or_default()
+                            .insert(packet.header.seq_num, packet);
+                    }
+                    continue;
+                }
+                // Without quorum, the atomic broadcasts of the whole group are neither acknowledged nor accepted,
+                // so their sender (or the anti-entropy) brings them again once the partition heals
+                if packet.header.seq_num == expected && self.awaits_quorum(&packet) {
+                    debug!("Sem quórum, recusando broadcast de {}", Self::get_agnt(&packet.header.origin));
                     continue;
                 }
                 // Send ack otherwise
                 self.channel.send(&packet.get_ack());
 
                 if packet.header.seq_num < expected {
+                    // Another copy of a pending uniform broadcast means its sender has the message too
+                    if packet.header.is_brd() && packet.header.is_last() {
+                        let key = (packet.header.origin, packet.header.group_id, packet.header.seq_num);
+                        if let Some(pending) = pending_urbs.get_mut(&key) {
+                            self.add_holder(pending, packet.header.src_addr);
+                            if self.has_majority(pending) {
+                                let pending = pending_urbs.remove(&key).expect("Broadcast pendente sumiu");
+                                Self::warn_brd_waiters(&mut broadcast_waiters, &brd_waiters_rx, &pending.payload);
+                                self.deliver(&messages_tx, packet.header.group_id, pending.payload,
+                                    Some((packet.header.origin, packet.header.seq_num + 1)));
+                            }
+                        }
+                    }
                     continue;
                 }
+                // The broadcast packet kept after this one is handled next, the older copies aren't needed anymore
+                if let Some(ahead) = brd_ahead.get_mut(&(packet.header.origin, packet.header.group_id))
+                    .filter(|_| packet.header.is_brd()) {
+                    *ahead = ahead.split_off(&(packet.header.seq_num + 1));
+                    if let Some(next) = ahead.remove(&(packet.header.seq_num + 1)) {
+                        ready.push_front(next);
+                    }
+                }
 
                 if packet.header.is_last() {
                     let (message, origin, sequence_number) =
                         Self::receive_last_packet(&self, packets, &packet);
+                    // Pull requests are answered with the broadcasts the requester is missing
+                    if packet.header.is_pull() {
+                        if let Some(requester) = self.get_node(&origin) {
+                            AntiEntropy::handle_pull(&self.history, &self.reg_to_snd_tx, &requester, &message);
+                        }
+                        packets.push(packet);
+                        continue;
+                    }
+                    // RPC messages are handled by the RPC thread, never delivered to the application
+                    if packet.header.is_rpc() {
+                        if let Err(e) = rpc_tx.send((origin, message)) {
+                            debug!("Erro ao enviar mensagem para a thread de RPC: {e}");
+                        }
+                        packets.push(packet);
+                        continue;
+                    }
+                    // Membership messages are handled by the membership thread
+                    // except for the state transfer after restarting, which tells where each broadcast stream is
+                    if packet.header.is_membership() {
+                        if message.first() == Some(&RECOVER) {
+                            // Streams already being received keep their numbering, the start only matters while they're empty
+                            brd_starts.extend(self.views.recover(&message).unwrap_or_default());
+                        } else if let Err(e) = membership_tx.send((origin, message)) {
+                            debug!("Erro ao enviar mensagem para a thread de membros: {e}");
+                        }
+                        packets.push(packet);
+                        continue;
+                    }
+                    // Atomic broadcast requests are broadcast by the leader they target, never delivered
+                    if packet.header.is_request() {
+                        self.handle_request(&message, &origin, packet.header.group_id);
+                        packets.push(packet);
+                        continue;
+                    }
+                    let ttl = packet.header.ttl;
+                    let group_id = packet.header.group_id;
+                    // Multicasts carry their members in front of the message
+                    let (multicast, payload) = if group_id == Multicast::GROUP_ID {
+                        (None, message.clone())
+                    } else {
+                        match Multicast::from_bytes(group_id, &message) {
+                            Some((multicast, payload)) => (Some(multicast), payload),
+                            None => {
+                                debug!("Multicast recebido com lista de membros inválida");
+                                packets.push(packet);
+                                continue;
+                            }
+                        }
+                    };
                     // Handling broadcasts
+                    let mode = Self::get_broadcast(&self.groups, &self.broadcast, group_id);
                     let dlv: bool = if packet.header.is_brd() {
-                        match self.broadcast {
+                        match mode {
                             // BEB: All broadcasts must be delivered
                             Broadcast::BEB => {true}
-                            // URB and AB: All broadcasts must be gossiped and then delivered
-                            // those who are waiting for the broadcast must be warned
+                            // URB: All broadcasts are relayed to every member and only delivered
+                            // once a majority of the members is known to have them,
+                            // so a delivered message can't be lost even if this node crashes right after
                             Broadcast::URB => {
-                                Self::warn_brd_waiters(&mut broadcast_waiters, &brd_waiters_rx, &message);
-                                Self::gossip(&self.reg_to_snd_tx, message.clone(), origin, sequence_number);
-                                true
+                                let mut pending = PendingUrb {
+                                    holders: HashSet::new(),
+                                    message: message.clone(),
+                                    payload: payload.clone(),
+                                    sequence_number,
+                                    multicast: multicast.clone(),
+                                    checked: Instant::now(),
+                                };
+                                for holder in [self.host.addr, origin, packet.header.src_addr] {
+                                    self.add_holder(&mut pending, holder);
+                                }
+                                Self::relay(&self.reg_to_snd_tx, message.clone(), origin, sequence_number, multicast);
+                                if self.has_majority(&pending) {
+                                    Self::warn_brd_waiters(&mut broadcast_waiters, &brd_waiters_rx, &payload);
+                                    true
+                                } else {
+                                    pending_urbs.insert((origin, group_id, packet.header.seq_num), pending);
+                                    false
+                                }
                             },
+                            // AB: All broadcasts come from the leader, they must be gossiped and then delivered
+                            // those who are waiting for the broadcast must be warned
                             Broadcast::AB => {
-                                Self::warn_brd_waiters(&mut broadcast_waiters, &brd_waiters_rx, &message);
-                                self.atm_gossip(message.clone(), &origin, sequence_number)
+                                Self::warn_brd_waiters(&mut broadcast_waiters, &brd_waiters_rx, &payload);
+                                self.push_gossip(message.clone(), origin, sequence_number, ttl, multicast);
+                                true
                             }
                         }
                     } else {
                         true
                     };
+                    // Pending uniform broadcasts are stored as well, so anti-entropy can spread them
+                    if packet.header.is_brd() && (dlv || mode == Broadcast::URB) {
+                        AntiEntropy::store(&self.history, origin, group_id,
+                            sequence_number, packet.header.seq_num + 1, message);
+                    }
                     if dlv {
-                        match messages_tx.send(message) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                debug!("Erro ao enviar mensagem: {e}");
-                            }
-                        }
+                        let broadcast = packet.header.is_brd().then_some((origin, packet.header.seq_num + 1));
+                        self.deliver(&messages_tx, group_id, payload, broadcast);
                     }
                 }
                 debug!(">>> pushing {packet} on the packets buffer");
@@ -153,23 +377,148 @@ impl RecListener {
         }
     }
 
-    /// Decides what to do with a broadcast message in the AB algorithm
-    /// Based on your priority and the priority of the origin of the message
-    /// The return boolean is used to tell the listener thread whether the message should be delivered or not (in case it's a broadcast request for the leader)
-    fn atm_gossip(&self, message: Vec<u8>, origin: &SocketAddr,
-            sequence_number: u32) -> bool {
-        let origin_priority = self.get_leader_priority(&origin);
-        let own_priority = self.get_leader_priority(&self.host.addr);
-        if origin_priority < own_priority {
-            // If the origin priority is lower than yours, it means the the origin considers you the leader and you must broadcast the message
-            debug!("Recebeu um Leader Request de {}", Self::get_agnt(origin));
-            Self::brd_req(&self.reg_to_snd_tx, message.clone());
-            false
+    /// Compares the epoch of the packet with the known incarnation of its sender, learning it on the first packet
+    /// A sender that restarted is alive again, even if it was marked as dead
+    fn check_incarnation(&self, packet: &Packet) -> Incarnation {
+        let mut group = self.group.lock().expect("Erro ao verificar encarnação: Mutex lock do grupo falhou");
+        let node = match group.iter_mut().find(|node| node.addr == packet.header.src_addr) {
+            Some(node) => node,
+            None => return Incarnation::Current,
+        };
+        let epoch = packet.header.epoch;
+        if node.epoch == epoch {
+            Incarnation::Current
+        } else if node.epoch == 0 {
+            node.epoch = epoch;
+            Incarnation::Current
+        } else if (epoch.wrapping_sub(node.epoch) as i32) > 0 {
+            node.epoch = epoch;
+            // A restart is a recovery even if the node wasn't found dead meanwhile
+            if node.is_member() {
+                self.events.emit(MembershipEvent::NodeRecovered(node.agent_number));
+            }
+            if node.is_dead() {
+                node.state = NodeState::Alive;
+            }
+            Incarnation::Restarted
         } else {
-            // If the origin priority is higher or equal to yours,
-            // it means the origin is the leader and you must simply gossip the message
-            Self::gossip(&self.reg_to_snd_tx, message.clone(), *origin, sequence_number);
-            true
+            Incarnation::Stale
+        }
+    }
+
+    /// Sends the message to the application, messages of named groups go to the group's own queue
+    /// Other broadcasts, given with their origin and next sequence number, go through the virtual synchrony,
+    /// which holds them while a view is being installed
+    fn deliver(&self, messages_tx: &Sender<Event>, group_id: u32, payload: Vec<u8>,
+        broadcast: Option<(SocketAddr, u32)>) {
+        let result = match (self.get_group_tx(group_id), broadcast) {
+            (Some(group_tx), _) => group_tx.send(payload).map_err(|e| e.to_string()),
+            (None, Some((origin, next_seq_num))) => {
+                self.views.deliver(origin, group_id, next_seq_num, payload);
+                Ok(())
+            }
+            (None, None) => messages_tx.send(Event::Message(payload)).map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            debug!("Erro ao enviar mensagem: {e}");
+        }
+    }
+
+    /// Whether the packet ends an atomic broadcast of the whole group, which is only accepted with quorum
+    fn awaits_quorum(&self, packet: &Packet) -> bool {
+        let header = &packet.header;
+        header.is_brd() && header.is_last() && header.group_id == Multicast::GROUP_ID
+            && !(header.is_pull() || header.is_rpc() || header.is_membership() || header.is_request())
+            && Self::get_broadcast(&self.groups, &self.broadcast, header.group_id) == Broadcast::AB
+            && !self.election.has_quorum()
+    }
+
+    /// Counts a node as having the pending broadcast, only members of the group (or multicast) are counted
+    fn add_holder(&self, pending: &mut PendingUrb, addr: SocketAddr) {
+        if let Some(node) = self.get_node(&addr) {
+            if pending.multicast.as_ref().is_none_or(|m| m.contains(node.agent_number)) {
+                pending.holders.insert(addr);
+            }
+        }
+    }
+
+    /// Checks again the uniform broadcasts pending for longer than URB_PENDING_TIMEOUT
+    /// They're never dropped, another member may have delivered them and their later copies only count as holders:
+    /// those that reached a majority (the members may have changed) are delivered, the others are relayed again
+    /// so the members still waiting for them learn this node has them too
+    fn retry_urbs(&self, pending_urbs: &mut HashMap<(SocketAddr, u32, u32), PendingUrb>,
+        broadcast_waiters: &mut Vec<Option<Sender<Vec<u8>>>>, brd_waiters_rx: &Receiver<Sender<Vec<u8>>>,
+        messages_tx: &Sender<Event>) {
+        let due: Vec<(SocketAddr, u32, u32)> = pending_urbs.iter()
+            .filter(|(_, pending)| pending.checked.elapsed() >= URB_PENDING_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in due {
+            let (origin, group_id, seq_num) = key;
+            let pending = pending_urbs.get_mut(&key).expect("Broadcast pendente sumiu");
+            if self.has_majority(pending) {
+                let pending = pending_urbs.remove(&key).expect("Broadcast pendente sumiu");
+                Self::warn_brd_waiters(broadcast_waiters, brd_waiters_rx, &pending.payload);
+                self.deliver(messages_tx, group_id, pending.payload, Some((origin, seq_num + 1)));
+            } else {
+                debug!("Broadcast de {} ainda sem maioria, retransmitindo", Self::get_agnt(&origin));
+                pending.checked = Instant::now();
+                Self::relay(&self.reg_to_snd_tx, pending.message.clone(), origin,
+                    pending.sequence_number, pending.multicast.clone());
+            }
+        }
+    }
+
+    /// Whether more than half of the members are known to have the pending broadcast
+    fn has_majority(&self, pending: &PendingUrb) -> bool {
+        let members = match &pending.multicast {
+            Some(multicast) => multicast.members.len(),
+            None => self.group
+                .lock()
+                .expect("Erro ao contar membros: Mutex lock do grupo falhou")
+                .iter()
+                .filter(|node| node.is_member())
+                .count(),
+        };
+        pending.holders.len() > members / 2
+    }
+
+    /// Broadcasts the message of an atomic broadcast request, as long as the host leads what the request targets:
+    /// the term of the whole group, or the multicast (whose leader is its first living member)
+    /// Requests to someone else are dropped, the requester tries again once it learns the leader
+    fn handle_request(&self, message: &[u8], origin: &SocketAddr, group_id: u32) {
+        let term = match message.get(0..4) {
+            Some(bytes) => u32::from_be_bytes(bytes.try_into().expect("Slice de tamanho 4")),
+            None => return,
+        };
+        let data = message[4..].to_vec();
+        let (multicast, leads) = if group_id == Multicast::GROUP_ID {
+            (None, self.election.is_leader(term))
+        } else {
+            match Multicast::from_bytes(group_id, &data) {
+                Some((multicast, _)) => {
+                    let leader = Self::get_leader(&self.group, &self.host, Some(&multicast));
+                    (Some(multicast), leader.addr == self.host.addr)
+                }
+                None => {
+                    debug!("Leader Request recebido com lista de membros inválida");
+                    return;
+                }
+            }
+        };
+        if !leads {
+            debug!("Ignorando Leader Request de {} para o mandato {term}", Self::get_agnt(origin));
+            return;
+        }
+        debug!("Recebeu um Leader Request de {}", Self::get_agnt(origin));
+        Self::brd_req(&self.reg_to_snd_tx, data, multicast);
+    }
+
+    /// Gossips a received broadcast if the push gossip is enabled and the message still has hops left
+    fn push_gossip(&self, message: Vec<u8>, origin: SocketAddr, sequence_number: u32,
+        ttl: u8, multicast: Option<Multicast>) {
+        if self.gossip.push() && ttl > 0 {
+            Self::gossip(&self.reg_to_snd_tx, message, origin, sequence_number, ttl - 1, multicast);
         }
     }
 
@@ -221,16 +570,167 @@ impl RecListener {
         brd_waiters.retain(|w| w.is_some());
     }
 
-    /// Calculates the priority of a node in the group (currently the lowest index in the group vector)
-    fn get_leader_priority(&self, node_address: &SocketAddr) -> usize {
-        let group = self.group
+    fn get_node(&self, addr: &SocketAddr) -> Option<Node> {
+        self.group
             .lock()
-            .expect("Erro ao obter prioridade do líder: Mutex lock do grupo falhou");
-        for (i, n) in group.iter().enumerate() {
-            if n.addr == *node_address {
-                return group.len()-i;
+            .expect("Erro ao buscar nodo: Mutex lock do grupo falhou")
+            .iter()
+            .find(|node| node.addr == *addr)
+            .cloned()
+    }
+
+    fn has_group(&self, group_id: u32) -> bool {
+        self.groups
+            .lock()
+            .expect("Erro ao buscar grupo: Mutex lock dos grupos falhou")
+            .contains_key(&group_id)
+    }
+
+    fn get_group_tx(&self, group_id: u32) -> Option<Sender<Vec<u8>>> {
+        self.groups
+            .lock()
+            .expect("Erro ao buscar grupo: Mutex lock dos grupos falhou")
+            .get(&group_id)
+            .map(|group| group.messages_tx.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use crate::flags::Flags;
+    use crate::header::Header;
+    use logger::{initializate_test_folder, free_ports};
+
+    /// Listener of the first node of the group, with the messages it delivers
+    fn group_listener(gossip: GossipMode, broadcast: Broadcast, group: Vec<Node>)
+        -> (RecListener, Receiver<SendRequest>, Sender<Event>, Receiver<Event>) {
+        initializate_test_folder!();
+        let host = group[0].clone();
+        let group = Arc::new(Mutex::new(group));
+        let channel = Channel::new(host.addr).expect("Erro ao criar o canal");
+        let history: History = Arc::new(Mutex::new(HashMap::new()));
+        let (reg_to_snd_tx, reg_to_snd_rx) = mpsc::channel();
+        let (messages_tx, messages_rx) = mpsc::channel();
+        let views = Arc::new(ViewSync::new(
+            host.clone(), group.clone(), history.clone(), messages_tx.clone(), reg_to_snd_tx.clone()
+        ));
+        let events = Events::default();
+        let election = Arc::new(Election::new(host.clone(), group.clone(), reg_to_snd_tx.clone(), events.clone()));
+        let listener = RecListener::new(
+            host, group, channel, broadcast, gossip, history, Arc::new(Mutex::new(HashMap::new())),
+            reg_to_snd_tx, views, election, events,
+        );
+        (listener, reg_to_snd_rx, messages_tx, messages_rx)
+    }
+
+    fn listener(gossip: GossipMode) -> (RecListener, Receiver<SendRequest>) {
+        let host = Node::new(SocketAddr::from(([127, 0, 0, 1], 0)), 0);
+        let (listener, reg_to_snd_rx, _, _) = group_listener(gossip, Broadcast::BEB, vec![host]);
+        (listener, reg_to_snd_rx)
+    }
+
+    /// Starts the listener thread, returning where the ACKs it sends to the given channel arrive
+    fn run(listener: RecListener, messages_tx: Sender<Event>, acked: Arc<Channel>) -> Receiver<Packet> {
+        let (_, reg_snd_rx) = mpsc::channel();
+        let (_, reg_brd_rx) = mpsc::channel();
+        let (_, brd_waiters_rx) = mpsc::channel();
+        let channels = ListenerChannels {
+            messages_tx,
+            snd_acks_tx: mpsc::channel().0,
+            brd_acks_tx: mpsc::channel().0,
+            reg_snd_rx,
+            reg_brd_rx,
+            hb_tx: mpsc::channel().0,
+            brd_waiters_rx,
+            rpc_tx: mpsc::channel().0,
+            membership_tx: mpsc::channel().0,
+        };
+        thread::spawn(move || listener.run(channels));
+        let (acks_tx, acks_rx) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(packet) = acked.receive() {
+                if packet.header.is_ack() && acks_tx.send(packet).is_err() { break; }
             }
+        });
+        acks_rx
+    }
+
+    fn gossiped_ttls(reg_to_snd_rx: &Receiver<SendRequest>) -> Vec<u8> {
+        reg_to_snd_rx
+            .try_iter()
+            .filter_map(|request| match request.options {
+                SendRequestData::Gossip { ttl, .. } => Some(ttl),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn push_gossip_spends_one_hop_and_stops_at_zero() {
+        let (listener, reg_to_snd_rx) = listener(GossipMode::PushPull);
+        let origin = SocketAddr::from(([127, 0, 0, 1], free_ports!(1)[0]));
+        listener.push_gossip(b"m".to_vec(), origin, 0, 2, None);
+        listener.push_gossip(b"m".to_vec(), origin, 0, 1, None);
+        listener.push_gossip(b"m".to_vec(), origin, 0, 0, None);
+        assert_eq!(gossiped_ttls(&reg_to_snd_rx), vec![1, 0]);
+    }
+
+    #[test]
+    fn pull_only_gossip_never_pushes() {
+        let (listener, reg_to_snd_rx) = listener(GossipMode::Pull);
+        let origin = SocketAddr::from(([127, 0, 0, 1], free_ports!(1)[0]));
+        listener.push_gossip(b"m".to_vec(), origin, 0, 4, None);
+        assert!(gossiped_ttls(&reg_to_snd_rx).is_empty());
+    }
+
+    #[test]
+    fn broadcasts_are_delivered_in_order_when_the_friends_change() {
+        let group: Vec<Node> = free_ports!(3)
+            .into_iter()
+            .enumerate()
+            .map(|(i, port)| {
+                let mut node = Node::new(SocketAddr::from(([127, 0, 0, 1], port)), i);
+                node.state = NodeState::Alive;
+                node
+            })
+            .collect();
+        let (host, origin, friend) = (group[0].addr, group[1].addr, group[2].addr);
+        let (listener, _reg_to_snd_rx, messages_tx, messages_rx) =
+            group_listener(GossipMode::PushPull, Broadcast::AB, group);
+        let origin_channel = Channel::new(origin).expect("Erro ao criar o canal");
+        let friend_channel = Channel::new(friend).expect("Erro ao criar o canal");
+        let acks_rx = run(listener, messages_tx, origin_channel.clone());
+        let broadcast = |src: SocketAddr, seq_num: u32, data: &[u8]| Packet::packets_from_message(
+            Header::builder(src, host).origin(origin).seq_num(seq_num).flags(Flags::BRD).group_id(Multicast::GROUP_ID),
+            data.to_vec(),
+        );
+        // The host wasn't a friend of the first broadcast, the second one comes straight from the origin
+        for packet in broadcast(origin, 1, b"b") {
+            origin_channel.send(&packet);
         }
-        0
+        let ack = acks_rx.recv_timeout(Duration::from_secs(1)).expect("Broadcast adiantado sem ACK");
+        assert_eq!(ack.header.seq_num, 1);
+        assert!(messages_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        // The first broadcast arrives later through the gossip of a friend
+        for packet in broadcast(friend, 0, b"a") {
+            friend_channel.send(&packet);
+        }
+        let delivered: Vec<Event> = (0..2)
+            .map(|_| messages_rx.recv_timeout(Duration::from_secs(1)).expect("Broadcast não entregue"))
+            .collect();
+        assert_eq!(delivered, vec![Event::Message(b"a".to_vec()), Event::Message(b"b".to_vec())]);
+    }
+
+    #[test]
+    fn ttl_travels_in_the_header() {
+        let ports = free_ports!(2);
+        let (src, dst) = (SocketAddr::from(([127, 0, 0, 1], ports[0])), SocketAddr::from(([127, 0, 0, 1], ports[1])));
+        let header = Header::builder(src, dst).ttl(3).build();
+        let bytes: [u8; Header::HEADER_SIZE] = header.to_bytes().try_into().expect("Tamanho do cabeçalho");
+        assert_eq!(Header::from_bytes(bytes).ttl, 3);
     }
 }
@@ -6,19 +6,26 @@ use std::sync::mpsc::{Receiver, Sender, RecvTimeoutError};
 
 use logger::debug;
 use crate::rec_aux::{SendRequest, Broadcast, SendRequestData, RecAux};
-use crate::config::{GOSSIP_RATE, TIMEOUT, TIMEOUT_LIMIT, W_SIZE};
+use crate::config::{GOSSIP_RATE, GOSSIP_TTL, TIMEOUT, TIMEOUT_LIMIT, W_SIZE};
 use crate::node::Node;
 use crate::channels::Channel;
+use crate::flags::Flags;
+use crate::multicast::{Multicast, Groups};
 use crate::packet::Packet;
+use crate::header::Header;
 
 /// Sender thread that handles the sending of messages
 pub struct RecSender {
     host: Node,
     group: Arc<Mutex<Vec<Node>>>,
     channel: Arc<Channel>,
-    // Keeps track of the sequence number, for sends and broadcasts
-    dst_seq_num_cnt: Mutex<HashMap<SocketAddr, (u32, u32)>>,
+    // Keeps track of the sequence number, for sends and broadcasts, of each destination and group id
+    dst_seq_num_cnt: Mutex<HashMap<(SocketAddr, u32), (u32, u32)>>,
+    // Sequence number of the broadcasts started by this node for each group id, shared by all destinations
+    // so that gossiped copies keep the same numbering no matter the path they take
+    brd_seq_num: Mutex<HashMap<u32, u32>>,
     broadcast: Broadcast,
+    groups: Groups,
     timeout: Duration,
     timeout_limit: u32,
     w_size: usize,
@@ -34,13 +41,16 @@ impl RecSender {
         group: Arc<Mutex<Vec<Node>>>,
         channel: Arc<Channel>,
         broadcast: Broadcast,
+        groups: Groups,
     ) -> Self {
         Self {
             host,
             group,
             channel,
             dst_seq_num_cnt: Mutex::new(HashMap::new()),
+            brd_seq_num: Mutex::new(HashMap::new()),
             broadcast,
+            groups,
             timeout: TIMEOUT,
             timeout_limit: TIMEOUT_LIMIT,
             w_size: W_SIZE,
@@ -53,8 +63,8 @@ impl RecSender {
         snd_acks_rx: Receiver<Packet>,
         brd_acks_rx: Receiver<Packet>,
         reg_to_send_rx: Receiver<SendRequest>,
-        reg_snd_to_listener_tx: Sender<((SocketAddr, SocketAddr), u32)>,
-        reg_brd_to_listener_tx: Sender<((SocketAddr, SocketAddr), u32)>,
+        reg_snd_to_listener_tx: Sender<((SocketAddr, SocketAddr, u32), u32)>,
+        reg_brd_to_listener_tx: Sender<((SocketAddr, SocketAddr, u32), u32)>,
     ) {
         while let Ok(request) = reg_to_send_rx.recv() {
             let messages_to_send = self.get_messages(&request);
@@ -79,7 +89,9 @@ impl RecSender {
                 };
                 if target.is_dead() {
                     debug!("Erro ao enviar mensagem: {} está morto", target);
-                    self.reset_seq_num(&first);
+                    if request.options.uses_dst_seq_num() {
+                        self.reset_seq_num(first);
+                    }
                     continue;
                 }
                 let (reg, acks_rx) = {
@@ -90,7 +102,7 @@ impl RecSender {
                     }
                 };
                 match reg.send((
-                    (first.header.dst_addr, first.header.origin),
+                    (first.header.dst_addr, first.header.origin, first.header.group_id),
                     first.header.seq_num,
                 )) {
                     Ok(_) => {},
@@ -118,57 +130,116 @@ impl RecSender {
         let mut messages = Vec::new();
         match &request.options {
             SendRequestData::Send { dst_addr } => {
-                let packets = self.get_pkts( &self.host.addr, dst_addr, &self.host.addr, request.data.clone(), false);
+                let packets = self.get_pkts(dst_addr, request.data.clone(), Flags::EMP, Multicast::GROUP_ID);
                 debug!("Starting send from {}", packets[0]);
                 messages.push(packets);
             },
-            SendRequestData::RequestLeader {} => {
-                let leader = Self::get_leader(&self.group, &self.host);
-                let packets = self.get_pkts(&self.host.addr, &leader.addr, &self.host.addr, request.data.clone(), true);
+            SendRequestData::Pull { dst_addr } => {
+                let packets = self.get_flagged_pkts(dst_addr, request.data.clone(), Flags::PLL);
+                debug!("Pulling from Agent {}", Self::get_agnt(dst_addr));
+                messages.push(packets);
+            },
+            SendRequestData::Membership { dst_addr } => {
+                let packets = self.get_flagged_pkts(dst_addr, request.data.clone(), Flags::MBR);
+                debug!("Sending membership message to Agent {}", Self::get_agnt(dst_addr));
+                messages.push(packets);
+            },
+            SendRequestData::Rpc { dst_addr } => {
+                let packets = self.get_flagged_pkts(dst_addr, request.data.clone(), Flags::RPC);
+                debug!("Sending RPC to Agent {}", Self::get_agnt(dst_addr));
+                messages.push(packets);
+            },
+            SendRequestData::Reset { dst_addr } => {
+                debug!("Reiniciando a sessão com Agent {}", Self::get_agnt(dst_addr));
+                self.dst_seq_num_cnt
+                    .lock()
+                    .expect("Erro ao obter lock de dst_seq_num_cnt em get_messages")
+                    .retain(|(addr, _), _| addr != dst_addr);
+            },
+            SendRequestData::RequestLeader { dst_addr, term, multicast } => {
+                let group_id = multicast.as_ref().map_or(Multicast::GROUP_ID, |m| m.id);
+                let mut data = term.to_be_bytes().to_vec();
+                data.extend(&request.data);
+                let packets = self.get_pkts(dst_addr, data, Flags::BRD | Flags::REQ, group_id);
                 debug!("Requesting leader with {}", packets[0]);
                 messages.push(packets);
             },
-            SendRequestData::Gossip { origin, seq_num } => {
-                debug!("Gossiping msg from Agent {}, with seq_num {}", Self::get_agnt(&origin), seq_num);
-                for node in self.get_friends() {
+            SendRequestData::Gossip { origin, seq_num, ttl, multicast } => {
+                debug!("Gossiping msg from Agent {}, with seq_num {} and ttl {}", Self::get_agnt(origin), seq_num, ttl);
+                let group_id = multicast.as_ref().map_or(Multicast::GROUP_ID, |m| m.id);
+                for node in Self::get_friends(&self.group, &self.host, self.gossip_rate, multicast.as_ref()) {
+                    let packets = Packet::packets_from_message(
+                        Header::builder(self.host.addr, node)
+                            .origin(*origin)
+                            .seq_num(*seq_num)
+                            .flags(Flags::BRD)
+                            .ttl(*ttl)
+                            .group_id(group_id),
+                        request.data.clone(),
+                    );
+                    messages.push(packets);
+                }
+            },
+            SendRequestData::Relay { origin, seq_num, multicast } => {
+                debug!("Relaying msg from Agent {}, with seq_num {}", Self::get_agnt(origin), seq_num);
+                let group_id = multicast.as_ref().map_or(Multicast::GROUP_ID, |m| m.id);
+                let targets: Vec<SocketAddr> = Self::get_livings(&self.group)
+                    .iter()
+                    .filter(|node| node.addr != self.host.addr)
+                    .filter(|node| multicast.as_ref().is_none_or(|m| m.contains(node.agent_number)))
+                    .map(|node| node.addr)
+                    .collect();
+                for node in targets {
                     let packets = Packet::packets_from_message(
-                        self.host.addr,
-                        node,
-                        *origin,
+                        Header::builder(self.host.addr, node)
+                            .origin(*origin)
+                            .seq_num(*seq_num)
+                            .flags(Flags::BRD)
+                            .group_id(group_id),
                         request.data.clone(),
-                        *seq_num,
-                        true,
                     );
                     messages.push(packets);
                 }
             },
-            SendRequestData::StartBroadcast {} => {
+            SendRequestData::Repair { dst_addr, origin, seq_num, group_id } => {
+                // Repairs are never gossiped again, the requester only needs its own copy
+                let packets = Packet::packets_from_message(
+                    Header::builder(self.host.addr, *dst_addr)
+                        .origin(*origin)
+                        .seq_num(*seq_num)
+                        .flags(Flags::BRD)
+                        .group_id(*group_id),
+                    request.data.clone(),
+                );
+                messages.push(packets);
+            },
+            SendRequestData::StartBroadcast { multicast } => {
                 debug!("Starting broadcast");
-                match self.broadcast {
+                let multicast = multicast.as_ref();
+                let is_member = |agent_number: usize| multicast.is_none_or(|m| m.contains(agent_number));
+                let group_id = multicast.map_or(Multicast::GROUP_ID, |m| m.id);
+                let targets = match Self::get_broadcast(&self.groups, &self.broadcast, group_id) {
                     Broadcast::BEB => {
-                        for node in self.group
+                        self.group
                             .lock()
                             .expect("Couldn't get grupo lock on get_messages")
                             .iter()
-                        {
-                            let packets = self.get_pkts(&self.host.addr, &node.addr, &self.host.addr, request.data.clone(), true);
-                            messages.push(packets);
-                        }
+                            .filter(|node| node.is_member() && is_member(node.agent_number))
+                            .map(|node| node.addr)
+                            .collect()
                     }
                     Broadcast::URB | Broadcast::AB => {
-                        let friends = self.get_friends();
-                        for node in self.group
-                            .lock()
-                            .expect("Couldn't get grupo lock on get_messages")
-                            .iter()
-                        {
-                            let packets = self.get_pkts(&self.host.addr, &node.addr, &self.host.addr, request.data.clone(), true);
-                            if friends.contains(&node.addr) {
-                                messages.push(packets);
-                            }
+                        // The friends start the gossip and the host delivers its own broadcast
+                        // The messages are sent in order, so the host only delivers, and a sender waiting for its
+                        // delivery only returns, once the friends have the broadcast and it survives the sender
+                        let mut targets = Self::get_friends(&self.group, &self.host, self.gossip_rate, multicast);
+                        if is_member(self.host.agent_number) {
+                            targets.push(self.host.addr);
                         }
+                        targets
                     }
-                }
+                };
+                messages.extend(self.get_brd_pkts(&targets, request.data.clone(), group_id));
             },
         }  
         messages
@@ -179,7 +250,7 @@ impl RecSender {
         let mut seq_lock = self.dst_seq_num_cnt
             .lock()
             .expect("Erro ao obter lock de dst_seq_num_cnt em reset_seq_num");
-        let start_seq_tup = seq_lock.entry(first.header.dst_addr).or_insert((0, 0));
+        let start_seq_tup = seq_lock.entry((first.header.dst_addr, first.header.group_id)).or_insert((0, 0));
         if first.header.is_brd() {
             debug!("->-> Agent {} brd seq_num was {} and was reset to {}", Self::get_agnt(&first.header.dst_addr), start_seq_tup.1, first.header.seq_num);
             start_seq_tup.1 = first.header.seq_num;
@@ -191,22 +262,63 @@ impl RecSender {
 
     /// Builds the packets based on the message and the destination.
     /// Will also update the sequence number counter for the destination
-    fn get_pkts(&self, src_addr: &SocketAddr, dst_addr: &SocketAddr,
-        origin: &SocketAddr, data: Vec<u8>, is_brd: bool) -> Vec<Packet>
+    fn get_pkts(&self, dst_addr: &SocketAddr, data: Vec<u8>, flags: Flags, group_id: u32) -> Vec<Packet>
     {
         let mut seq_lock = self.dst_seq_num_cnt
             .lock()
             .expect("Erro ao obter lock de dst_seq_num_cnt em get_pkts");
-        let start_seq_tup = seq_lock.entry(*dst_addr).or_insert((0, 0));
-        let seq_num = if is_brd {&mut start_seq_tup.1} else {&mut start_seq_tup.0};
+        let start_seq_tup = seq_lock.entry((*dst_addr, group_id)).or_insert((0, 0));
+        let seq_num = if flags.is_set(Flags::BRD) {&mut start_seq_tup.1} else {&mut start_seq_tup.0};
         let packets = Packet::packets_from_message(
-            *src_addr, *dst_addr, *origin, data, *seq_num, is_brd,
+            Header::builder(self.host.addr, *dst_addr).seq_num(*seq_num).flags(flags).group_id(group_id),
+            data,
         );
         debug!("<< Agent {} seq_num was {} and was set to {}", Self::get_agnt(dst_addr), *seq_num, *seq_num + packets.len() as u32);
         *seq_num += packets.len() as u32;
         packets
     }
 
+    /// Builds the packets of pull requests, RPC and membership messages, which share the sequence number counter of the sends
+    /// The flag tells the listener of the destination where the message should go
+    fn get_flagged_pkts(&self, dst_addr: &SocketAddr, data: Vec<u8>, flags: Flags) -> Vec<Packet> {
+        let mut seq_lock = self.dst_seq_num_cnt
+            .lock()
+            .expect("Erro ao obter lock de dst_seq_num_cnt em get_flagged_pkts");
+        let start_seq_tup = seq_lock.entry((*dst_addr, Multicast::GROUP_ID)).or_insert((0, 0));
+        let packets = Packet::packets_from_message(
+            Header::builder(self.host.addr, *dst_addr)
+                .seq_num(start_seq_tup.0)
+                .flags(flags)
+                .group_id(Multicast::GROUP_ID),
+            data,
+        );
+        start_seq_tup.0 += packets.len() as u32;
+        packets
+    }
+
+    /// Builds the packets of a broadcast started by this node, one message for each target
+    /// All of them use the same sequence numbers, taken from the broadcast counter
+    fn get_brd_pkts(&self, targets: &[SocketAddr], data: Vec<u8>, group_id: u32) -> Vec<Vec<Packet>> {
+        let mut seq_lock = self.brd_seq_num
+            .lock()
+            .expect("Erro ao obter lock de brd_seq_num em get_brd_pkts");
+        let seq_num = seq_lock.entry(group_id).or_insert(0);
+        let messages: Vec<Vec<Packet>> = targets.iter().map(|dst_addr| {
+            Packet::packets_from_message(
+                Header::builder(self.host.addr, *dst_addr)
+                    .seq_num(*seq_num)
+                    .flags(Flags::BRD)
+                    .ttl(GOSSIP_TTL)
+                    .group_id(group_id),
+                data.clone(),
+            )
+        }).collect();
+        if let Some(packets) = messages.first() {
+            *seq_num += packets.len() as u32;
+        }
+        messages
+    }
+
     /// Go-Back-N algorithm to send packets
     fn go_back_n(&self, packets: &Vec<Packet>, acks_rx: &Receiver<Packet>) -> bool {
         let mut base = 0;
@@ -253,40 +365,4 @@ impl RecSender {
         }
         true
     }
-
-    /// Currently, the friends are the next N nodes in the group vector, where N is the gossip rate
-    fn get_friends(&self) -> Vec<SocketAddr> {
-        let or_group = self.group
-            .lock()
-            .expect("Couldn't get grupo lock on get_friends");
-        let mut group = Vec::new();
-        let mut start = 0;
-        for node in or_group.iter() {
-            if !node.is_dead() {
-                group.push(node.addr);
-            }
-            if node.agent_number == self.host.agent_number {
-                start = group.len();
-            }
-        }
-
-        let mut friends = Vec::new();
-        for i in start..group.len() {
-            if friends.len() < self.gossip_rate {
-                friends.push(group[i]);
-            }
-            else {
-                break;
-            }
-        }
-        for i in 0..start {
-            if friends.len() < self.gossip_rate {
-                friends.push(group[i]);
-            }
-            else {
-                break;
-            }
-        }
-        friends
-    }
 }
@@ -5,18 +5,33 @@ permitindo o envio e recebimento de mensagens com garantias de entrega e ordem.
 */
 
 use std::thread;
+use std::net::SocketAddr;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
 use std::time::Duration;
+use std::io::{Error, ErrorKind};
 
 use logger::debug;
-use crate::config::{BROADCAST, MESSAGE_TIMEOUT, BROADCAST_TIMEOUT};
+use crate::config::{BROADCAST, GOSSIP, MESSAGE_TIMEOUT, BROADCAST_TIMEOUT, RPC_TIMEOUT, JOIN_TIMEOUT, ELECTION_TIMEOUT,
+    FAILURE_DETECTOR, PHI_SUSPECT_THRESHOLD, PHI_DEAD_THRESHOLD};
+use crate::anti_entropy::AntiEntropy;
 use crate::channels::Channel;
-use crate::failure_detection::FailureDetection;
-use crate::node::Node;
-use crate::rec_aux::{SendRequest, SendRequestData, Broadcast, RecAux};
-use crate::rec_listener::RecListener;
+use crate::discovery::Discovery;
+use crate::election::Election;
+use crate::failure_detection::{FailureDetection, Detector};
+use crate::membership::{Events, Membership, MembershipEvent};
+use crate::multicast::{Multicast, Group, Groups};
+use crate::node::{Node, NodeState};
+use crate::rec_aux::{SendRequest, SendRequestData, Broadcast, GossipMode, RecAux};
+use crate::rec_listener::{ListenerChannels, RecListener};
 use crate::rec_sender::RecSender;
+use crate::rpc::{Rpc, RpcCall};
+use crate::swim::Swim;
+use crate::view::{Event, View, ViewSync};
+
+/// Queues of received messages of each named group, by group id
+type GroupsRx = Mutex<HashMap<u32, Arc<Mutex<Receiver<Vec<u8>>>>>>;
 
 pub struct ReliableCommunication {
     pub host: Node,
@@ -25,7 +40,14 @@ pub struct ReliableCommunication {
     message_timeout: Duration,
     broadcast_timeout: Duration,
     broadcast_waiters_tx: Sender<Sender<Vec<u8>>>,
-    receive_rx: Mutex<Receiver<Vec<u8>>>,
+    receive_rx: Mutex<Receiver<Event>>,
+    groups: Groups,
+    groups_rx: GroupsRx,
+    rpc: Arc<Rpc>,
+    membership: Arc<Membership>,
+    views: Arc<ViewSync>,
+    election: Arc<Election>,
+    channel: Arc<Channel>,
     reg_to_snd_tx: Sender<SendRequest>,
 }
 
@@ -37,15 +59,47 @@ impl ReliableCommunication {
     pub fn new(
         host: Node,
         group: Vec<Node>
+    ) -> Result<Arc<Self>, std::io::Error> {
+        let detector = match FAILURE_DETECTOR {
+            "MISS" => Detector::HeartbeatMiss,
+            "PHI" => Detector::PhiAccrual { suspect: PHI_SUSPECT_THRESHOLD, dead: PHI_DEAD_THRESHOLD },
+            "SWIM" => Detector::Swim,
+            _ => panic!("Falha ao converter detector {FAILURE_DETECTOR} para Detector"),
+        };
+        Self::with_detector(host, group, detector)
+    }
+
+    /// Discovers the members of the group (see SEEDS and DISCOVERY_MULTICAST in the config file) and starts as the one
+    /// with the given address, which must be among them
+    pub fn discover(addr: SocketAddr) -> Result<Arc<Self>, Error> {
+        let group = Discovery::from_config()?.members(addr)?;
+        let host = match group.iter().find(|node| node.addr == addr) {
+            Some(host) => host.clone(),
+            None => return Err(Error::new(ErrorKind::NotFound, "Address isn't one of the discovered members")),
+        };
+        Self::new(host, group)
+    }
+
+    /// Same as new, but with the given failure detector instead of the configured one
+    pub fn with_detector(
+        host: Node,
+        group: Vec<Node>,
+        detector: Detector,
     ) -> Result<Arc<Self>, std::io::Error> {
         let channel = Channel::new(host.addr)?;
-        let broadcast: Broadcast = match BROADCAST {
-            "BEB" => Broadcast::BEB,
-            "URB" => Broadcast::URB,
-            "AB" => Broadcast::AB,
-            _ => panic!("Falha ao converter broadcast {BROADCAST} para Broadcast"),
+        let broadcast: Broadcast = match Broadcast::from_name(BROADCAST) {
+            Some(broadcast) => broadcast,
+            None => panic!("Falha ao converter broadcast {BROADCAST} para Broadcast"),
+        };
+        let gossip: GossipMode = match GOSSIP {
+            "PUSH" => GossipMode::Push,
+            "PULL" => GossipMode::Pull,
+            "PUSHPULL" => GossipMode::PushPull,
+            _ => panic!("Falha ao converter gossip {GOSSIP} para GossipMode"),
         };
         let group = Arc::new(Mutex::new(group));
+        let history = Arc::new(Mutex::new(HashMap::new()));
+        let groups: Groups = Arc::new(Mutex::new(HashMap::new()));
 
         let (reg_to_snd_tx, reg_to_send_rx) = mpsc::channel();
         let (messages_tx, receive_rx) = mpsc::channel();
@@ -55,25 +109,85 @@ impl ReliableCommunication {
         let (snd_acks_tx, snd_acks_rx) = mpsc::channel();
         let (brd_acks_tx, brd_acks_rx) = mpsc::channel();
         let (hb_tx, hb_rx) = mpsc::channel();
+        let (rpc_tx, rpc_rx) = mpsc::channel();
+        let (membership_tx, membership_rx) = mpsc::channel();
+
+        let views = Arc::new(ViewSync::new(
+            host.clone(), group.clone(), history.clone(), messages_tx.clone(), reg_to_snd_tx.clone()
+        ));
+
+        // Membership events are emitted by every thread that changes the state of the group
+        let events = Events::default();
+
+        let election = Arc::new(Election::new(host.clone(), group.clone(), reg_to_snd_tx.clone(), events.clone()));
 
-        let sender = RecSender::new(host.clone(), group.clone(), channel.clone(), broadcast.clone());
+        let sender = RecSender::new(host.clone(), group.clone(), channel.clone(), broadcast.clone(), groups.clone());
 
         let listener = RecListener::new(
             host.clone(),
             group.clone(),
             channel.clone(),
             broadcast.clone(),
-            reg_to_snd_tx.clone()
+            gossip.clone(),
+            history.clone(),
+            groups.clone(),
+            reg_to_snd_tx.clone(),
+            views.clone(),
+            election.clone(),
+            events.clone(),
         );
 
-        let mut failure_detection = FailureDetection::new(group.clone());
+        // spawn anti-entropy thread, best-effort broadcasts are never repaired
+        if gossip.pull() && broadcast != Broadcast::BEB {
+            let anti_entropy = AntiEntropy::new(host.clone(), group.clone(), history, reg_to_snd_tx.clone());
+            thread::spawn(move || {
+                anti_entropy.run();
+            });
+        }
 
-        // spawn failure detection thread
-        let (heart_beats, agent_num) = FailureDetection::get_hbs(&group, &host);
+        // spawn RPC thread
+        let rpc = Arc::new(Rpc::new(group.clone(), reg_to_snd_tx.clone()));
+        let rpc_clone = Arc::clone(&rpc);
         thread::spawn(move || {
-            failure_detection.run(hb_rx, channel, heart_beats, agent_num);
+            rpc_clone.run(rpc_rx);
         });
 
+        // spawn membership thread
+        let membership = Arc::new(Membership::new(
+            host.clone(), group.clone(), reg_to_snd_tx.clone(), views.clone(), election.clone(), events.clone()
+        ));
+        let membership_clone = Arc::clone(&membership);
+        thread::spawn(move || {
+            membership_clone.run(membership_rx);
+        });
+
+        // spawn leader election thread
+        let election_clone = Arc::clone(&election);
+        thread::spawn(move || {
+            election_clone.run();
+        });
+
+        // spawn virtual synchrony thread
+        let views_clone = Arc::clone(&views);
+        thread::spawn(move || {
+            views_clone.run();
+        });
+
+        // spawn failure detection thread
+        if detector == Detector::Swim {
+            let mut swim = Swim::new(host.clone(), group.clone(), channel.clone(), events.clone());
+            thread::spawn(move || {
+                swim.run(hb_rx);
+            });
+        } else {
+            let mut failure_detection = FailureDetection::new(group.clone(), detector, events);
+            let host = host.clone();
+            let channel = channel.clone();
+            thread::spawn(move || {
+                failure_detection.run(hb_rx, channel, host);
+            });
+        }
+
         // Spawn sender thread
         thread::spawn(move || {
             sender.run(
@@ -84,10 +198,17 @@ impl ReliableCommunication {
 
         // Spawn listener thread
         thread::spawn(move || {
-            listener.run(
-                messages_tx, snd_acks_tx, brd_acks_tx,
-                reg_snd_rx, reg_brd_rx, hb_tx, brd_waiters_rx,
-            );
+            listener.run(ListenerChannels {
+                messages_tx,
+                snd_acks_tx,
+                brd_acks_tx,
+                reg_snd_rx,
+                reg_brd_rx,
+                hb_tx,
+                brd_waiters_rx,
+                rpc_tx,
+                membership_tx,
+            });
         });
 
         let message_timeout = MESSAGE_TIMEOUT;
@@ -102,10 +223,101 @@ impl ReliableCommunication {
             broadcast_timeout,
             broadcast_waiters_tx,
             receive_rx,
+            groups,
+            groups_rx: Mutex::new(HashMap::new()),
+            rpc,
+            membership,
+            views,
+            election,
+            channel,
             reg_to_snd_tx,
         }))
     }
 
+    /// Creates an instance that isn't a member of the group yet, it must join through one of the known nodes
+    /// The known nodes are only addresses, none of them is considered a member until the join is accepted
+    /// The host keeps its agent number, which must be its index in the member list of the group
+    pub fn new_joiner(
+        host: Node,
+        mut known: Vec<Node>
+    ) -> Result<Arc<Self>, std::io::Error> {
+        for node in known.iter_mut() {
+            node.state = NodeState::Left;
+        }
+        Self::new(host, known)
+    }
+
+    /// Asks a member of the group (the seed) to let this node in, waiting until the member list includes it
+    pub fn join(&self, seed_addr: SocketAddr) -> Result<(), Error> {
+        if self.membership.is_member() {
+            return Ok(());
+        }
+        let known = self.group
+            .lock()
+            .expect("Erro ao entrar no grupo: Mutex lock do grupo falhou")
+            .iter()
+            .any(|node| node.addr == seed_addr);
+        if !known {
+            return Err(Error::new(ErrorKind::NotFound, "Seed address is not a known node"));
+        }
+        match self.membership.join(seed_addr) {
+            Some(joined_rx) => match joined_rx.recv_timeout(JOIN_TIMEOUT) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(Error::new(ErrorKind::TimedOut, "Join wasn't accepted in time")),
+            },
+            None => Err(Error::new(ErrorKind::NotConnected, "Join request couldn't reach the seed")),
+        }
+    }
+
+    /// Leaves the group, after which this node doesn't send to nor receive broadcasts from the other members
+    pub fn leave(&self) -> Result<(), Error> {
+        if !self.membership.is_member() {
+            return Err(Error::new(ErrorKind::NotConnected, "Not a member of the group"));
+        }
+        if self.membership.leave() {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::NotConnected, "Leave request couldn't reach the leader"))
+        }
+    }
+
+    /// Current members of the group, with their states
+    pub fn members(&self) -> Vec<Node> {
+        self.membership.members()
+    }
+
+    /// The node that currently leads the group, elected by the living members
+    pub fn leader(&self) -> Node {
+        self.membership.leader()
+    }
+
+    /// Enables or disables the majority quorum of atomic broadcasts (disabled by default, see AB_QUORUM)
+    /// With it, a partition without a majority of the members elects no leader and delivers no atomic broadcast
+    pub fn set_quorum(&self, enabled: bool) {
+        self.election.set_quorum(enabled);
+    }
+
+    /// Simulates a network partition, dropping every packet to and from the given nodes
+    /// Each call replaces the previous partition, an empty list heals it
+    pub fn partition(&self, ids: &[usize]) {
+        let addrs = {
+            let group = self.group.lock().expect("Erro ao particionar: Mutex lock do grupo falhou");
+            ids.iter().filter_map(|id| group.get(*id)).map(|node| node.addr).collect()
+        };
+        self.channel.block(addrs);
+    }
+
+    /// State of a node, or None if there's no node with this id
+    pub fn state_of(&self, id: usize) -> Option<NodeState> {
+        self.membership.state_of(id)
+    }
+
+    /// Returns a channel that receives the membership events (joins, leaves, suspicions, deaths, recoveries
+    /// and leader changes) from now on
+    pub fn subscribe(&self) -> Receiver<MembershipEvent> {
+        self.membership.subscribe()
+    }
+
     /// Send a message to a specific destination
     pub fn send(&self, id: usize, message: Vec<u8>) -> u32 {
         let node = {
@@ -130,9 +342,47 @@ impl ReliableCommunication {
     }
 
     /// Read one already received message or wait for a message to arrive
+    /// View changes are skipped, use receive_event to see them
     pub fn receive(&self, buffer: &mut Vec<u8>) -> bool {
-        match self
-            .receive_rx
+        loop {
+            match self.receive_event() {
+                Some(Event::Message(msg)) => {
+                    buffer.extend(msg);
+                    return true;
+                }
+                Some(_) => continue,
+                None => return false,
+            }
+        }
+    }
+
+    /// Read one already received event (a message or a view change) or wait for one to arrive
+    /// Every message broadcast in a view is received before the change to the next view
+    pub fn receive_event(&self) -> Option<Event> {
+        match self.receive_rx
+            .lock()
+            .expect("Erro ao receber evento: Mutex lock o receive_rx falhou")
+            .recv_timeout(self.message_timeout)
+        {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => {
+                debug!("Timed out waiting for event");
+                None
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                debug!("Erro ao receber evento: Canal de comunicação desconectado");
+                None
+            }
+        }
+    }
+
+    /// The view currently installed on this node
+    pub fn view(&self) -> View {
+        self.views.view()
+    }
+
+    fn receive_from(&self, receive_rx: &Mutex<Receiver<Vec<u8>>>, buffer: &mut Vec<u8>) -> bool {
+        match receive_rx
             .lock()
             .expect("Erro ao receber mensagem: Mutex lock o receive_rx falhou")
             .recv_timeout(self.message_timeout)
@@ -153,14 +403,130 @@ impl ReliableCommunication {
     }
 
     /// Broadcasts a message, reliability level may be configured in the config file
-    pub fn broadcast(&self, message: Vec<u8>) -> u32 {
+    /// Atomic broadcasts fail if the majority quorum is enabled and a majority of the group isn't reachable
+    pub fn broadcast(&self, message: Vec<u8>) -> Result<u32, Error> {
         match self.broadcast {
-            Broadcast::BEB => self.beb(message),
-            Broadcast::URB => self.urb(message),
-            Broadcast::AB => self.ab(message),
+            Broadcast::BEB => Ok(self.beb(message, None)),
+            Broadcast::URB => Ok(self.urb(message, None)),
+            Broadcast::AB => self.ab(message, None),
+        }
+    }
+
+    /// Whether every member delivers the broadcasts in the same order, which only the atomic broadcast (AB) ensures
+    pub fn totally_ordered(&self) -> bool {
+        self.broadcast == Broadcast::AB
+    }
+
+    /// Multicasts a message to a subset of the group, identified by the ids of its members
+    /// It has the same guarantees of the configured broadcast, restricted to the members
+    pub fn multicast(&self, ids: &[usize], message: Vec<u8>) -> u32 {
+        let multicast = Multicast::new(ids);
+        match self.broadcast {
+            Broadcast::BEB => self.beb(message, Some(multicast)),
+            Broadcast::URB => self.urb(message, Some(multicast)),
+            // Multicasts have no quorum, so they never fail
+            Broadcast::AB => self.ab(message, Some(multicast)).unwrap_or(0),
+        }
+    }
+
+    /// Creates a named group with the given members and broadcast algorithm ("BEB", "URB" or "AB")
+    /// The group shares this node's socket and threads, and has its own queue of received messages
+    /// Every member must create the group with the same name and members, messages of the group are ignored until then
+    pub fn create_group(&self, name: &str, ids: &[usize], broadcast: &str) -> Result<(), Error> {
+        let broadcast = match Broadcast::from_name(broadcast) {
+            Some(broadcast) => broadcast,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Invalid broadcast algorithm")),
+        };
+        let multicast = Multicast::named(name, ids);
+        let mut groups = self.groups.lock().expect("Erro ao criar grupo: Mutex lock dos grupos falhou");
+        if groups.contains_key(&multicast.id) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Group already exists"));
+        }
+        let (messages_tx, receive_rx) = mpsc::channel();
+        self.groups_rx
+            .lock()
+            .expect("Erro ao criar grupo: Mutex lock do groups_rx falhou")
+            .insert(multicast.id, Arc::new(Mutex::new(receive_rx)));
+        groups.insert(multicast.id, Group { multicast, broadcast, messages_tx });
+        Ok(())
+    }
+
+    /// Broadcasts a message to the members of a named group, using the group's broadcast algorithm
+    pub fn group_broadcast(&self, name: &str, message: Vec<u8>) -> u32 {
+        let group = self.groups
+            .lock()
+            .expect("Erro ao difundir para grupo: Mutex lock dos grupos falhou")
+            .get(&Multicast::named_id(name))
+            .map(|group| (group.multicast.clone(), group.broadcast.clone()));
+        match group {
+            Some((multicast, Broadcast::BEB)) => self.beb(message, Some(multicast)),
+            Some((multicast, Broadcast::URB)) => self.urb(message, Some(multicast)),
+            Some((multicast, Broadcast::AB)) => self.ab(message, Some(multicast)).unwrap_or(0),
+            None => {
+                debug!("Erro ao difundir para grupo: grupo {name} não existe");
+                0
+            }
+        }
+    }
+
+    /// Read one already received message of a named group or wait for a message to arrive
+    pub fn group_receive(&self, name: &str, buffer: &mut Vec<u8>) -> bool {
+        let receive_rx = self.groups_rx
+            .lock()
+            .expect("Erro ao receber mensagem: Mutex lock do groups_rx falhou")
+            .get(&Multicast::named_id(name))
+            .cloned();
+        match receive_rx {
+            Some(receive_rx) => self.receive_from(&receive_rx, buffer),
+            None => {
+                debug!("Erro ao receber mensagem: grupo {name} não existe");
+                false
+            }
         }
     }
 
+    /// Sends a request to a specific destination and waits for its response, using the default RPC timeout
+    pub fn call(&self, id: usize, request: Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.call_async(id, request, RPC_TIMEOUT)?.wait()
+    }
+
+    /// Sends a request to a specific destination and returns the call, which can be waited for or cancelled by its id
+    /// The timeout counts from now, and a call that times out is cancelled
+    pub fn call_async(&self, id: usize, request: Vec<u8>, timeout: Duration) -> Result<RpcCall, Error> {
+        let node = {
+            let g = self.group.lock().expect("Erro ao fazer RPC: Mutex lock do grupo falhou");
+            g.get(id).cloned()
+        };
+        match node {
+            Some(node) => self.rpc.call(&node, request, timeout),
+            None => Err(Error::new(ErrorKind::NotFound, "Destination id not found")),
+        }
+    }
+
+    /// Cancels a call that is still waiting for its response, returns false if it already ended
+    pub fn cancel(&self, call_id: u32) -> bool {
+        self.rpc.cancel(call_id)
+    }
+
+    /// Registers the function that answers the requests sent to this node
+    /// It receives the id of the caller and the request, and returns the response
+    /// The requests are handled one at a time, in the order they arrive, so a slow handler delays the next ones
+    pub fn register_handler<F>(&self, handler: F)
+    where F: Fn(usize, Vec<u8>) -> Vec<u8> + Send + Sync + 'static {
+        self.rpc.register_handler(Arc::new(handler));
+    }
+
+    /// How much the failure detector suspects the node, higher means more likely to have failed
+    /// The heartbeat-miss detector counts missed heartbeats, the phi-accrual detector gives the phi value
+    /// and SWIM gives 0 for alive, 1 for suspect and 2 for dead
+    pub fn suspicion(&self, id: usize) -> Option<f64> {
+        self.group
+            .lock()
+            .expect("Erro ao obter suspeita: Mutex lock do grupo falhou")
+            .get(id)
+            .map(|node| node.suspicion)
+    }
+
     /// Register to receive broadcasts confirmations
     fn reg_to_brd(&self) -> Receiver<Vec<u8>> {
         let (broadcast_tx, broadcast_rx) = mpsc::channel::<Vec<u8>>();
@@ -176,13 +542,13 @@ impl ReliableCommunication {
     /// Listen for any broadcasts until your message arrives
     /// While there are broadcasts arriving, it means the leader is still alive
     /// If the channel times out before your message arrives, it means the leader died
-    fn wait_for_brd(&self, broadcast_rx: &Receiver<Vec<u8>>, message: Vec<u8>) -> Result<u32, RecvTimeoutError> {
+    fn wait_for_brd(&self, broadcast_rx: &Receiver<Vec<u8>>, message: Vec<u8>,
+        multicast: Option<&Multicast>) -> Result<u32, RecvTimeoutError> {
         loop {
             match broadcast_rx.recv_timeout(self.broadcast_timeout) {
                 Ok(msg) => {
                     if msg == message {
-                        let len = Self::get_livings(&self.group).len() as u32;
-                        return Ok(len);
+                        return Ok(self.living_members(multicast));
                     }
                 }
                 Err(RecvTimeoutError::Timeout) => {
@@ -195,10 +561,33 @@ impl ReliableCommunication {
         }
     }
 
+    /// The term and leader that an atomic broadcast request targets, None while the group elects its leader
+    /// Multicasts have no terms, their leader is the first living member
+    fn ab_target(&self, multicast: Option<&Multicast>) -> Option<(u32, Node)> {
+        match multicast {
+            Some(multicast) => Some((0, Self::get_leader(&self.group, &self.host, Some(multicast)))),
+            None => self.election.leader(),
+        }
+    }
+
+    /// How many nodes that are not dead are destinations of the broadcast
+    fn living_members(&self, multicast: Option<&Multicast>) -> u32 {
+        Self::get_livings(&self.group)
+            .iter()
+            .filter(|node| multicast.is_none_or(|m| m.contains(node.agent_number)))
+            .count() as u32
+    }
+
+    /// Whether the host will deliver the broadcast itself, which is false for multicasts it isn't a member of
+    fn is_member(&self, multicast: Option<&Multicast>) -> bool {
+        multicast.is_none_or(|m| m.contains(self.host.agent_number))
+    }
+
     /// Best-Effort Broadcast: attempts to send a message to all nodes in the group and return how many were successful
     /// This algorithm does not garantee delivery to all nodes if the sender fails
-    fn beb(&self, message: Vec<u8>) -> u32 {
-        let result_rx = Self::brd_req(&self.reg_to_snd_tx, message);
+    fn beb(&self, message: Vec<u8>, multicast: Option<Multicast>) -> u32 {
+        let data = multicast.as_ref().map_or(message.clone(), |m| m.to_bytes(&message));
+        let result_rx = Self::brd_req(&self.reg_to_snd_tx, data, multicast);
 
         match result_rx.recv() {
             Ok(result) => result,
@@ -210,11 +599,22 @@ impl ReliableCommunication {
     }
 
     /// Uniform Reliable Broadcast: sends a message to all nodes in the group and returns how many were successful
-    /// This algorithm garantees that all nodes receive the message if the sender does not fail
-    fn urb(&self, message: Vec<u8>) -> u32 {
+    /// This algorithm garantees that if any node delivers the message, even one that fails right after, all correct nodes deliver it
+    /// It returns once the sender itself delivers, which only happens after a majority of the group has the message
+    /// A sender that isn't a member of the multicast can't see its own message, so it only waits for the first sends
+    fn urb(&self, message: Vec<u8>, multicast: Option<Multicast>) -> u32 {
+        let data = multicast.as_ref().map_or(message.clone(), |m| m.to_bytes(&message));
+        let is_member = self.is_member(multicast.as_ref());
+        let members = self.living_members(multicast.as_ref());
         let rx = self.reg_to_brd();
-        Self::brd_req(&self.reg_to_snd_tx, message.clone());
-        match self.wait_for_brd(&rx, message) {
+        let result_rx = Self::brd_req(&self.reg_to_snd_tx, data, multicast.clone());
+        if !is_member {
+            return match result_rx.recv() {
+                Ok(0) | Err(_) => 0,
+                Ok(_) => members,
+            };
+        }
+        match self.wait_for_brd(&rx, message, multicast.as_ref()) {
             Ok(result) => {
                 result
             },
@@ -226,21 +626,45 @@ impl ReliableCommunication {
 
     /// Atomic Broadcast: sends a message to all nodes in the group and returns how many were successful
     /// This algorithm garantees that all messages are delivered in the same order to all nodes
-    fn ab(&self, message: Vec<u8>) -> u32 {
+    /// A sender that isn't a member of the multicast can't see its own message, so it returns once the leader accepts it
+    /// With the majority quorum, broadcasts to the whole group fail while a majority of its members isn't reachable
+    fn ab(&self, message: Vec<u8>, multicast: Option<Multicast>) -> Result<u32, Error> {
+        let data = multicast.as_ref().map_or(message.clone(), |m| m.to_bytes(&message));
+        let is_member = self.is_member(multicast.as_ref());
         let broadcast_rx = self.reg_to_brd();
+        if multicast.is_none() && !self.membership.is_member() {
+            debug!("Fora do grupo, não há líder para fazer broadcast");
+            return Ok(0);
+        }
         // Constantly try to get a leader and ask it to broadcast
-        let mut prev_leader = self.host.agent_number;
+        let mut prev_target = None;
         loop {
-            let leader = Self::get_leader(&self.group, &self.host).agent_number;
+            if multicast.is_none() && !self.election.has_quorum() {
+                debug!("Sem a maioria do grupo, o broadcast não pode ser ordenado");
+                return Err(Error::new(ErrorKind::NotConnected, "A majority of the group isn't reachable"));
+            }
+            let (term, leader) = match self.ab_target(multicast.as_ref()) {
+                Some((term, leader)) => (term, leader.agent_number),
+                None => {
+                    debug!("Esperando a eleição de um líder para fazer broadcast");
+                    thread::sleep(ELECTION_TIMEOUT);
+                    continue;
+                }
+            };
             if leader == self.host.agent_number {
+                if !is_member {
+                    debug!("Nenhum membro do multicast está vivo");
+                    return Ok(0);
+                }
                 // Start the broadcast
                 debug!("Sou o líder, começando o broadcast");
-                Self::brd_req(&self.reg_to_snd_tx, message.clone());
-            } else if leader != prev_leader {
-                // Ask the leader to broadcast and wait for confirmation
+                Self::brd_req(&self.reg_to_snd_tx, data.clone(), multicast.clone());
+            } else if prev_target != Some((term, leader)) {
+                // Ask the leader of the term to broadcast and wait for confirmation
+                let dst_addr = self.group.lock().expect("Erro ao fazer AB: Mutex lock do grupo falhou")[leader].addr;
                 let (request, request_result_rx) = SendRequest::new (
-                    message.clone(),
-                    SendRequestData::RequestLeader {},
+                    data.clone(),
+                    SendRequestData::RequestLeader { dst_addr, term, multicast: multicast.clone() },
                 );
                 match self.reg_to_snd_tx.send(request) {
                     Ok(_) => {}
@@ -248,27 +672,31 @@ impl ReliableCommunication {
                         debug!("Erro ao enviar request de AB: {e}");
                     }
                 }
-                prev_leader = leader;
+                prev_target = Some((term, leader));
                 // Wait for the request result
                 match request_result_rx.recv() {
                     Ok(0) => {
                         debug!("Falha em enviar mensagem para o líder, tentando novamente...");
                         continue;
                     }
-                    Ok(_) => {}
+                    Ok(_) => {
+                        if !is_member {
+                            return Ok(self.living_members(multicast.as_ref()));
+                        }
+                    }
                     Err(e) => {
                         debug!("Erro ao fazer requisitar AB para o lider: {e}");
                         continue;
                     }
                 }
             } else {
-                // never make more than 1 request to the same leader
-                debug!("Tentando de novo o líder {leader} para fazer broadcast");
+                // never make more than 1 request to the same leader in the same term
+                debug!("Tentando de novo o líder {leader} do mandato {term} para fazer broadcast");
             }
-            match self.wait_for_brd(&broadcast_rx, message.clone()) {
+            match self.wait_for_brd(&broadcast_rx, message.clone(), multicast.as_ref()) {
                 Ok(result) => {
                     debug!("Recebeu a mensagem de broadcast de volta");
-                    return result;
+                    return Ok(result);
                 },
                 Err(RecvTimeoutError::Timeout) => {
                     debug!("Timed out ao esperar por broadcast do Agente {leader}");
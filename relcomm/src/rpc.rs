@@ -0,0 +1,263 @@
+/*
+A camada de RPC (Remote Procedure Call) oferece chamadas de requisição e resposta sobre o envio confiável 1:1.
+Cada requisição recebe um identificador, que volta na resposta para que ela seja associada à chamada que a originou.
+O destinatário registra um tratador (handler), executado por uma thread própria, que transforma a requisição na resposta.
+As requisições são tratadas uma de cada vez, na ordem em que chegam: um tratador lento atrasa as requisições seguintes,
+e um tratador que faz chamadas para o próprio nó fica esperando por si mesmo até o timeout.
+Chamadas podem ser canceladas, e uma requisição cancelada que ainda não foi tratada é descartada pelo destinatário.
+O cancelamento é lembrado por RPC_CANCEL_TIMEOUT, a requisição que chega depois disso é tratada normalmente.
+*/
+use std::thread;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender, RecvTimeoutError};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use logger::debug;
+use crate::config::RPC_CANCEL_TIMEOUT;
+use crate::node::Node;
+use crate::rec_aux::{SendRequest, SendRequestData, RecAux};
+
+/// Function that answers the requests, receives the id of the caller and the request
+pub type Handler = Arc<dyn Fn(usize, Vec<u8>) -> Vec<u8> + Send + Sync>;
+
+type RpcResult = Result<Vec<u8>, Error>;
+
+/// Kinds of RPC messages, the first byte of each message
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const CANCEL: u8 = 2;
+const NO_HANDLER: u8 = 3;
+
+pub struct Rpc {
+    group: Arc<Mutex<Vec<Node>>>,
+    reg_to_snd_tx: Sender<SendRequest>,
+    next_id: Mutex<u32>,
+    // Calls waiting for a response, by request id, with the address of the callee
+    pending: Mutex<HashMap<u32, (SocketAddr, Sender<RpcResult>)>>,
+    // Requests cancelled by their callers before being handled, by caller address and request id, with when
+    // the cancel arrived, since a cancel that arrives after its request was handled is never removed by it
+    cancelled: Mutex<HashMap<(SocketAddr, u32), Instant>>,
+    handler: Mutex<Option<Handler>>,
+}
+
+/// A call waiting for its response
+pub struct RpcCall {
+    id: u32,
+    deadline: Instant,
+    response_rx: Receiver<RpcResult>,
+    rpc: Arc<Rpc>,
+}
+
+impl RecAux for Rpc {}
+
+impl RpcCall {
+    /// Id of the request, used to cancel the call
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Waits for the response until the timeout of the call
+    /// A call that times out is cancelled, so the callee doesn't handle it if it hasn't yet
+    pub fn wait(self) -> RpcResult {
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        match self.response_rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                self.rpc.cancel(self.id);
+                Err(Error::new(ErrorKind::TimedOut, "RPC timed out"))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::new(ErrorKind::Interrupted, "RPC cancelled"))
+            }
+        }
+    }
+}
+
+impl Rpc {
+    /// Constructor
+    pub fn new(group: Arc<Mutex<Vec<Node>>>, reg_to_snd_tx: Sender<SendRequest>) -> Self {
+        Self {
+            group,
+            reg_to_snd_tx,
+            next_id: Mutex::new(0),
+            pending: Mutex::new(HashMap::new()),
+            cancelled: Mutex::new(HashMap::new()),
+            handler: Mutex::new(None),
+        }
+    }
+
+    /// Thread that receives the RPC messages from the listener
+    /// Requests are passed on to the handler thread, so that a slow handler never delays the responses of this node's own calls
+    pub fn run(self: Arc<Self>, rpc_rx: Receiver<(SocketAddr, Vec<u8>)>) {
+        let (requests_tx, requests_rx) = mpsc::channel();
+        let handler = Arc::clone(&self);
+        thread::spawn(move || {
+            handler.handle_requests(requests_rx);
+        });
+        while let Ok((src, message)) = rpc_rx.recv() {
+            let (kind, id, payload) = match Self::parse(&message) {
+                Some(parsed) => parsed,
+                None => {
+                    debug!("Mensagem de RPC inválida recebida de Agent {}", Self::get_agnt(&src));
+                    continue;
+                }
+            };
+            match kind {
+                REQUEST => {
+                    if let Err(e) = requests_tx.send((src, id, payload)) {
+                        debug!("Erro ao repassar requisição de RPC: {e}");
+                        break;
+                    }
+                }
+                CANCEL => {
+                    let mut cancelled = self.cancelled
+                        .lock()
+                        .expect("Erro ao cancelar RPC: Mutex lock dos cancelados falhou");
+                    cancelled.retain(|_, since| since.elapsed() < RPC_CANCEL_TIMEOUT);
+                    cancelled.insert((src, id), Instant::now());
+                }
+                RESPONSE => self.respond(src, id, Ok(payload)),
+                NO_HANDLER => self.respond(src, id,
+                    Err(Error::new(ErrorKind::Unsupported, "No RPC handler registered at the destination"))),
+                _ => {
+                    debug!("Tipo de mensagem de RPC desconhecido: {kind}");
+                }
+            }
+        }
+    }
+
+    /// Registers the function that answers the requests received by this node, replacing the previous one
+    /// The handler runs on a single thread, so it answers one request at a time
+    pub fn register_handler(&self, handler: Handler) {
+        *self.handler
+            .lock()
+            .expect("Erro ao registrar handler: Mutex lock do handler falhou") = Some(handler);
+    }
+
+    /// Sends a request and returns the call waiting for its response
+    /// Returns only after the request reached the destination, or with an error if it couldn't
+    pub fn call(self: &Arc<Self>, dst: &Node, request: Vec<u8>, timeout: Duration) -> Result<RpcCall, Error> {
+        let deadline = Instant::now() + timeout;
+        let id = {
+            let mut next_id = self.next_id.lock().expect("Erro ao criar RPC: Mutex lock do next_id falhou");
+            *next_id = next_id.wrapping_add(1);
+            *next_id
+        };
+        let (response_tx, response_rx) = mpsc::channel();
+        self.pending
+            .lock()
+            .expect("Erro ao criar RPC: Mutex lock dos pendentes falhou")
+            .insert(id, (dst.addr, response_tx));
+        let sent = match self.send(dst.addr, REQUEST, id, request).recv() {
+            Ok(result) => result,
+            Err(e) => {
+                debug!("Erro ao enviar requisição de RPC: {e}");
+                0
+            }
+        };
+        if sent == 0 {
+            self.forget(id);
+            return Err(Error::new(ErrorKind::NotConnected, "RPC request couldn't reach the destination"));
+        }
+        Ok(RpcCall {
+            id,
+            deadline,
+            response_rx,
+            rpc: Arc::clone(self),
+        })
+    }
+
+    /// Cancels a call, the caller stops waiting and the callee is told to not handle the request
+    /// Returns false if the call already ended
+    pub fn cancel(&self, id: u32) -> bool {
+        match self.forget(id) {
+            Some((dst_addr, _)) => {
+                debug!("Cancelando RPC {id} para Agent {}", Self::get_agnt(&dst_addr));
+                self.send(dst_addr, CANCEL, id, Vec::new());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Thread that answers the requests, one at a time
+    fn handle_requests(&self, requests_rx: Receiver<(SocketAddr, u32, Vec<u8>)>) {
+        while let Ok((src, id, request)) = requests_rx.recv() {
+            if self.cancelled
+                .lock()
+                .expect("Erro ao tratar RPC: Mutex lock dos cancelados falhou")
+                .remove(&(src, id))
+                .is_some() {
+                debug!("Ignorando RPC {id} cancelado por Agent {}", Self::get_agnt(&src));
+                continue;
+            }
+            let handler = self.handler
+                .lock()
+                .expect("Erro ao tratar RPC: Mutex lock do handler falhou")
+                .clone();
+            let caller = self.group
+                .lock()
+                .expect("Erro ao tratar RPC: Mutex lock do grupo falhou")
+                .iter()
+                .find(|node| node.addr == src)
+                .map(|node| node.agent_number);
+            match (handler, caller) {
+                (Some(handler), Some(caller)) => {
+                    let response = handler(caller, request);
+                    self.send(src, RESPONSE, id, response);
+                }
+                (None, _) => {
+                    self.send(src, NO_HANDLER, id, Vec::new());
+                }
+                (_, None) => {
+                    debug!("RPC recebido de um endereço fora do grupo: {src}");
+                }
+            }
+        }
+    }
+
+    /// Hands the result to the call waiting for it, if it's still waiting
+    fn respond(&self, src: SocketAddr, id: u32, result: RpcResult) {
+        let mut pending = self.pending.lock().expect("Erro ao responder RPC: Mutex lock dos pendentes falhou");
+        match pending.get(&id) {
+            Some((dst_addr, _)) if *dst_addr == src => {
+                if let Some((_, response_tx)) = pending.remove(&id) {
+                    // The caller may have given up on the call
+                    let _ = response_tx.send(result);
+                }
+            }
+            _ => {
+                debug!("Resposta de RPC {id} recebida sem chamada esperando");
+            }
+        }
+    }
+
+    fn forget(&self, id: u32) -> Option<(SocketAddr, Sender<RpcResult>)> {
+        self.pending
+            .lock()
+            .expect("Erro ao remover RPC: Mutex lock dos pendentes falhou")
+            .remove(&id)
+    }
+
+    /// Sends an RPC message without blocking, returns the channel with the result of the send
+    fn send(&self, dst_addr: SocketAddr, kind: u8, id: u32, payload: Vec<u8>) -> Receiver<u32> {
+        let mut data = vec![kind];
+        data.extend(id.to_be_bytes());
+        data.extend(payload);
+        let (request, result_rx) = SendRequest::new(data, SendRequestData::Rpc { dst_addr });
+        if let Err(e) = self.reg_to_snd_tx.send(request) {
+            debug!("Erro ao enviar mensagem de RPC: {e}");
+        }
+        result_rx
+    }
+
+    /// Splits an RPC message into its kind, request id and payload
+    fn parse(message: &[u8]) -> Option<(u8, u32, Vec<u8>)> {
+        let kind = *message.first()?;
+        let id = u32::from_be_bytes(message.get(1..5)?.try_into().ok()?);
+        Some((kind, id, message[5..].to_vec()))
+    }
+}
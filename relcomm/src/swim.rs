@@ -0,0 +1,506 @@
+/*
+Detecção de defeitos no estilo SWIM (Scalable Weakly-consistent Infection-style Membership).
+A cada período, cada processo testa (ping) um único membro, escolhido em ordem aleatória;
+se ele não responde a tempo, k outros membros são pedidos para testá-lo indiretamente (ping-req).
+Sem resposta até o fim do período, o membro passa a ser suspeito, e só é declarado morto se ninguém refutar a suspeita a tempo.
+As mudanças de estado viajam de carona (piggyback) nas próprias mensagens do protocolo,
+e cada processo refuta suspeitas sobre si aumentando seu número de encarnação.
+Cada estado vem com a época do processo (ver channels.rs), então um processo reiniciado, que volta com uma época maior
+e com a encarnação zerada, é aceito como vivo mesmo por quem o declarou morto.
+*/
+use std::thread;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::time::Instant;
+use rand::seq::SliceRandom;
+
+use logger::debug;
+use crate::config::{SWIM_PERIOD, SWIM_PING_TIMEOUT, SWIM_PING_REQ, SWIM_SUSPECT_TIMEOUT,
+    SWIM_RETRANSMIT_MULT, SWIM_PIGGYBACK};
+use crate::channels::Channel;
+use crate::node::{Node, NodeState};
+use crate::packet::Packet;
+use crate::membership::Events;
+
+/// Kinds of SWIM messages, the first byte of each message
+const PING: u8 = 0;
+const ACK: u8 = 1;
+const PING_REQ: u8 = 2;
+
+/// State of a member as seen by this node
+struct Member {
+    state: NodeState,
+    incarnation: u32,
+    // Epoch of the process the state is about, 0 while unknown
+    epoch: u32,
+    suspect_since: Instant,
+}
+
+/// Change in the state of a member, disseminated by piggybacking on the SWIM messages
+#[derive(Clone)]
+struct Update {
+    agent_number: usize,
+    state: NodeState,
+    incarnation: u32,
+    epoch: u32,
+}
+
+pub struct Swim {
+    host: Node,
+    group: Arc<Mutex<Vec<Node>>>,
+    channel: Arc<Channel>,
+    members: Vec<Member>,
+    // Updates still to be piggybacked, with how many more times each one will be sent
+    updates: Vec<(Update, u32)>,
+    retransmissions: u32,
+    probe_order: Vec<usize>,
+    next_seq_num: u32,
+    // Pings sent on behalf of others, by sequence number, with the requester and its own sequence number
+    proxied: HashMap<u32, (SocketAddr, u32, Instant)>,
+    events: Events,
+}
+
+impl Swim {
+    /// Constructor, every member starts alive
+    pub fn new(host: Node, group: Arc<Mutex<Vec<Node>>>, channel: Arc<Channel>, events: Events) -> Self {
+        let mut swim = Self {
+            host,
+            group,
+            channel,
+            members: Vec::new(),
+            updates: Vec::new(),
+            retransmissions: 0,
+            probe_order: Vec::new(),
+            next_seq_num: 0,
+            proxied: HashMap::new(),
+            events,
+        };
+        swim.sync();
+        let own = swim.host.agent_number;
+        swim.members[own].epoch = swim.channel.epoch();
+        swim
+    }
+
+    /// Follows the membership of the group: nodes that joined start alive and nodes that left are no longer probed
+    /// Nodes that restarted, whose packets came with a newer epoch, start alive again with their new epoch
+    fn sync(&mut self) {
+        let group = self.group.lock().expect("Failed to lock group on Swim::sync");
+        for node in group.iter().skip(self.members.len()) {
+            self.members.push(Member {
+                state: if node.is_member() { NodeState::Alive } else { NodeState::Left },
+                incarnation: 0,
+                epoch: node.epoch,
+                suspect_since: Instant::now(),
+            });
+        }
+        let mut restarted = Vec::new();
+        for (node, member) in group.iter().zip(self.members.iter_mut()) {
+            if !node.is_member() {
+                member.state = NodeState::Left;
+            } else if member.state == NodeState::Left {
+                member.state = NodeState::Alive;
+            }
+            if node.agent_number == self.host.agent_number || node.epoch == 0 {
+                continue;
+            }
+            if member.epoch == 0 {
+                member.epoch = node.epoch;
+            } else if Self::epoch_order(node.epoch, member.epoch) == Ordering::Greater {
+                debug!("Agent {} reiniciou, agora é vivo com a época {}", node.agent_number, node.epoch);
+                member.epoch = node.epoch;
+                member.incarnation = 0;
+                if member.state != NodeState::Left {
+                    member.state = NodeState::Alive;
+                }
+                restarted.push(Update {
+                    agent_number: node.agent_number,
+                    state: member.state.clone(),
+                    incarnation: 0,
+                    epoch: node.epoch,
+                });
+            }
+        }
+        let agent_num = group.iter().filter(|node| node.is_member()).count();
+        drop(group);
+        self.retransmissions = SWIM_RETRANSMIT_MULT * (agent_num as f64 + 1.).log2().ceil() as u32;
+        for update in restarted {
+            self.disseminate(update);
+        }
+    }
+
+    /// Orders two epochs, which come from the clock and wrap around like in the listener
+    /// An unknown epoch (0) is the same as any other
+    fn epoch_order(epoch: u32, other: u32) -> Ordering {
+        if epoch == 0 || other == 0 {
+            return Ordering::Equal;
+        }
+        (epoch.wrapping_sub(other) as i32).cmp(&0)
+    }
+
+    /// Runs the protocol periods forever
+    /// The SWIM messages arrive from the listener as heartbeats
+    pub fn run(&mut self, hb_rx: Receiver<Packet>) {
+        loop {
+            self.sync();
+            let start = Instant::now();
+            let period_end = start + SWIM_PERIOD;
+            let ping_req_at = start + SWIM_PING_TIMEOUT;
+            let probe = self.next_target().map(|target| (target, self.send(target, PING, 0)));
+            let mut acked = probe.is_none();
+            let mut indirect = false;
+            loop {
+                let now = Instant::now();
+                if now >= period_end { break; }
+                if let Some((target, seq_num)) = probe {
+                    if !acked && !indirect && now >= ping_req_at {
+                        self.send_ping_reqs(target, seq_num);
+                        indirect = true;
+                    }
+                }
+                let wake = if !acked && !indirect { ping_req_at } else { period_end };
+                match hb_rx.recv_timeout(wake.saturating_duration_since(now)) {
+                    Ok(packet) => {
+                        let ack = self.handle(&packet);
+                        if ack.is_some() && ack == probe.map(|(_, seq_num)| seq_num) {
+                            acked = true;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        thread::sleep(period_end.saturating_duration_since(now));
+                    }
+                }
+            }
+            if let Some((target, _)) = probe {
+                if !acked {
+                    self.suspect(target);
+                }
+            }
+            self.expire_suspicions();
+            self.proxied.retain(|_, (_, _, sent)| sent.elapsed() < SWIM_PERIOD);
+            self.publish();
+        }
+    }
+
+    /// Randomized round-robin: every member that isn't dead is probed once, in random order, before starting again
+    fn next_target(&mut self) -> Option<usize> {
+        if self.probe_order.is_empty() {
+            self.probe_order = self.others(None);
+            self.probe_order.shuffle(&mut rand::thread_rng());
+        }
+        while let Some(target) = self.probe_order.pop() {
+            let state = &self.members[target].state;
+            if *state != NodeState::Dead && *state != NodeState::Left {
+                return Some(target);
+            }
+        }
+        None
+    }
+
+    /// Members that aren't dead, other than the host and the excluded one
+    fn others(&self, exclude: Option<usize>) -> Vec<usize> {
+        (0..self.members.len())
+            .filter(|i| *i != self.host.agent_number && Some(*i) != exclude)
+            .filter(|i| self.members[*i].state != NodeState::Dead && self.members[*i].state != NodeState::Left)
+            .collect()
+    }
+
+    /// Asks k random members to probe the target on this node's behalf
+    fn send_ping_reqs(&mut self, target: usize, seq_num: u32) {
+        let others = self.others(Some(target));
+        let proxies: Vec<usize> = others
+            .choose_multiple(&mut rand::thread_rng(), SWIM_PING_REQ)
+            .cloned()
+            .collect();
+        for proxy in proxies {
+            self.send_message(proxy, PING_REQ, seq_num, target);
+        }
+    }
+
+    /// Handles a SWIM message, returns the sequence number if it's an ack to this node's own ping
+    fn handle(&mut self, packet: &Packet) -> Option<u32> {
+        let (kind, seq_num, target, updates) = match Self::parse(&packet.data) {
+            Some(message) => message,
+            None => {
+                debug!("Mensagem SWIM inválida");
+                return None;
+            }
+        };
+        for update in updates {
+            self.apply(update);
+        }
+        let src = packet.header.seq_num as usize;
+        if src >= self.members.len() {
+            return None;
+        }
+        match kind {
+            PING => {
+                self.send_message(src, ACK, seq_num, 0);
+                None
+            }
+            PING_REQ => {
+                if target < self.members.len() {
+                    let proxied_seq_num = self.send(target, PING, 0);
+                    self.proxied.insert(proxied_seq_num, (packet.header.src_addr, seq_num, Instant::now()));
+                }
+                None
+            }
+            ACK => {
+                // Acks to pings made on behalf of others are passed on to the requester
+                match self.proxied.remove(&seq_num) {
+                    Some((requester, requester_seq_num, _)) => {
+                        let data = self.message(ACK, requester_seq_num, src);
+                        self.channel.send(&Packet::probe(&self.host, requester, data));
+                        None
+                    }
+                    None => Some(seq_num),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Applies an update, following the precedence rules of SWIM:
+    /// a newer epoch overrides everything about an older one, since the process restarted,
+    /// and within the same epoch a higher incarnation overrides a lower one (bringing dead members back),
+    /// suspect overrides alive on the same incarnation, and dead overrides everything else
+    /// Suspicions about the host itself are refuted with a higher incarnation,
+    /// the ones about its previous epochs are already refuted by the epoch of its packets
+    fn apply(&mut self, update: Update) {
+        if update.agent_number == self.host.agent_number {
+            let own = match self.members.get_mut(update.agent_number) {
+                Some(own) => own,
+                None => return,
+            };
+            if update.state != NodeState::Alive && update.incarnation >= own.incarnation
+                && Self::epoch_order(update.epoch, own.epoch) != Ordering::Less {
+                own.incarnation = update.incarnation + 1;
+                debug!("Refutando suspeita sobre mim, encarnação {}", own.incarnation);
+                let refutation = Update {
+                    agent_number: update.agent_number,
+                    state: NodeState::Alive,
+                    incarnation: own.incarnation,
+                    epoch: own.epoch,
+                };
+                self.disseminate(refutation);
+            }
+            return;
+        }
+        // Nodes that aren't members are only brought in by the membership protocol
+        let member = match self.members.get_mut(update.agent_number) {
+            Some(member) if member.state != NodeState::Left => member,
+            _ => return,
+        };
+        let epoch = Self::epoch_order(update.epoch, member.epoch);
+        let same_epoch = epoch == Ordering::Equal;
+        let overrides = epoch == Ordering::Greater || (same_epoch && match update.state {
+            NodeState::Alive => update.incarnation > member.incarnation,
+            NodeState::Suspect => member.state != NodeState::Dead && (update.incarnation > member.incarnation
+                || (update.incarnation == member.incarnation && member.state == NodeState::Alive)),
+            NodeState::Dead => member.state != NodeState::Dead,
+            NodeState::Unborn | NodeState::Left => false,
+        });
+        if !overrides || matches!(update.state, NodeState::Unborn | NodeState::Left) { return; }
+        debug!("Agent {} agora é {} com encarnação {}", update.agent_number, update.state, update.incarnation);
+        if update.state == NodeState::Suspect {
+            member.suspect_since = Instant::now();
+        }
+        member.state = update.state.clone();
+        member.incarnation = update.incarnation;
+        if member.epoch == 0 || epoch == Ordering::Greater {
+            member.epoch = update.epoch;
+        }
+        self.disseminate(update);
+    }
+
+    /// Marks a member that didn't answer the probe as suspect
+    fn suspect(&mut self, target: usize) {
+        let member = &self.members[target];
+        if member.state != NodeState::Alive { return; }
+        debug!("Agent {target} não respondeu ao ping, agora é suspeito");
+        self.apply(Update {
+            agent_number: target,
+            state: NodeState::Suspect,
+            incarnation: member.incarnation,
+            epoch: member.epoch,
+        });
+    }
+
+    /// Suspects that weren't refuted in time are declared dead
+    fn expire_suspicions(&mut self) {
+        let expired: Vec<Update> = self.members.iter().enumerate()
+            .filter(|(_, m)| m.state == NodeState::Suspect && m.suspect_since.elapsed() >= SWIM_SUSPECT_TIMEOUT)
+            .map(|(i, m)| Update { agent_number: i, state: NodeState::Dead, incarnation: m.incarnation, epoch: m.epoch })
+            .collect();
+        for update in expired {
+            self.apply(update);
+        }
+    }
+
+    /// Writes the view of this node to the group used by the other threads
+    fn publish(&self) {
+        let mut group = self.group.lock().expect("Failed to lock group on Swim::publish");
+        for (node, member) in group.iter_mut().zip(self.members.iter()) {
+            if member.state == NodeState::Left || !node.is_member() { continue; }
+            node.suspicion = match member.state {
+                NodeState::Suspect => 1.,
+                NodeState::Dead => 2.,
+                _ => 0.,
+            };
+            self.events.set_state(node, member.state.clone());
+        }
+    }
+
+    fn disseminate(&mut self, update: Update) {
+        self.updates.retain(|(u, _)| u.agent_number != update.agent_number);
+        self.updates.push((update, self.retransmissions));
+    }
+
+    /// Sends a message with a new sequence number, which is returned
+    fn send(&mut self, dst: usize, kind: u8, target: usize) -> u32 {
+        self.next_seq_num = self.next_seq_num.wrapping_add(1);
+        let seq_num = self.next_seq_num;
+        self.send_message(dst, kind, seq_num, target);
+        seq_num
+    }
+
+    fn send_message(&mut self, dst: usize, kind: u8, seq_num: u32, target: usize) {
+        let dst_addr = match self.group.lock().expect("Failed to lock group on Swim::send").get(dst) {
+            Some(node) => node.addr,
+            None => return,
+        };
+        let data = self.message(kind, seq_num, target);
+        self.channel.send(&Packet::probe(&self.host, dst_addr, data));
+    }
+
+    /// Builds a message: kind, sequence number, target and the piggybacked updates
+    /// Each update is only sent a limited number of times, enough for it to reach the whole group
+    fn message(&mut self, kind: u8, seq_num: u32, target: usize) -> Vec<u8> {
+        let mut data = vec![kind];
+        data.extend(seq_num.to_be_bytes());
+        data.extend((target as u32).to_be_bytes());
+        let count = self.updates.len().min(SWIM_PIGGYBACK);
+        data.push(count as u8);
+        for (update, remaining) in self.updates.iter_mut().take(count) {
+            data.extend((update.agent_number as u32).to_be_bytes());
+            data.push(match update.state {
+                NodeState::Suspect => 1,
+                NodeState::Dead => 2,
+                _ => 0,
+            });
+            data.extend(update.incarnation.to_be_bytes());
+            data.extend(update.epoch.to_be_bytes());
+            *remaining -= 1;
+        }
+        self.updates.retain(|(_, remaining)| *remaining > 0);
+        // The least sent updates go first next time
+        self.updates.sort_by_key(|(_, remaining)| std::cmp::Reverse(*remaining));
+        data
+    }
+
+    fn parse(data: &[u8]) -> Option<(u8, u32, usize, Vec<Update>)> {
+        let kind = *data.first()?;
+        let seq_num = u32::from_be_bytes(data.get(1..5)?.try_into().ok()?);
+        let target = u32::from_be_bytes(data.get(5..9)?.try_into().ok()?) as usize;
+        let count = *data.get(9)? as usize;
+        let mut updates = Vec::new();
+        for i in 0..count {
+            let start = 10 + i * 13;
+            let entry = data.get(start..start + 13)?;
+            updates.push(Update {
+                agent_number: u32::from_be_bytes(entry[0..4].try_into().ok()?) as usize,
+                state: match entry[4] {
+                    1 => NodeState::Suspect,
+                    2 => NodeState::Dead,
+                    _ => NodeState::Alive,
+                },
+                incarnation: u32::from_be_bytes(entry[5..9].try_into().ok()?),
+                epoch: u32::from_be_bytes(entry[9..13].try_into().ok()?),
+            });
+        }
+        Some((kind, seq_num, target, updates))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use logger::{initializate_test_folder, free_ports};
+
+    /// SWIM of the first of three nodes, whose views of the others the tests change
+    fn swim() -> Swim {
+        initializate_test_folder!();
+        let channel = Channel::new(SocketAddr::from(([127, 0, 0, 1], 0))).expect("Erro ao criar o canal");
+        let group: Vec<Node> = free_ports!(3)
+            .into_iter()
+            .enumerate()
+            .map(|(i, port)| Node::new(SocketAddr::from(([127, 0, 0, 1], port)), i))
+            .collect();
+        Swim::new(group[0].clone(), Arc::new(Mutex::new(group)), channel, Events::default())
+    }
+
+    fn update(agent_number: usize, state: NodeState, incarnation: u32, epoch: u32) -> Update {
+        Update { agent_number, state, incarnation, epoch }
+    }
+
+    fn set_epoch(swim: &mut Swim, agent_number: usize, epoch: u32) {
+        swim.group.lock().expect("Erro no lock do grupo")[agent_number].epoch = epoch;
+        swim.sync();
+    }
+
+    #[test]
+    fn dead_member_comes_back_with_a_higher_incarnation() {
+        let mut swim = swim();
+        swim.apply(update(1, NodeState::Dead, 2, 0));
+        assert_eq!(swim.members[1].state, NodeState::Dead);
+        swim.apply(update(1, NodeState::Alive, 2, 0));
+        assert_eq!(swim.members[1].state, NodeState::Dead);
+        swim.apply(update(1, NodeState::Alive, 3, 0));
+        assert_eq!(swim.members[1].state, NodeState::Alive);
+        assert_eq!(swim.members[1].incarnation, 3);
+    }
+
+    #[test]
+    fn restarted_member_comes_back_with_a_newer_epoch() {
+        let mut swim = swim();
+        set_epoch(&mut swim, 1, 100);
+        swim.apply(update(1, NodeState::Dead, 5, 100));
+        assert_eq!(swim.members[1].state, NodeState::Dead);
+        // The restarted process starts over from incarnation 0
+        set_epoch(&mut swim, 1, 200);
+        assert_eq!(swim.members[1].state, NodeState::Alive);
+        assert_eq!(swim.members[1].incarnation, 0);
+        // Rumors about the previous epoch no longer apply
+        swim.apply(update(1, NodeState::Dead, 5, 100));
+        swim.apply(update(1, NodeState::Suspect, 7, 100));
+        assert_eq!(swim.members[1].state, NodeState::Alive);
+        swim.publish();
+        assert_eq!(swim.group.lock().expect("Erro no lock do grupo")[1].state, NodeState::Alive);
+    }
+
+    #[test]
+    fn alive_update_with_a_newer_epoch_overrides_dead() {
+        let mut swim = swim();
+        set_epoch(&mut swim, 2, 100);
+        swim.apply(update(2, NodeState::Dead, 4, 100));
+        swim.apply(update(2, NodeState::Alive, 0, 300));
+        assert_eq!(swim.members[2].state, NodeState::Alive);
+        assert_eq!(swim.members[2].epoch, 300);
+    }
+
+    #[test]
+    fn updates_keep_their_epoch_in_the_messages() {
+        let mut swim = swim();
+        swim.disseminate(update(2, NodeState::Suspect, 6, 123_456));
+        let data = swim.message(PING, 9, 1);
+        let (kind, seq_num, target, updates) = Swim::parse(&data).expect("Mensagem inválida");
+        assert_eq!((kind, seq_num, target), (PING, 9, 1));
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].agent_number, 2);
+        assert_eq!(updates[0].state, NodeState::Suspect);
+        assert_eq!((updates[0].incarnation, updates[0].epoch), (6, 123_456));
+    }
+}
@@ -0,0 +1,596 @@
+/*
+A sincronia virtual (Virtual Synchrony) organiza a execução do grupo em visões instaladas:
+cada visão tem um identificador e a lista dos membros vivos, e é entregue à aplicação como um evento,
+na mesma fila das mensagens.
+Quando os membros vivos mudam, o coordenador (o primeiro membro vivo) pede a todos que parem de entregar difusões (flush)
+e informem até onde já entregaram as difusões de cada origem. O coordenador junta as respostas em um corte,
+o máximo entregue por algum sobrevivente, e instala a nova visão: cada membro entrega o que falta até o corte,
+buscando com quem já entregou, e só então entrega a mudança de visão.
+A visão nunca é instalada com difusões faltando: se quem as entregou morre, elas são buscadas com outro sobrevivente
+que também as entregou, e se nenhum sobrou, a próxima visão proposta recalcula o corte com os sobreviventes.
+Assim todos os sobreviventes entregam o mesmo conjunto de difusões em cada visão antes de passar para a próxima.
+Um processo que reinicia recebe de um membro vivo a visão atual e até onde estão as difusões de cada origem (transferência de estado),
+para voltar a receber as difusões do grupo sem esperar pelas mensagens de antes de reiniciar.
+*/
+use std::thread;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use logger::debug;
+use crate::anti_entropy::{AntiEntropy, History};
+use crate::config::{HEARTBEAT_INTERVAL, VIEW_FLUSH_TIMEOUT};
+use crate::header::Header;
+use crate::node::Node;
+use crate::rec_aux::{SendRequest, SendRequestData, RecAux};
+
+/// Kinds of view messages, they travel as membership messages after the join, leave and member list kinds
+pub const FLUSH: u8 = 3;
+pub const FLUSH_OK: u8 = 4;
+pub const INSTALL: u8 = 5;
+pub const RECOVER: u8 = 6;
+
+/// An installed view: its id, which grows at every change, and the agent numbers of its members
+#[derive(Clone, Debug, PartialEq)]
+pub struct View {
+    pub id: u32,
+    pub members: Vec<usize>,
+}
+
+/// What the application receives from the group
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Message(Vec<u8>),
+    // A new view was installed, every message of the previous view was already received
+    ViewChange(View),
+}
+
+/// Next sequence number delivered from each origin and group id
+type Cut = HashMap<(SocketAddr, u32), u32>;
+
+/// Cut agreed for a view change, with the members that delivered up to it for each origin and group id
+type AgreedCut = HashMap<(SocketAddr, u32), (u32, Vec<SocketAddr>)>;
+
+/// View being installed on this node
+struct Flush {
+    view: View,
+    // Known once the coordinator installs the view
+    cut: Option<AgreedCut>,
+    started: Instant,
+    // Times the missing broadcasts were asked for, each time to the next of their holders
+    attempts: usize,
+}
+
+/// View proposed by this node as coordinator, waiting for the cut of every member
+struct Proposal {
+    view: View,
+    cuts: HashMap<SocketAddr, Cut>,
+    started: Instant,
+}
+
+struct State {
+    view: View,
+    delivered: Cut,
+    flush: Option<Flush>,
+    // Broadcasts received during a flush that may be beyond the cut, by origin and group id and next sequence number
+    held: Vec<((SocketAddr, u32), u32, Vec<u8>)>,
+    proposal: Option<Proposal>,
+}
+
+pub struct ViewSync {
+    host: Node,
+    group: Arc<Mutex<Vec<Node>>>,
+    history: History,
+    messages_tx: Sender<Event>,
+    reg_to_snd_tx: Sender<SendRequest>,
+    state: Mutex<State>,
+}
+
+impl RecAux for ViewSync {}
+
+impl ViewSync {
+    /// Constructor, the first view has every member of the group, or none if the host still has to join
+    pub fn new(
+        host: Node,
+        group: Arc<Mutex<Vec<Node>>>,
+        history: History,
+        messages_tx: Sender<Event>,
+        reg_to_snd_tx: Sender<SendRequest>,
+    ) -> Self {
+        let members: Vec<usize> = group
+            .lock()
+            .expect("Erro ao criar visão: Mutex lock do grupo falhou")
+            .iter()
+            .filter(|node| node.is_member())
+            .map(|node| node.agent_number)
+            .collect();
+        let members = if members.contains(&host.agent_number) { members } else { Vec::new() };
+        Self {
+            host,
+            group,
+            history,
+            messages_tx,
+            reg_to_snd_tx,
+            state: Mutex::new(State {
+                view: View { id: 0, members },
+                delivered: HashMap::new(),
+                flush: None,
+                held: Vec::new(),
+                proposal: None,
+            }),
+        }
+    }
+
+    /// Thread that watches the living members, proposing a new view when they change
+    pub fn run(&self) {
+        loop {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            self.check();
+        }
+    }
+
+    /// The view currently installed on this node
+    pub fn view(&self) -> View {
+        self.lock().view.clone()
+    }
+
+    /// Delivers a broadcast to the application, unless a view is being installed:
+    /// then only the broadcasts up to the agreed cut are delivered, the others wait for the new view
+    pub fn deliver(&self, origin: SocketAddr, group_id: u32, next_seq_num: u32, payload: Vec<u8>) {
+        let mut state = self.lock();
+        let stream = (origin, group_id);
+        let in_view = match &state.flush {
+            None => true,
+            Some(flush) => Self::in_cut(flush.cut.as_ref(), &stream, next_seq_num),
+        };
+        if in_view {
+            self.deliver_locked(&mut state, stream, next_seq_num, payload);
+            self.try_install(&mut state);
+        } else {
+            state.held.push((stream, next_seq_num, payload));
+        }
+    }
+
+    /// Sends the state of the group to a process that restarted: the installed view and the digest of the broadcasts
+    pub fn send_recovery(&self, dst_addr: SocketAddr) {
+        let mut message = Self::view_to_bytes(RECOVER, &self.view());
+        message.extend(AntiEntropy::get_digest(&self.history));
+        debug!("Transferindo o estado para Agent {}", Self::get_agnt(&dst_addr));
+        self.send(dst_addr, message);
+    }
+
+    /// Adopts the state received after restarting, returning the next sequence number of each origin and group id
+    /// The view received doesn't have this node yet, it's added back by the next view change
+    pub fn recover(&self, message: &[u8]) -> Option<HashMap<(SocketAddr, u32), u32>> {
+        if message.first() != Some(&RECOVER) { return None; }
+        let mut start = 1;
+        let view = Self::parse_view(message, &mut start)?;
+        let digest = AntiEntropy::parse_digest(&message[start..]);
+        let mut state = self.lock();
+        if view.id > state.view.id {
+            debug!("Recuperou a visão {} com os membros {:?}", view.id, view.members);
+            // A view change that started after the one received is still valid
+            if state.flush.as_ref().is_some_and(|flush| flush.view.id <= view.id) {
+                state.flush = None;
+            }
+            if state.proposal.as_ref().is_some_and(|proposal| proposal.view.id <= view.id) {
+                state.proposal = None;
+            }
+            state.view = view;
+        }
+        for (stream, next_seq_num) in digest.iter() {
+            let delivered = state.delivered.entry(*stream).or_insert(0);
+            *delivered = (*delivered).max(*next_seq_num);
+        }
+        Some(digest)
+    }
+
+    /// Forgets what was delivered from an origin that restarted, its new broadcasts are numbered from zero again
+    pub fn forget(&self, origin: SocketAddr) {
+        let mut state = self.lock();
+        state.delivered.retain(|(addr, _), _| *addr != origin);
+        state.held.retain(|((addr, _), _, _)| *addr != origin);
+        if let Some(Flush { cut: Some(cut), .. }) = state.flush.as_mut() {
+            cut.retain(|(addr, _), _| *addr != origin);
+        }
+        self.try_install(&mut state);
+    }
+
+    /// Handles the view messages passed on by the membership thread
+    pub fn handle(&self, src: SocketAddr, message: &[u8]) {
+        let mut start = 1;
+        match message.first() {
+            Some(&FLUSH) => match Self::parse_view(message, &mut start) {
+                Some(view) => self.handle_flush(src, view),
+                None => {
+                    debug!("Pedido de flush inválido");
+                }
+            },
+            Some(&FLUSH_OK) => match (Self::parse_u32(message, &mut start), Self::parse_cut(message, &mut start)) {
+                (Some(id), Some(cut)) => self.handle_flush_ok(src, id, cut),
+                _ => {
+                    debug!("Resposta de flush inválida");
+                }
+            },
+            Some(&INSTALL) => match (Self::parse_view(message, &mut start), Self::parse_agreed(message, &mut start)) {
+                (Some(view), Some(agreed)) => self.handle_install(view, agreed),
+                _ => {
+                    debug!("Instalação de visão inválida");
+                }
+            },
+            _ => {
+                debug!("Mensagem de visão inválida recebida de Agent {}", Self::get_agnt(&src));
+            }
+        }
+    }
+
+    /// Proposes a new view if this node is the coordinator and the living members differ from the installed view
+    /// Also keeps fetching the broadcasts missing to install a view
+    fn check(&self) {
+        let livings: Vec<(usize, SocketAddr)> = Self::get_livings(&self.group)
+            .iter()
+            .map(|node| (node.agent_number, node.addr))
+            .collect();
+        let members: Vec<usize> = livings.iter().map(|(agent_number, _)| *agent_number).collect();
+        let view = {
+            let mut state = self.lock();
+            self.retry(&mut state);
+            if members.first() != Some(&self.host.agent_number) {
+                state.proposal = None;
+                return;
+            }
+            let installing = |view: &View, started: Instant| view.members == members && started.elapsed() < VIEW_FLUSH_TIMEOUT;
+            if state.flush.is_none() && state.view.members == members { return; }
+            if state.flush.as_ref().is_some_and(|flush| installing(&flush.view, flush.started)) { return; }
+            if state.proposal.as_ref().is_some_and(|proposal| installing(&proposal.view, proposal.started)) { return; }
+            let id = [
+                Some(state.view.id),
+                state.flush.as_ref().map(|flush| flush.view.id),
+                state.proposal.as_ref().map(|proposal| proposal.view.id),
+            ].into_iter().flatten().max().unwrap_or(0) + 1;
+            let view = View { id, members: members.clone() };
+            state.proposal = Some(Proposal { view: view.clone(), cuts: HashMap::new(), started: Instant::now() });
+            view
+        };
+        debug!("Propondo a visão {} com os membros {:?}", view.id, view.members);
+        let message = Self::view_to_bytes(FLUSH, &view);
+        for (_, addr) in livings {
+            if addr == self.host.addr {
+                self.handle_flush(addr, view.clone());
+            } else {
+                self.send(addr, message.clone());
+            }
+        }
+    }
+
+    /// Stops delivering broadcasts beyond what was already delivered and tells the coordinator how far that is
+    fn handle_flush(&self, src: SocketAddr, view: View) {
+        let cut = {
+            let mut state = self.lock();
+            if view.id <= state.view.id || !view.members.contains(&self.host.agent_number) { return; }
+            if state.flush.as_ref().is_some_and(|flush| flush.view.id >= view.id) { return; }
+            state.flush = Some(Flush { view: view.clone(), cut: None, started: Instant::now(), attempts: 0 });
+            state.delivered.clone()
+        };
+        if src == self.host.addr {
+            self.handle_flush_ok(src, view.id, cut);
+        } else {
+            let mut message = vec![FLUSH_OK];
+            message.extend(view.id.to_be_bytes());
+            message.extend(Self::cut_to_bytes(&cut));
+            self.send(src, message);
+        }
+    }
+
+    /// Collects the cuts of the members, installing the view once every member answered
+    fn handle_flush_ok(&self, src: SocketAddr, id: u32, cut: Cut) {
+        let (view, agreed) = {
+            let mut state = self.lock();
+            let proposal = match state.proposal.as_mut() {
+                Some(proposal) if proposal.view.id == id => proposal,
+                _ => return,
+            };
+            proposal.cuts.insert(src, cut);
+            let addrs = self.get_addrs(&proposal.view.members);
+            if !addrs.iter().all(|addr| proposal.cuts.contains_key(addr)) { return; }
+            let proposal = state.proposal.take().expect("Proposta de visão sumiu");
+            // The cut is the furthest any member delivered of each origin, held by every member that got that far
+            let mut agreed: AgreedCut = HashMap::new();
+            for (holder, cut) in proposal.cuts {
+                for (stream, next_seq_num) in cut {
+                    let entry = agreed.entry(stream).or_insert((next_seq_num, Vec::new()));
+                    if next_seq_num > entry.0 {
+                        *entry = (next_seq_num, Vec::new());
+                    }
+                    if next_seq_num == entry.0 {
+                        entry.1.push(holder);
+                    }
+                }
+            }
+            (proposal.view, agreed)
+        };
+        debug!("Instalando a visão {}", view.id);
+        let mut message = Self::view_to_bytes(INSTALL, &view);
+        message.extend(Self::agreed_to_bytes(&agreed));
+        for addr in self.get_addrs(&view.members) {
+            if addr == self.host.addr {
+                self.handle_install(view.clone(), agreed.clone());
+            } else {
+                self.send(addr, message.clone());
+            }
+        }
+    }
+
+    /// Delivers what is missing up to the agreed cut, then the view change
+    fn handle_install(&self, view: View, agreed: AgreedCut) {
+        let mut state = self.lock();
+        // A node joining in this view took no part in the previous one, so it has nothing to catch up
+        let agreed = if state.view.members.contains(&self.host.agent_number) { agreed } else { HashMap::new() };
+        match state.flush.as_mut() {
+            Some(flush) if flush.view == view => {
+                flush.cut = Some(agreed.clone());
+                flush.started = Instant::now();
+            }
+            _ => return,
+        }
+        // Broadcasts held that are within the cut belong to the view being closed
+        for (stream, next_seq_num, payload) in std::mem::take(&mut state.held) {
+            if Self::in_cut(Some(&agreed), &stream, next_seq_num) {
+                self.deliver_locked(&mut state, stream, next_seq_num, payload);
+            } else {
+                state.held.push((stream, next_seq_num, payload));
+            }
+        }
+        if !self.try_install(&mut state) {
+            self.retry(&mut state);
+        }
+    }
+
+    /// Installs the view being flushed if every broadcast up to the cut was delivered
+    fn try_install(&self, state: &mut State) -> bool {
+        let complete = match &state.flush {
+            Some(Flush { cut: Some(cut), .. }) => cut.iter().all(|(stream, (end, _))| {
+                state.delivered.get(stream).unwrap_or(&0) >= end
+            }),
+            _ => false,
+        };
+        if complete {
+            self.install(state);
+        }
+        complete
+    }
+
+    fn install(&self, state: &mut State) {
+        let flush = match state.flush.take() {
+            Some(flush) => flush,
+            None => return,
+        };
+        debug!("Instalou a visão {} com os membros {:?}", flush.view.id, flush.view.members);
+        state.view = flush.view.clone();
+        self.emit(Event::ViewChange(flush.view));
+        for (stream, next_seq_num, payload) in std::mem::take(&mut state.held) {
+            self.deliver_locked(state, stream, next_seq_num, payload);
+        }
+    }
+
+    /// Asks a holder for each of the broadcasts still missing to reach the cut, the view waits for all of them
+    /// Holders that died are skipped, and each retry asks the next holder, in case one of them forgot the broadcasts
+    fn retry(&self, state: &mut State) {
+        let State { flush, delivered, .. } = state;
+        let (cut, attempts) = match flush {
+            Some(Flush { cut: Some(cut), attempts, .. }) => (cut, attempts),
+            _ => return,
+        };
+        let livings: HashSet<SocketAddr> = Self::get_livings(&self.group).iter().map(|node| node.addr).collect();
+        let mut holders = HashSet::new();
+        for (stream, (end, stream_holders)) in cut.iter() {
+            if delivered.get(stream).unwrap_or(&0) >= end { continue; }
+            let living: Vec<SocketAddr> = stream_holders.iter().filter(|holder| livings.contains(holder)).copied().collect();
+            if living.is_empty() {
+                debug!("Nenhum sobrevivente entregou as difusões faltantes de {}, esperando a próxima visão", stream.0);
+                continue;
+            }
+            holders.insert(living[*attempts % living.len()]);
+        }
+        *attempts += 1;
+        for holder in holders {
+            let (request, _) = SendRequest::new(
+                AntiEntropy::get_digest(&self.history),
+                SendRequestData::Pull { dst_addr: holder },
+            );
+            if let Err(e) = self.reg_to_snd_tx.send(request) {
+                debug!("Erro ao buscar difusões faltantes: {e}");
+            }
+        }
+    }
+
+    fn deliver_locked(&self, state: &mut State, stream: (SocketAddr, u32), next_seq_num: u32, payload: Vec<u8>) {
+        let delivered = state.delivered.entry(stream).or_insert(0);
+        *delivered = (*delivered).max(next_seq_num);
+        self.emit(Event::Message(payload));
+    }
+
+    fn emit(&self, event: Event) {
+        if let Err(e) = self.messages_tx.send(event) {
+            debug!("Erro ao entregar evento: {e}");
+        }
+    }
+
+    /// Whether the broadcast is up to the cut, nothing is while the cut is unknown
+    fn in_cut(cut: Option<&AgreedCut>, stream: &(SocketAddr, u32), next_seq_num: u32) -> bool {
+        cut.and_then(|cut| cut.get(stream)).is_some_and(|(end, _)| next_seq_num <= *end)
+    }
+
+    fn get_addrs(&self, members: &[usize]) -> Vec<SocketAddr> {
+        let group = self.group.lock().expect("Erro ao buscar membros da visão: Mutex lock do grupo falhou");
+        members.iter().filter_map(|agent_number| group.get(*agent_number)).map(|node| node.addr).collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Erro na sincronia virtual: Mutex lock do estado falhou")
+    }
+
+    fn view_to_bytes(kind: u8, view: &View) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend(view.id.to_be_bytes());
+        bytes.extend((view.members.len() as u32).to_be_bytes());
+        for member in view.members.iter() {
+            bytes.extend((*member as u32).to_be_bytes());
+        }
+        bytes
+    }
+
+    fn cut_to_bytes(cut: &Cut) -> Vec<u8> {
+        let mut bytes = (cut.len() as u32).to_be_bytes().to_vec();
+        for ((origin, group_id), next_seq_num) in cut.iter() {
+            bytes.extend(Header::addr_to_bytes(*origin));
+            bytes.extend(group_id.to_be_bytes());
+            bytes.extend(next_seq_num.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn agreed_to_bytes(agreed: &AgreedCut) -> Vec<u8> {
+        let mut bytes = (agreed.len() as u32).to_be_bytes().to_vec();
+        for ((origin, group_id), (next_seq_num, holders)) in agreed.iter() {
+            bytes.extend(Header::addr_to_bytes(*origin));
+            bytes.extend(group_id.to_be_bytes());
+            bytes.extend(next_seq_num.to_be_bytes());
+            bytes.extend((holders.len() as u32).to_be_bytes());
+            for holder in holders {
+                bytes.extend(Header::addr_to_bytes(*holder));
+            }
+        }
+        bytes
+    }
+
+    fn parse_u32(bytes: &[u8], start: &mut usize) -> Option<u32> {
+        let value = u32::from_be_bytes(bytes.get(*start..*start + 4)?.try_into().ok()?);
+        *start += 4;
+        Some(value)
+    }
+
+    fn parse_view(bytes: &[u8], start: &mut usize) -> Option<View> {
+        let id = Self::parse_u32(bytes, start)?;
+        let len = Self::parse_u32(bytes, start)?;
+        let mut members = Vec::new();
+        for _ in 0..len {
+            members.push(Self::parse_u32(bytes, start)? as usize);
+        }
+        Some(View { id, members })
+    }
+
+    fn parse_cut(bytes: &[u8], start: &mut usize) -> Option<Cut> {
+        let len = Self::parse_u32(bytes, start)?;
+        let mut cut = HashMap::new();
+        for _ in 0..len {
+            if bytes.len() < *start + 14 { return None; }
+            let origin = Header::addr_from_bytes(bytes, start);
+            let group_id = Self::parse_u32(bytes, start)?;
+            cut.insert((origin, group_id), Self::parse_u32(bytes, start)?);
+        }
+        Some(cut)
+    }
+
+    fn parse_agreed(bytes: &[u8], start: &mut usize) -> Option<AgreedCut> {
+        let len = Self::parse_u32(bytes, start)?;
+        let mut agreed = HashMap::new();
+        for _ in 0..len {
+            if bytes.len() < *start + 18 { return None; }
+            let origin = Header::addr_from_bytes(bytes, start);
+            let group_id = Self::parse_u32(bytes, start)?;
+            let next_seq_num = Self::parse_u32(bytes, start)?;
+            let mut holders = Vec::new();
+            for _ in 0..Self::parse_u32(bytes, start)? {
+                if bytes.len() < *start + 6 { return None; }
+                holders.push(Header::addr_from_bytes(bytes, start));
+            }
+            agreed.insert((origin, group_id), (next_seq_num, holders));
+        }
+        Some(agreed)
+    }
+
+    fn send(&self, dst_addr: SocketAddr, message: Vec<u8>) {
+        let (request, _) = SendRequest::new(message, SendRequestData::Membership { dst_addr });
+        if let Err(e) = self.reg_to_snd_tx.send(request) {
+            debug!("Erro ao enviar mensagem de visão: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver};
+    use crate::node::NodeState;
+    use logger::{initializate_test_folder, free_ports};
+
+    fn view_sync(agent_num: usize, host: usize) -> (ViewSync, Receiver<Event>, Receiver<SendRequest>) {
+        initializate_test_folder!();
+        let group: Vec<Node> = free_ports!(agent_num)
+            .into_iter()
+            .enumerate()
+            .map(|(i, port)| {
+                let mut node = Node::new(SocketAddr::from(([127, 0, 0, 1], port)), i);
+                node.state = NodeState::Alive;
+                node
+            })
+            .collect();
+        let (messages_tx, messages_rx) = mpsc::channel();
+        let (reg_to_snd_tx, reg_to_snd_rx) = mpsc::channel();
+        let host = group[host].clone();
+        let history = Arc::new(Mutex::new(HashMap::new()));
+        let views = ViewSync::new(host, Arc::new(Mutex::new(group)), history, messages_tx, reg_to_snd_tx);
+        (views, messages_rx, reg_to_snd_rx)
+    }
+
+    fn pulled(reg_to_snd_rx: &Receiver<SendRequest>) -> Vec<SocketAddr> {
+        reg_to_snd_rx
+            .try_iter()
+            .filter_map(|request| match request.options {
+                SendRequestData::Pull { dst_addr } => Some(dst_addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn survivor_missing_a_broadcast_waits_for_it_before_the_view_change() {
+        let (views, messages_rx, reg_to_snd_rx) = view_sync(3, 2);
+        let (origin, holder) = {
+            let mut group = views.group.lock().expect("Erro no lock do grupo");
+            group[0].state = NodeState::Dead;
+            (group[0].addr, group[1].addr)
+        };
+        let view = View { id: 1, members: vec![1, 2] };
+        views.handle_flush(holder, view.clone());
+        // Agent 0 broadcast twice and died, only Agent 1 delivered the broadcasts besides it
+        let agreed = HashMap::from([((origin, 0), (2, vec![origin, holder]))]);
+        views.handle_install(view.clone(), agreed);
+        assert_eq!(pulled(&reg_to_snd_rx), vec![holder]);
+        views.lock().flush.as_mut().expect("Sem flush").started -= VIEW_FLUSH_TIMEOUT * 2;
+        views.check();
+        assert_eq!(pulled(&reg_to_snd_rx), vec![holder]);
+        assert!(messages_rx.try_recv().is_err());
+        assert_eq!(views.view().id, 0);
+
+        views.deliver(origin, 0, 1, b"a".to_vec());
+        views.deliver(origin, 0, 2, b"b".to_vec());
+        assert_eq!(
+            messages_rx.try_iter().collect::<Vec<Event>>(),
+            vec![Event::Message(b"a".to_vec()), Event::Message(b"b".to_vec()), Event::ViewChange(view)]
+        );
+    }
+
+    #[test]
+    fn agreed_cut_keeps_every_holder_in_the_messages() {
+        let mut addrs = free_ports!(3).into_iter().map(|port| SocketAddr::from(([127, 0, 0, 1], port)));
+        let origin = addrs.next().expect("Porta reservada");
+        let holders: Vec<SocketAddr> = addrs.collect();
+        let agreed = HashMap::from([((origin, 3), (7, holders))]);
+        let bytes = ViewSync::agreed_to_bytes(&agreed);
+        assert_eq!(ViewSync::parse_agreed(&bytes, &mut 0), Some(agreed));
+    }
+}
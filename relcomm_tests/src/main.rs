@@ -1,16 +1,22 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::{Arc, mpsc::{RecvError, Sender, self}};
 use std::{thread, vec};
+use std::time::{Duration, Instant};
 use std::{fs::{File, OpenOptions}, io::{Write, BufRead, BufReader}};
 
 use logger::{debug_file, debug, initializate_folders};
 use relcomm::reliable_communication::ReliableCommunication;
-use relcomm::node::Node;
+use relcomm::node::{Node, NodeState};
+use relcomm::discovery::Discovery;
+use relcomm::config::MESSAGE_TIMEOUT;
+use relcomm::view::Event;
 use tests::{Action, ReceiveAction, SendAction};
 
 // Importa as configurações de endereços dos processos
 mod tests;
 
+/// How long a restarted agent waits before running again, so the previous incarnation has already died
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+
 struct Agent {
     id: usize,
     communication: Arc<ReliableCommunication>,
@@ -19,14 +25,20 @@ struct Agent {
 impl Agent {
     fn new(
         id: usize,
-        nodes: Vec<Node>
+        nodes: Vec<Node>,
+        joiner: bool
     ) -> Result<Self, std::io::Error> {
+        let host = nodes[id].clone();
+        let communication = if joiner {
+            ReliableCommunication::new_joiner(host, nodes)?
+        } else {
+            ReliableCommunication::new(host, nodes)?
+        };
+        // Every agent answers the RPCs it receives with the request itself
+        communication.register_handler(|_, request| request);
         Ok(Agent {
             id,
-            communication: ReliableCommunication::new(
-                nodes[id].clone(),
-                nodes
-            )?,
+            communication,
         })
     }
 
@@ -37,6 +49,16 @@ impl Agent {
         let mut send_actions = Vec::new();
         let mut receive_actions = Vec::new();
         for action in actions {
+            // Named groups must exist before any of their messages arrive
+            match &action {
+                Action::Send(SendAction::GroupBroadcast { group, members, broadcast, .. }) |
+                Action::Receive(ReceiveAction::GroupReceive { group, members, broadcast, .. }) => {
+                    if let Err(e) = self.communication.create_group(group, members, broadcast) {
+                        debug!("Erro ao criar grupo {group}: {e}");
+                    }
+                },
+                _ => {}
+            }
             match action {
                 Action::Send(action) => {
                     send_actions.push(action);
@@ -47,6 +69,20 @@ impl Agent {
                 // Die instruction means the agent shouldn't even start running
                 Action::Die() => {
                     return (0, 0);
+                },
+                // Already handled when the agent was created
+                Action::Restart(_) => {},
+                Action::Partition(ids) => {
+                    self.communication.set_quorum(true);
+                    self.communication.partition(&ids);
+                    self.wait_partition(&ids);
+                },
+                Action::Join(seed) => {
+                    let seed_addr = self.communication.group.lock().expect("Falha ao ler do grupo")[seed].addr;
+                    if let Err(e) = self.communication.join(seed_addr) {
+                        debug!("Erro ao entrar no grupo: {e}");
+                        return (0, 0);
+                    }
                 }
             }
         }
@@ -119,6 +155,18 @@ impl Agent {
         return (s_acertos, r_acertos);
     }
 
+    /// Waits for the agents on the other side of the partition to be considered dead, so the sides are settled
+    fn wait_partition(&self, ids: &[usize]) {
+        let deadline = Instant::now() + MESSAGE_TIMEOUT;
+        while Instant::now() < deadline {
+            if ids.iter().all(|id| self.communication.state_of(*id) == Some(NodeState::Dead)) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        debug!("Partição não foi percebida a tempo");
+    }
+
     /// Agent thread that always receives messages and checks if they are the expected ones from the selected test
     fn receiver(&self, actions: Vec<ReceiveAction>, death_tx: Sender<(&str, u32)>,
                 survival_tx: Sender<u32>, test_id: usize) -> u32 {
@@ -126,10 +174,34 @@ impl Agent {
         let mut i = 0;
         let mut expected_messages = Vec::new();
         let mut msg_limit = u32::MAX;
+        let mut group = None;
+        let mut expected_views = Vec::new();
+        let mut expected_events = Vec::new();
         for action in actions {
             match action {
                 ReceiveAction::Receive { message } => { expected_messages.push(message); },
+                ReceiveAction::GroupReceive { group: name, message, .. } => {
+                    expected_messages.push(message);
+                    group = Some(name);
+                },
                 ReceiveAction::DieAfterReceive { after_n_messages } => { msg_limit = after_n_messages; },
+                ReceiveAction::ViewChange { members } => { expected_views.push(members); },
+                ReceiveAction::Membership { event } => { expected_events.push(event); },
+            }
+        }
+        if !expected_events.is_empty() {
+            let events_rx = self.communication.subscribe();
+            while !expected_events.is_empty() {
+                match events_rx.recv_timeout(MESSAGE_TIMEOUT) {
+                    Ok(event) => {
+                        if let Some(idx) = expected_events.iter().position(|expected| *expected == event) {
+                            expected_events.remove(idx);
+                            acertos += 1;
+                            let _ = survival_tx.send(acertos);
+                        }
+                    },
+                    Err(_) => break,
+                }
             }
         }
         loop {
@@ -141,9 +213,32 @@ impl Agent {
             }
             // Receive the next message
             let mut message = Vec::new();
-            if !self.communication.receive(&mut message) {
+            let received = match &group {
+                Some(name) => self.communication.group_receive(name, &mut message),
+                None if expected_views.is_empty() => self.communication.receive(&mut message),
+                // Views that weren't expected, like the ones installed while the agents are starting, are ignored
+                None => match self.communication.receive_event() {
+                    Some(Event::Message(msg)) => {
+                        message = msg;
+                        true
+                    },
+                    Some(Event::ViewChange(view)) => {
+                        if let Some(idx) = expected_views.iter().position(|members| *members == view.members) {
+                            expected_views.remove(idx);
+                            acertos += 1;
+                            let _ = survival_tx.send(acertos);
+                        }
+                        continue;
+                    },
+                    None => false,
+                },
+            };
+            if !received {
                 break;
             }
+            // Every delivery is recorded, one per line, for the agreement check of the main process
+            let path = format!("tests/test_{test_id}/entregas_{}.txt", self.id);
+            debug_file!(path, format!("{:?}\n", String::from_utf8_lossy(&message)).as_bytes());
             // Check if the message is the expected one
             match String::from_utf8(message.clone()) {
                 Ok(msg) => {
@@ -175,7 +270,26 @@ impl Agent {
                     let _ = survival_tx.send(acertos);
                 },
                 SendAction::Broadcast { message } => {
-                    acertos += self.communication.broadcast(message.as_bytes().to_vec());
+                    match self.communication.broadcast(message.as_bytes().to_vec()) {
+                        Ok(n) => { acertos += n; },
+                        Err(e) => { debug!("Erro ao fazer broadcast: {e}"); },
+                    }
+                    let _ = survival_tx.send(acertos);
+                },
+                SendAction::Multicast { destinations, message } => {
+                    acertos += self.communication.multicast(&destinations, message.as_bytes().to_vec());
+                    let _ = survival_tx.send(acertos);
+                },
+                SendAction::Call { destination, message } => {
+                    match self.communication.call(destination, message.as_bytes().to_vec()) {
+                        Ok(response) if response == message.as_bytes() => { acertos += 1; },
+                        Ok(_) => { debug!("Resposta de RPC diferente da requisição"); },
+                        Err(e) => { debug!("Erro ao fazer RPC: {e}"); },
+                    }
+                    let _ = survival_tx.send(acertos);
+                },
+                SendAction::GroupBroadcast { group, message, .. } => {
+                    acertos += self.communication.group_broadcast(&group, message.as_bytes().to_vec());
                     let _ = survival_tx.send(acertos);
                 },
                 SendAction::DieAfterSend {} => {
@@ -217,7 +331,8 @@ fn main() {
             let file_path = format!("tests/test_{test_id}/Resultado.txt");
             let file_path = file_path.as_str();
             let expected = get_expected(test);
-            if calculate_test(file_path, final_path, agent_num, test_name, test_id, expected) {
+            let disagreements = check_agreement(test, test_id);
+            if calculate_test(file_path, final_path, agent_num, test_name, test_id, expected, disagreements) {
                 pass_tests -= 1;
             }
         }
@@ -233,11 +348,23 @@ fn main() {
             .parse()
             .expect("Falha ao converter agent_id para usize");
         let (_, mut test) = tests::all_tests()[test_id].clone();
-        let agent_num = test.len();
+        // Restarts are extra entries of the test, they don't add nodes to the group
+        let agent_num = test.iter().filter(|actions| restart_of(actions).is_none()).count();
 
+        let joiners: Vec<usize> = (0..agent_num)
+            .filter(|&i| test[i].iter().any(|a| matches!(a, Action::Join(_))))
+            .collect();
+        let node_id = match restart_of(&test[agent_id]) {
+            Some(node_id) => {
+                thread::sleep(RESTART_DELAY);
+                node_id
+            },
+            None => agent_id,
+        };
         let agent = create_agents(
-            agent_id,
+            node_id,
             agent_num,
+            &joiners,
         );
         let actions = test.remove(agent_id);
         let (s_acertos, r_acertos) = match agent {
@@ -255,29 +382,55 @@ fn main() {
     }
 }
 
-/// Creates the vector of nodes for all of the members of the group
-/// Since this is currently always being tested in the same machine, the IP is always the same and the ports are based on the node id
+/// Creates the vector of nodes for all of the members of the group, from the first seeds of the discovery
 /// Then it creates and returns the agent for the node that matches the sub-process id
+/// The founders only know each other, while the joiners (which must be the last agents) know every node
 fn create_agents(
     id: usize,
     agent_num: usize,
+    joiners: &[usize],
 ) -> Result<Arc<Agent>, std::io::Error> {
-    let ip: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-    let port: u16 = 3000;
-    let nodes: Vec<Node> = (0..agent_num)
-        .map(|i| Node::new(SocketAddr::new(ip, port + (i as u16)), i))
+    let nodes: Vec<Node> = Discovery::from_config()?
+        .seeds()
+        .iter()
+        .take(agent_num)
+        .enumerate()
+        .map(|(i, addr)| Node::new(*addr, i))
         .collect();
 
+    let joiner = joiners.contains(&id);
+    let founders = if joiner { agent_num } else { agent_num - joiners.len() };
     let agent = Arc::new(
-        Agent::new(id, nodes)?
+        Agent::new(id, nodes[..founders].to_vec(), joiner)?
     );
     Ok(agent)
 }
 
+/// The agent an entry of the test restarts, if it's a restart
+fn restart_of(actions: &[Action]) -> Option<usize> {
+    actions.iter().find_map(|action| match action {
+        Action::Restart(id) => Some(*id),
+        _ => None,
+    })
+}
+
 type Expected = (Vec<usize>, Vec<usize>, Vec<usize>);
 /// Calculates the expected number of sends, receives and deaths for each agent in the test
 fn get_expected(test: &Vec<Vec<Action>>) -> Expected {
     let agent_num: usize = test.len();
+    let node_num = test.iter().filter(|actions| restart_of(actions).is_none()).count();
+    // Broadcasts only reach the agent's side of a partition, and a side without a majority can't broadcast at all
+    let reachable = |agent: &Vec<Action>| {
+        let cut = agent.iter().find_map(|action| match action {
+            Action::Partition(ids) => Some(ids.len()),
+            _ => None,
+        });
+        match cut {
+            None => node_num,
+            Some(cut) if node_num - cut > node_num / 2 => node_num - cut,
+            Some(_) => 0,
+        }
+    };
     let mut send_actions = vec![0; agent_num];
     let mut receive_actions = vec![0; agent_num];
     let mut die_actions = vec![0; agent_num];
@@ -292,8 +445,20 @@ fn get_expected(test: &Vec<Vec<Action>>) -> Expected {
                             rec_snd += 1;
                         },
                         SendAction::Broadcast { .. } => {
-                            send_actions[id] += agent_num;
-                            rec_snd += agent_num;
+                            send_actions[id] += reachable(agent);
+                            rec_snd += reachable(agent);
+                        },
+                        SendAction::Multicast { destinations, .. } => {
+                            send_actions[id] += destinations.len();
+                            rec_snd += destinations.len();
+                        },
+                        // Calls are answered by the RPC handler, they never reach the receiver
+                        SendAction::Call { .. } => {
+                            send_actions[id] += 1;
+                        },
+                        SendAction::GroupBroadcast { members, .. } => {
+                            send_actions[id] += members.len();
+                            rec_snd += members.len();
                         },
                         SendAction::DieAfterSend {} => {
                             die_actions[id] += 1;
@@ -303,18 +468,23 @@ fn get_expected(test: &Vec<Vec<Action>>) -> Expected {
                 },
                 Action::Receive(r) => {
                     match r {
-                        ReceiveAction::Receive { .. } => {
+                        ReceiveAction::Receive { .. } | ReceiveAction::GroupReceive { .. } => {
                             receive_actions[id] += 1;
                             rec_snd -= 1;
                         },
                         ReceiveAction::DieAfterReceive { .. } => {
                             die_actions[id] += 1;
+                        },
+                        // View changes and membership events aren't sent by anyone
+                        ReceiveAction::ViewChange { .. } | ReceiveAction::Membership { .. } => {
+                            receive_actions[id] += 1;
                         }
                     }
                 },
                 Action::Die() => {
                     die_actions[id] += 1;
-                }
+                },
+                Action::Join(_) | Action::Restart(_) | Action::Partition(_) => {}
             }
         }
     }
@@ -328,11 +498,68 @@ fn get_expected(test: &Vec<Vec<Action>>) -> Expected {
     (send_actions, receive_actions, die_actions)
 }
 
+/// The agents each broadcast of the test must reach: the members of its group, or every agent on the side of the
+/// sender when the group is partitioned, with the restarts left out since they aren't members when it's sent
+fn broadcast_destinations(test: &[Vec<Action>]) -> Vec<(String, Vec<usize>)> {
+    let cut = |agent: usize| test[agent].iter().find_map(|action| match action {
+        Action::Partition(ids) => Some(ids.clone()),
+        _ => None,
+    }).unwrap_or_default();
+    let mut destinations = Vec::new();
+    for (sender, actions) in test.iter().enumerate() {
+        for action in actions {
+            let (message, members) = match action {
+                Action::Send(SendAction::Broadcast { message }) => {
+                    let members = (0..test.len())
+                        .filter(|&agent| restart_of(&test[agent]).is_none())
+                        .filter(|&agent| !cut(sender).contains(&agent) && !cut(agent).contains(&sender))
+                        .collect();
+                    (message, members)
+                },
+                Action::Send(SendAction::Multicast { destinations, message }) => (message, destinations.clone()),
+                Action::Send(SendAction::GroupBroadcast { members, message, .. }) => (message, members.clone()),
+                _ => continue,
+            };
+            destinations.push((message.clone(), members));
+        }
+    }
+    destinations
+}
+
+/// Checks the agreement of the broadcasts: every agent that survives the test must deliver each broadcast that any
+/// agent, even one that died, delivered
+/// Returns one line for each broadcast an agent that survived missed
+fn check_agreement(test: &[Vec<Action>], test_id: usize) -> String {
+    let delivered: Vec<Vec<String>> = (0..test.len())
+        .map(|agent| match File::open(format!("tests/test_{test_id}/entregas_{agent}.txt")) {
+            Ok(file) => BufReader::new(file).lines().map_while(Result::ok).collect(),
+            Err(_) => Vec::new(),
+        })
+        .collect();
+    let survives = |agent: usize| !test[agent].iter().any(|action| matches!(action,
+        Action::Die() | Action::Send(SendAction::DieAfterSend {}) | Action::Receive(ReceiveAction::DieAfterReceive { .. })
+    ));
+    let mut disagreements = String::new();
+    for (message, members) in broadcast_destinations(test) {
+        let line = format!("{:?}", message);
+        if !delivered.iter().any(|messages| messages.contains(&line)) {
+            continue;
+        }
+        for agent in members.into_iter().filter(|&agent| survives(agent)) {
+            if !delivered[agent].contains(&line) {
+                disagreements.push_str(&format!("Agente {agent}: não entregou um broadcast entregue por outro agente\n"));
+            }
+        }
+    }
+    disagreements
+}
+
 /// Reads and organizes the output file of all sub-processes in a test,
 /// then writes the results to a final file.
 /// The lines of output file must be formated as "AGENTE X -> ENVIOS: X - RECEBIDOS: X"
+/// The disagreements found by check_agreement also fail the test
 fn calculate_test(file_path: &str, final_path: &str, agent_num: usize,
-                test_name: &str, test_id: usize, expected: Expected) -> bool {
+                test_name: &str, test_id: usize, expected: Expected, disagreements: String) -> bool {
     let file = File::open(file_path).expect("Erro ao abrir o arquivo de log");
     let mut reader = BufReader::new(file);
 
@@ -347,7 +574,8 @@ fn calculate_test(file_path: &str, final_path: &str, agent_num: usize,
         }
     }
 
-    let mut has_errors = false;
+    let mut has_errors = !disagreements.is_empty();
+    errors[0].push_str(&disagreements);
 
     let mut total_sends = 0;
     let mut total_receivs = 0;
@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+use relcomm::membership::MembershipEvent;
 
 #[derive(PartialEq, Clone)]
 pub enum SendAction {
@@ -9,6 +10,20 @@ pub enum SendAction {
     Broadcast {
         message: String
     },
+    Multicast {
+        destinations: Vec<usize>,
+        message: String
+    },
+    Call {
+        destination: usize,
+        message: String
+    },
+    GroupBroadcast {
+        group: String,
+        members: Vec<usize>,
+        broadcast: String,
+        message: String
+    },
     DieAfterSend {}
 } 
 
@@ -17,8 +32,22 @@ pub enum ReceiveAction {
     Receive {
         message: String
     },
+    GroupReceive {
+        group: String,
+        members: Vec<usize>,
+        broadcast: String,
+        message: String
+    },
     DieAfterReceive {
         after_n_messages: u32
+    },
+    // Expects the installation of a view with exactly these members
+    ViewChange {
+        members: Vec<usize>
+    },
+    // Expects a membership event, they are awaited before any message
+    Membership {
+        event: MembershipEvent
     }
 }
 
@@ -26,7 +55,13 @@ pub enum ReceiveAction {
 pub enum Action {
     Send(SendAction),
     Receive(ReceiveAction),
-    Die()
+    Die(),
+    // The agent starts outside the group and joins it through the given seed before running the other actions
+    Join(usize),
+    // This entry is a restart of the given agent, which must have died, running on the same address after a delay
+    Restart(usize),
+    // The agent enables the majority quorum and is cut off from the given agents before running the other actions
+    Partition(Vec<usize>)
 }
 
 const MSG_0: &str = "
@@ -384,6 +419,229 @@ pub fn broadcast_test_6() -> Test {
     ]
 }
 
+/// 10 Nodos, um faz multicast para metade do grupo, incluindo ele mesmo
+pub fn multicast_test_1() -> Test {
+    vec![
+        // Agent 0
+        vec![],
+        // Agent 1
+        vec![
+            Action::Send(SendAction::Multicast { destinations: vec![1, 3, 5, 7, 9], message: MSG_0.to_string() }),
+            Action::Receive(ReceiveAction::Receive { message: MSG_0.to_string() })
+        ],
+        vec![],
+        vec![Action::Receive(ReceiveAction::Receive { message: MSG_0.to_string() })],
+        vec![],
+        vec![Action::Receive(ReceiveAction::Receive { message: MSG_0.to_string() })],
+        vec![],
+        vec![Action::Receive(ReceiveAction::Receive { message: MSG_0.to_string() })],
+        vec![],
+        vec![Action::Receive(ReceiveAction::Receive { message: MSG_0.to_string() })],
+    ]
+}
+
+/// 10 Nodos, um que não é membro faz multicast para parte do grupo
+pub fn multicast_test_2() -> Test {
+    vec![
+        // Agent 0
+        vec![Action::Send(SendAction::Multicast { destinations: vec![2, 4, 6], message: "message_0".to_string() })],
+        // Agent 1
+        vec![],
+        vec![Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })],
+        vec![],
+        vec![Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })],
+        vec![],
+        vec![Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })],
+        vec![],
+        vec![],
+        vec![],
+    ]
+}
+
+/// 10 Nodos, três deles criam um grupo nomeado e um dos membros difunde para o grupo
+pub fn group_test_1() -> Test {
+    let group = "grupo_1".to_string();
+    let members = vec![2, 5, 8];
+    let broadcast = "AB".to_string();
+    let receive = Action::Receive(ReceiveAction::GroupReceive {
+        group: group.clone(),
+        members: members.clone(),
+        broadcast: broadcast.clone(),
+        message: MSG_1.to_string()
+    });
+    vec![
+        // Agent 0
+        vec![],
+        // Agent 1
+        vec![],
+        vec![
+            Action::Send(SendAction::GroupBroadcast { group: group.clone(), members: members.clone(), broadcast, message: MSG_1.to_string() }),
+            receive.clone()
+        ],
+        vec![],
+        vec![],
+        vec![receive.clone()],
+        vec![],
+        vec![],
+        vec![receive],
+        vec![],
+    ]
+}
+
+/// 10 Nodos num grupo com URB, o remetente morre logo após entregar a própria mensagem
+/// Como a entrega só ocorre depois que a maioria tem a mensagem, todos os outros devem entregá-la
+pub fn group_test_2() -> Test {
+    let group = "grupo_2".to_string();
+    let members: Vec<usize> = (0..10).collect();
+    let broadcast = "URB".to_string();
+    let receive = Action::Receive(ReceiveAction::GroupReceive {
+        group: group.clone(),
+        members: members.clone(),
+        broadcast: broadcast.clone(),
+        message: "message_0".to_string()
+    });
+    let mut test = vec![vec![receive.clone()]; 10];
+    // Agent 0
+    // The URB only returns once the sender delivers the message, so dying after the send is dying after the delivery
+    test[0] = vec![
+        Action::Send(SendAction::GroupBroadcast { group, members, broadcast, message: "message_0".to_string() }),
+        Action::Send(SendAction::DieAfterSend {}),
+        receive,
+    ];
+    test
+}
+
+/// 4 Nodos, todos respondem requisições com a própria requisição
+/// Dois deles fazem chamadas, inclusive para si mesmos
+pub fn rpc_test_1() -> Test {
+    vec![
+        // Agent 0
+        vec![
+            Action::Send(SendAction::Call { destination: 1, message: "request_0".to_string() }),
+            Action::Send(SendAction::Call { destination: 2, message: MSG_0.to_string() }),
+            Action::Send(SendAction::Call { destination: 0, message: "request_1".to_string() }),
+        ],
+        // Agent 1
+        vec![],
+        vec![Action::Send(SendAction::Call { destination: 3, message: "request_2".to_string() })],
+        vec![],
+    ]
+}
+
+pub fn membership_test_1() -> Test {
+    vec![
+        // Agent 0
+        vec![Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })],
+        // Agent 1
+        vec![Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })],
+        // Agent 2
+        vec![Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })],
+        // Agent 3
+        vec![Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })],
+        // Agent 4
+        vec![
+            Action::Join(2),
+            Action::Send(SendAction::Broadcast { message: "message_0".to_string() }),
+            Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })
+        ],
+    ]
+}
+
+pub fn view_test_1() -> Test {
+    vec![
+        // Agent 0
+        vec![Action::Receive(ReceiveAction::ViewChange { members: vec![0, 1, 2] })],
+        // Agent 1
+        vec![Action::Receive(ReceiveAction::ViewChange { members: vec![0, 1, 2] })],
+        // Agent 2
+        vec![Action::Receive(ReceiveAction::ViewChange { members: vec![0, 1, 2] })],
+        // Agent 3
+        vec![Action::Die()],
+    ]
+}
+
+pub fn recovery_test_1() -> Test {
+    vec![
+        // Agent 0
+        vec![
+            Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() }),
+            Action::Receive(ReceiveAction::Receive { message: "message_1".to_string() }),
+        ],
+        // Agent 1
+        vec![Action::Receive(ReceiveAction::Receive { message: "message_1".to_string() })],
+        // Agent 2
+        vec![
+            Action::Send(SendAction::Send { destination: 0, message: "message_0".to_string() }),
+            Action::Send(SendAction::DieAfterSend {}),
+        ],
+        // Agent 2, restarted
+        vec![
+            Action::Restart(2),
+            Action::Send(SendAction::Send { destination: 0, message: "message_1".to_string() }),
+            Action::Send(SendAction::Send { destination: 1, message: "message_1".to_string() }),
+        ],
+    ]
+}
+
+pub fn events_test_1() -> Test {
+    vec![
+        // Agent 0
+        vec![Action::Receive(ReceiveAction::Membership { event: MembershipEvent::NodeDied(2) })],
+        // Agent 1
+        vec![Action::Receive(ReceiveAction::Membership { event: MembershipEvent::NodeDied(2) })],
+        // Agent 2
+        vec![Action::Die()],
+    ]
+}
+
+/// 3 Nodos, o primeiro nem nasce: o segundo é eleito líder e ordena o broadcast do terceiro
+pub fn election_test_1() -> Test {
+    vec![
+        // Agent 0
+        vec![Action::Die()],
+        // Agent 1
+        vec![
+            Action::Receive(ReceiveAction::Membership { event: MembershipEvent::LeaderChanged(1) }),
+            Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })
+        ],
+        // Agent 2
+        vec![
+            Action::Receive(ReceiveAction::Membership { event: MembershipEvent::LeaderChanged(1) }),
+            Action::Send(SendAction::Broadcast { message: "message_0".to_string() }),
+            Action::Receive(ReceiveAction::Receive { message: "message_0".to_string() })
+        ],
+    ]
+}
+
+/// 5 Nodos divididos em duas partições com quórum: só a maioria consegue fazer broadcast
+pub fn partition_test_1() -> Test {
+    vec![
+        // Agent 0
+        vec![
+            Action::Partition(vec![2, 3, 4]),
+            Action::Send(SendAction::Broadcast { message: "message_0".to_string() }),
+        ],
+        // Agent 1
+        vec![Action::Partition(vec![2, 3, 4])],
+        // Agent 2
+        vec![
+            Action::Partition(vec![0, 1]),
+            Action::Send(SendAction::Broadcast { message: "message_1".to_string() }),
+            Action::Receive(ReceiveAction::Receive { message: "message_1".to_string() })
+        ],
+        // Agent 3
+        vec![
+            Action::Partition(vec![0, 1]),
+            Action::Receive(ReceiveAction::Receive { message: "message_1".to_string() })
+        ],
+        // Agent 4
+        vec![
+            Action::Partition(vec![0, 1]),
+            Action::Receive(ReceiveAction::Receive { message: "message_1".to_string() })
+        ],
+    ]
+}
+
 // a vec of function pointers to the tests
 pub fn all_tests() -> Vec<(&'static str, Test)> {
     vec![
@@ -396,6 +654,17 @@ pub fn all_tests() -> Vec<(&'static str, Test)> {
         ("broadcast_3", broadcast_test_3()),
         ("broadcast_4", broadcast_test_4()),
         ("broadcast_5", broadcast_test_5()),
-        ("broadcast_6", broadcast_test_6())
+        ("broadcast_6", broadcast_test_6()),
+        ("multicast_1", multicast_test_1()),
+        ("multicast_2", multicast_test_2()),
+        ("group_1", group_test_1()),
+        ("group_2", group_test_2()),
+        ("rpc_1", rpc_test_1()),
+        ("membership_1", membership_test_1()),
+        ("view_1", view_test_1()),
+        ("recovery_1", recovery_test_1()),
+        ("events_1", events_test_1()),
+        ("election_1", election_test_1()),
+        ("partition_1", partition_test_1())
     ]
 }